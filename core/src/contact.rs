@@ -0,0 +1,529 @@
+//! Contact define
+
+use std::{ fmt, iter };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use rand::{ Rng, OsRng };
+use sha3::Shake256;
+use digest::{ Input, ExtendableOutput, XofReader };
+use seckey::SecKey;
+use subtle::ConstantTimeEq;
+use serde_derive::{ Serialize, Deserialize };
+use crate::key;
+use crate::alg::{ self, Protocol };
+use crate::format::Short;
+use crate::error::{ StoreError, ProtoError };
+
+
+/// Contact storage
+pub trait ContactStore {
+    fn get(&self, id: &str) -> Result<Option<Record>, StoreError>;
+
+    fn set(&self, id: &str, record: &Record) -> Result<(), StoreError>;
+
+    fn del(&self, id: &str) -> Result<(), StoreError>;
+
+    /// Scan contacts whose ID starts with `prefix`
+    fn scan<'a>(&'a self, prefix: &'a str)
+        -> Box<dyn Iterator<Item = Result<(String, Record), StoreError>> + 'a>;
+
+    /// Find contacts that have a component key whose fingerprint starts with `prefix`
+    ///
+    /// Fall back to decode every record, stores should override it with an index.
+    fn find<'a>(&'a self, prefix: &'a str)
+        -> Box<dyn Iterator<Item = Result<(String, Record), StoreError>> + 'a>
+    {
+        Box::new(self.scan("").filter(move |item| match item {
+            Ok((_, record)) => record.matches(prefix),
+            Err(_) => true
+        }))
+    }
+
+    /// Members of group
+    fn get_group(&self, name: &str) -> Result<Option<Vec<String>>, StoreError>;
+
+    /// Set members of group, delete the group if `members` is empty
+    fn set_group(&self, name: &str, members: &[String]) -> Result<(), StoreError>;
+
+    /// All groups and their members
+    fn groups(&self) -> Result<Vec<(String, Vec<String>)>, StoreError>;
+
+    fn history(&self, id: &str) -> Result<Vec<Seen>, StoreError> {
+        self.get(id)
+            .map(|record| record.map(|record| record.history).unwrap_or_default())
+    }
+
+    fn get_pk(&self, id: &str) -> Result<Option<key::PublicKey>, StoreError> {
+        self.get(id)
+            .map(|record| record.map(|record| record.pk))
+    }
+}
+
+/// Group names are written as `@name`, contact IDs must not use this prefix.
+pub const GROUP_PREFIX: char = '@';
+
+pub fn check_id(id: &str) -> Result<(), StoreError> {
+    if id.starts_with(GROUP_PREFIX) {
+        Err(StoreError::InvalidId(format!("must not start with {}: {}", GROUP_PREFIX, id)))
+    } else if id.starts_with('\0') {
+        Err(StoreError::InvalidId(format!("must not start with NUL: {:?}", id)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Contact Record
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    pub pk: key::PublicKey,
+
+    /// Display name
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub aliases: Vec<String>,
+
+    #[serde(default)]
+    pub notes: Option<String>,
+
+    /// Unix timestamp
+    #[serde(default)]
+    pub added: u64,
+
+    /// Where the PublicKey comes from
+    #[serde(default)]
+    pub source: Source,
+
+    #[serde(default)]
+    pub status: Status,
+
+    #[serde(default)]
+    pub prefs: Preferences,
+
+    /// Every key ever seen
+    #[serde(default)]
+    pub history: Vec<Seen>,
+
+    /// Changed key, waiting to be accepted
+    #[serde(default)]
+    pub pending: Option<key::PublicKey>,
+
+    /// Unix timestamp after which the key should not be used
+    #[serde(default)]
+    pub expires: Option<u64>,
+
+    /// Autocrypt peer state
+    #[serde(default)]
+    pub autocrypt: Option<Autocrypt>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Seen {
+    pub pk: key::PublicKey,
+    pub first_seen: u64,
+    pub last_seen: u64
+}
+
+#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize)]
+pub enum Source {
+    Unknown,
+    File(String),
+    Message,
+    KeyServer(String),
+    Autocrypt
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Status {
+    Unverified,
+    Verified,
+    Revoked
+}
+
+/// Autocrypt peer state, timestamps are effective message dates
+#[derive(Default)]
+#[derive(Serialize, Deserialize)]
+pub struct Autocrypt {
+    /// Latest message seen, with or without Autocrypt header
+    pub last_seen: u64,
+
+    /// Latest message seen with Autocrypt header
+    pub timestamp: u64,
+
+    /// Peer prefers encrypted mail, `prefer-encrypt=mutual`
+    pub prefer_encrypt: bool
+}
+
+#[derive(Default)]
+#[derive(Serialize, Deserialize)]
+pub struct Preferences {
+    /// Preferred encryption protocol
+    #[serde(default)]
+    pub protocol: Option<Protocol>
+}
+
+impl Record {
+    pub fn new(pk: key::PublicKey, source: Source) -> Record {
+        let now = now();
+
+        Record {
+            history: vec![Seen { pk: pk.clone(), first_seen: now, last_seen: now }],
+            pk,
+            name: None,
+            aliases: Vec::new(),
+            notes: None,
+            added: now,
+            source,
+            status: Status::Unverified,
+            prefs: Preferences::default(),
+            pending: None,
+            expires: None,
+            autocrypt: None
+        }
+    }
+
+    /// Record a key seen, return `false` if it differs from the pinned key.
+    ///
+    /// The changed key is kept as pending until it is accepted.
+    pub fn observe(&mut self, pk: &key::PublicKey) -> bool {
+        let now = now();
+
+        if self.history.is_empty() {
+            self.history.push(Seen {
+                pk: self.pk.clone(),
+                first_seen: self.added,
+                last_seen: self.added
+            });
+        }
+
+        match self.history.iter_mut().find(|seen| same(&seen.pk, pk)) {
+            Some(seen) => seen.last_seen = now,
+            None => self.history.push(Seen { pk: pk.clone(), first_seen: now, last_seen: now })
+        }
+
+        if same(&self.pk, pk) {
+            true
+        } else {
+            self.pending = Some(self.pk.clone().merge(pk.clone()));
+            false
+        }
+    }
+
+    /// Pin the pending key, return `false` if there is no key change.
+    pub fn accept_change(&mut self) -> bool {
+        if let Some(pk) = self.pending.take() {
+            self.pk = pk;
+            self.status = Status::Unverified;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Why the key should not be used, `None` if it is usable.
+    pub fn unusable(&self, now: u64) -> Option<&'static str> {
+        if self.status == Status::Revoked {
+            Some("revoked")
+        } else if self.expires.map(|expires| expires <= now).unwrap_or(false) {
+            Some("expired")
+        } else {
+            None
+        }
+    }
+
+    /// Fingerprints of every component key, include pending and history keys.
+    pub fn fingerprints(&self) -> Vec<Short> {
+        let pks = iter::once(&self.pk)
+            .chain(&self.pending)
+            .chain(self.history.iter().map(|seen| &seen.pk));
+
+        let mut fingerprints = Vec::new();
+        for pk in pks {
+            let short = pk.to_short();
+            fingerprints.extend(short.ed25519);
+            fingerprints.extend(short.ristrettodh);
+
+            #[cfg(feature = "post-quantum")]
+            fingerprints.extend(short.kyber);
+        }
+
+        fingerprints.sort_by_key(|short| short.0);
+        fingerprints.dedup();
+        fingerprints
+    }
+
+    /// Whether any fingerprint starts with hex `prefix`.
+    pub fn matches(&self, prefix: &str) -> bool {
+        self.fingerprints()
+            .into_iter()
+            .any(|short| fingerprint(short).starts_with(prefix))
+    }
+}
+
+/// Lowercase hex fingerprint, without `0x`, always 16 digits as `Short` is printed.
+pub fn fingerprint(short: Short) -> String {
+    format!("{:016x}", short.0)
+}
+
+/// Web Key Directory style hash of the local part of email ID
+///
+/// z-base-32 of 20 bytes Shake256 over `ENE-WKD` and the lowercase local part, always 32 characters.
+/// It is not the SHA-1 hash of OpenPGP WKD, so the layout can not be shared with OpenPGP keys,
+/// which live under `.well-known/openpgpkey` anyway.
+pub fn wkd_hash(local: &str) -> String {
+    const ZBASE32: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+
+    let mut hash = [0; 20];
+    let mut hasher = Shake256::default();
+    hasher.process(b"ENE-WKD");
+    hasher.process(local.to_lowercase().as_bytes());
+    hasher.xof_result().read(&mut hash);
+
+    let mut output = String::with_capacity(32);
+    for chunk in hash.chunks(5) {
+        let n = chunk.iter().fold(0u64, |n, &b| (n << 8) | u64::from(b));
+        for i in (0..8).rev() {
+            output.push(ZBASE32[(n >> (i * 5)) as usize & 0x1f] as char);
+        }
+    }
+    output
+}
+
+/// Compare the keys present in both.
+pub fn same(pk: &key::PublicKey, pk2: &key::PublicKey) -> bool {
+    pk.contains(pk2, |_, _, _| Ok::<_, ()>(())).unwrap_or(false)
+}
+
+/// Keyed operations of protected contact store,
+/// done by `Sealer` locally or by the agent which holds the Profile.
+pub trait Seal {
+    fn hash_id(&self, id: &str) -> Result<Vec<u8>, StoreError>;
+
+    /// Keyed hash of ID and encoded record.
+    fn tag(&self, id: &str, record: &[u8]) -> Result<Vec<u8>, StoreError>;
+
+    /// Encrypt with random nonce, output `nonce || ciphertext`.
+    fn seal(&self, aad: &[u8], input: &[u8]) -> Result<Vec<u8>, StoreError>;
+
+    fn open(&self, aad: &[u8], input: &[u8]) -> Result<Vec<u8>, StoreError>;
+
+    fn verify(&self, id: &str, record: &[u8], tag: &[u8]) -> Result<bool, StoreError> {
+        Ok(self.tag(id, record)?[..].ct_eq(tag).unwrap_u8() == 1)
+    }
+}
+
+/// Contact store encryption and authentication
+///
+/// Encrypted store replaces IDs by keyed hash,
+/// and encrypts records with the hashed ID as associated data.
+/// Plain store keeps IDs and attaches a tag to records.
+pub struct Sealer {
+    enc: alg::Encrypt,
+    id_key: SecKey<[u8; 32]>,
+    mac_key: SecKey<[u8; 32]>,
+    key: SecKey<[u8; 32]>
+}
+
+impl Sealer {
+    /// Storage key context, see `Ene::derive_key`.
+    pub const CONTEXT: &'static str = "contact";
+
+    pub fn new(enc: alg::Encrypt, master: &[u8]) -> Result<Sealer, StoreError> {
+        let derive = |label: &[u8]| -> Result<SecKey<[u8; 32]>, StoreError> {
+            let mut key = SecKey::new([0; 32]).map_err(|_| StoreError::Alloc)?;
+            {
+                let mut key = key.write();
+                let mut hasher = Shake256::default();
+                hasher.process(label);
+                hasher.process(master);
+                hasher.xof_result().read(&mut key[..]);
+            }
+            Ok(key)
+        };
+
+        Ok(Sealer {
+            enc,
+            id_key: derive(b"ENE-CONTACT-ID")?,
+            mac_key: derive(b"ENE-CONTACT-MAC")?,
+            key: derive(b"ENE-CONTACT-RECORD")?
+        })
+    }
+}
+
+impl Seal for Sealer {
+    fn hash_id(&self, id: &str) -> Result<Vec<u8>, StoreError> {
+        let mut output = vec![0; 32];
+        let mut hasher = Shake256::default();
+        hasher.process(&self.id_key.read()[..]);
+        hasher.process(id.as_bytes());
+        hasher.xof_result().read(&mut output);
+        Ok(output)
+    }
+
+    fn tag(&self, id: &str, record: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let mut output = vec![0; 32];
+        let mut hasher = Shake256::default();
+        hasher.process(&self.mac_key.read()[..]);
+        hasher.process(id.as_bytes());
+        hasher.process(&[0xff]); // never appears in UTF-8
+        hasher.process(record);
+        hasher.xof_result().read(&mut output);
+        Ok(output)
+    }
+
+    fn seal(&self, aad: &[u8], input: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let aead = self.enc.take();
+        let key = self.key.read();
+
+        let mut output = vec![0; aead.nonce_length() + input.len() + aead.tag_length()];
+        let (nonce, c) = output.split_at_mut(aead.nonce_length());
+        OsRng::new()?.fill(nonce);
+        aead.seal(&key[..aead.key_length()], nonce, aad, input, c)?;
+
+        Ok(output)
+    }
+
+    fn open(&self, aad: &[u8], input: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let aead = self.enc.take();
+        let key = self.key.read();
+
+        if input.len() < aead.nonce_length() + aead.tag_length() {
+            return Err(ProtoError::InvalidLength.into());
+        }
+
+        let (nonce, c) = input.split_at(aead.nonce_length());
+        let mut output = vec![0; c.len() - aead.tag_length()];
+        aead.open(&key[..aead.key_length()], nonce, aad, c, &mut output)?;
+
+        Ok(output)
+    }
+}
+
+// old version only stores PublicKey
+impl From<key::PublicKey> for Record {
+    fn from(pk: key::PublicKey) -> Record {
+        Record {
+            added: 0,
+            history: Vec::new(),
+            ..Record::new(pk, Source::Unknown)
+        }
+    }
+}
+
+impl Default for Source {
+    fn default() -> Source {
+        Source::Unknown
+    }
+}
+
+impl Default for Status {
+    fn default() -> Status {
+        Status::Unverified
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Unknown => f.write_str("unknown"),
+            Source::File(path) => write!(f, "file ({})", path),
+            Source::Message => f.write_str("message"),
+            Source::KeyServer(server) => write!(f, "key server ({})", server),
+            Source::Autocrypt => f.write_str("autocrypt")
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Unverified => f.write_str("unverified"),
+            Status::Verified => f.write_str("verified"),
+            Status::Revoked => f.write_str("revoked")
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or(0)
+}
+
+
+#[test]
+fn test_record_observe() {
+    use rand::thread_rng;
+    use crate::Builder;
+
+    let mut rng = thread_rng();
+    let pk = Builder::default().generate("alice@core.ene", &mut rng).as_secret().to_public();
+    let pk2 = Builder::default().generate("alice@core.ene", &mut rng).as_secret().to_public();
+
+    let mut record = Record::new(pk.clone(), Source::Unknown);
+    assert!(record.observe(&pk));
+    assert!(record.pending.is_none());
+    assert_eq!(record.history.len(), 1);
+
+    assert!(!record.observe(&pk2));
+    assert!(record.pending.is_some());
+    assert_eq!(record.history.len(), 2);
+    assert!(record.pk.ed25519 == pk.ed25519);
+
+    assert!(record.accept_change());
+    assert!(!record.accept_change());
+    assert!(record.pk.ed25519 == pk2.ed25519);
+    assert!(record.observe(&pk2));
+    assert_eq!(record.history.len(), 2);
+
+    assert!(record.unusable(now()).is_none());
+    record.expires = Some(1);
+    assert_eq!(record.unusable(now()), Some("expired"));
+    record.status = Status::Revoked;
+    assert_eq!(record.unusable(now()), Some("revoked"));
+
+    let short = pk.to_short().ristrettodh.unwrap();
+    assert!(record.matches(&fingerprint(short)[..6]));
+    assert!(record.fingerprints().contains(&short));
+}
+
+#[test]
+fn test_fingerprint() {
+    let short = Short(0x0123_4567_89ab_cdef);
+    assert_eq!(fingerprint(short), "0123456789abcdef");
+    assert_eq!(format!("{:?}", short), format!("0x{}", fingerprint(short)));
+    assert_eq!(fingerprint(Short(1)).len(), 16);
+}
+
+#[test]
+fn test_sealer() {
+    let sealer = Sealer::new(alg::Encrypt::Aes128Colm0, &[42; 32]).unwrap();
+    let sealer2 = Sealer::new(alg::Encrypt::Aes128Colm0, &[43; 32]).unwrap();
+
+    let id = sealer.hash_id("alice@core.ene").unwrap();
+    assert_eq!(id, sealer.hash_id("alice@core.ene").unwrap());
+    assert_ne!(id, sealer2.hash_id("alice@core.ene").unwrap());
+
+    let c = sealer.seal(&id, b"record").unwrap();
+    assert_eq!(sealer.open(&id, &c).unwrap(), b"record");
+    assert!(sealer.open(&sealer.hash_id("bob@core.ene").unwrap(), &c).is_err());
+    assert!(sealer2.open(&id, &c).is_err());
+
+    let tag = sealer.tag("alice@core.ene", b"record").unwrap();
+    assert!(sealer.verify("alice@core.ene", b"record", &tag).unwrap());
+    assert!(!sealer.verify("alice@core.ene", b"record2", &tag).unwrap());
+    assert!(!sealer.verify("bob@core.ene", b"record", &tag).unwrap());
+    assert!(!sealer2.verify("alice@core.ene", b"record", &tag).unwrap());
+}
+
+#[test]
+fn test_wkd_hash() {
+    let hash = wkd_hash("Alice");
+    assert_eq!(hash.len(), 32);
+    assert_eq!(hash, wkd_hash("alice"));
+    assert_ne!(hash, wkd_hash("bob"));
+    assert!(hash.bytes().all(|c| b"ybndrfg8ejkmcpqxot1uwisza345h769".contains(&c)));
+}
@@ -45,10 +45,33 @@ pub enum ParseError {
     #[fail(display = "Unexpected end")]
     UnexpectedEnd,
 
+    #[fail(display = "Invalid checksum")]
+    InvalidChecksum,
+
     #[fail(display = "Not available: {}", _0)]
     NotAvailable(Cow<'static, str>)
 }
 
+/// Contact store Error
+#[derive(Debug, Fail)]
+pub enum StoreError {
+    #[fail(display = "Invalid ID: {}", _0)]
+    InvalidId(String),
+
+    #[fail(display = "Protocol Error: {}", _0)]
+    Proto(ProtoError),
+
+    #[fail(display = "Rand Error: {}", _0)]
+    Rand(rand::Error),
+
+    #[fail(display = "Secure alloc fail")]
+    Alloc,
+
+    /// Error of the storage backend, with its causes
+    #[fail(display = "Storage Error: {}", _0)]
+    Backend(String)
+}
+
 impl<E: failure::Fail + Sync + Send + 'static> From<rand::Error> for Error<E> {
     fn from(err: rand::Error) -> Error<E> {
         Error::Rand(err)
@@ -67,6 +90,18 @@ impl<E: failure::Fail + Sync + Send + 'static> From<ParseError> for Error<E> {
     }
 }
 
+impl From<ProtoError> for StoreError {
+    fn from(err: ProtoError) -> StoreError {
+        StoreError::Proto(err)
+    }
+}
+
+impl From<rand::Error> for StoreError {
+    fn from(err: rand::Error) -> StoreError {
+        StoreError::Rand(err)
+    }
+}
+
 impl From<ed25519_dalek::SignatureError> for ProtoError {
     fn from(err: ed25519_dalek::SignatureError) -> ProtoError {
         ProtoError::Ed25519(err)
@@ -19,6 +19,21 @@ pub type PublicKey = Envelope<PK, (String, key::PublicKey)>;
 /// Message Format
 pub type Message = Envelope<MSG, (Meta, Protocol, ByteBuf)>;
 
+/// Address Book Bundle Format
+///
+/// Encoded `BundleContent`, and optional Sonly signature.
+pub type Bundle = Envelope<BUNDLE, (ByteBuf, Option<Message>)>;
+
+/// Address Book Bundle Content
+///
+/// Created time, and entries of ID, display name and PublicKey.
+pub type BundleContent = (u64, Vec<(String, Option<String>, key::PublicKey)>);
+
+/// Secret Share Format
+///
+/// ID, threshold, share index and share.
+pub type Share = Envelope<SHARE, (String, u8, u8, ByteBuf)>;
+
 /// Envelope
 #[derive(Serialize, Deserialize)]
 pub struct Envelope<T: Type, V>(pub ENE<T>, pub Version, pub V);
@@ -44,6 +59,14 @@ pub struct SK;
 #[derive(Serialize, Deserialize)]
 pub struct MSG;
 
+/// Packet Type
+#[derive(Serialize, Deserialize)]
+pub struct SHARE;
+
+/// Packet Type
+#[derive(Serialize, Deserialize)]
+pub struct BUNDLE;
+
 impl Type for PK {
     const NAME: &'static str = "PK";
 }
@@ -56,6 +79,14 @@ impl Type for MSG {
     const NAME: &'static str = "MSG";
 }
 
+impl Type for SHARE {
+    const NAME: &'static str = "SHARE";
+}
+
+impl Type for BUNDLE {
+    const NAME: &'static str = "BUNDLE";
+}
+
 /// Meta information
 #[derive(Serialize, Deserialize)]
 pub struct Meta {
@@ -134,6 +165,6 @@ impl<'a, T: Packing> From<&'a T> for Short {
 
 impl fmt::Debug for Short {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:#016x}", self.0)
+        write!(f, "{:#018x}", self.0)
     }
 }
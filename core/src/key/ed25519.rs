@@ -17,7 +17,7 @@ use crate::error::ProtoError;
 #[derive(Serialize, Deserialize)]
 pub struct SecretKey(pub(crate) Keypair);
 
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 #[derive(Serialize)]
 pub struct PublicKey(pub(crate) PublicKey2);
 
@@ -28,6 +28,12 @@ impl SecretKey {
     pub fn generate<RNG: Rng + CryptoRng>(rng: &mut RNG) -> SecretKey {
         SecretKey(Keypair::generate::<Sha3_512, _>(rng))
     }
+
+    pub(crate) fn read_secret<F, R>(&self, f: F) -> R
+        where F: FnOnce(&[u8]) -> R
+    {
+        f(self.0.secret.as_bytes())
+    }
 }
 
 impl PublicKey {
@@ -29,6 +29,12 @@ impl PublicKey {
     }
 }
 
+impl Clone for PublicKey {
+    fn clone(&self) -> PublicKey {
+        PublicKey(self.0.read_bytes(kyber::PublicKey::from_bytes))
+    }
+}
+
 macro_rules! packing {
     ( $t:ident ) => {
         impl Packing for $t {
@@ -22,7 +22,7 @@ pub struct SecretKey {
 }
 
 /// PublicKey Set
-#[derive(Default)]
+#[derive(Default, Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct PublicKey {
     pub ed25519: Option<ed25519::PublicKey>,
@@ -58,6 +58,15 @@ impl PublicKey {
         }
     }
 
+    /// Replace keys with the present keys of `pk`.
+    pub fn merge(self, pk: PublicKey) -> PublicKey {
+        PublicKey {
+            ed25519: pk.ed25519.or(self.ed25519),
+            ristrettodh: pk.ristrettodh.or(self.ristrettodh),
+            #[cfg(feature = "post-quantum")] kyber: pk.kyber.or(self.kyber)
+        }
+    }
+
     pub fn contains<E, F>(&self, pk: &PublicKey, mut f: F)
         -> Result<bool, E>
         where F: FnMut(&'static str, Short, Short) -> Result<(), E>
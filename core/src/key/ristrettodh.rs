@@ -14,7 +14,7 @@ use crate::error::ProtoError;
 #[derive(Serialize, Deserialize)]
 pub struct SecretKey(pub(crate) Scalar, pub(crate) RistrettoPoint);
 
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 #[derive(Serialize)]
 pub struct PublicKey(pub(crate) RistrettoPoint);
 
@@ -11,9 +11,16 @@ pub mod key;
 pub mod aead;
 pub mod format;
 pub mod error;
+pub mod shamir;
+pub mod mnemonic;
+pub mod contact;
+pub mod merkle;
 
 use std::str::FromStr;
-use rand::{ Rng, CryptoRng, OsRng };
+use rand::{ Rng, CryptoRng, OsRng, SeedableRng };
+use rand::prng::ChaChaRng;
+use sha3::Shake256;
+use digest::{ Input, ExtendableOutput, XofReader };
 use serde_bytes::{ ByteBuf, Bytes };
 use crate::alg::Protocol;
 use crate::format::Message;
@@ -90,6 +97,44 @@ impl Builder {
             }
         }
     }
+
+    /// Deterministic derive all keys from master seed.
+    ///
+    /// Each algorithm uses an independent rng,
+    /// so adding an algorithm does not change other keys.
+    pub fn derive(&self, id: &str, seed: &[u8]) -> Ene {
+        fn rng(seed: &[u8], name: &str) -> ChaChaRng {
+            let mut hasher = Shake256::default();
+            hasher.process(b"ENE-DERIVE");
+            hasher.process(name.as_bytes());
+            hasher.process(seed);
+
+            let mut rng_seed = [0; 32];
+            hasher.xof_result().read(&mut rng_seed);
+            ChaChaRng::from_seed(rng_seed)
+        }
+
+        let ed25519_sk =
+            if self.ed25519 { Some(ed25519::SecretKey::generate(&mut rng(seed, Ed25519::NAME))) }
+            else { None };
+        let ristrettodh_sk =
+            if self.ristrettodh { Some(ristrettodh::SecretKey::generate(&mut rng(seed, RistrettoDH::NAME))) }
+            else { None };
+
+        #[cfg(feature = "post-quantum")]
+        let kyber_sk =
+            if self.kyber { Some(kyber::SecretKey::generate(&mut rng(seed, Kyber::NAME))) }
+            else { None };
+
+        Ene {
+            id: id.to_string(),
+            key: key::SecretKey {
+                ed25519: ed25519_sk,
+                ristrettodh: ristrettodh_sk,
+                #[cfg(feature = "post-quantum")] kyber: kyber_sk
+            }
+        }
+    }
 }
 
 impl Ene {
@@ -115,6 +160,23 @@ impl Ene {
     pub fn into_secret(self) -> key::SecretKey {
         self.key
     }
+
+    /// Derive a symmetric key bound to `context`, for local storage.
+    pub fn derive_key(&self, context: &str, output: &mut [u8]) -> Result<(), ParseError> {
+        let sk = match &self.key.ed25519 {
+            Some(sk) => sk,
+            None => return Err(ParseError::NotAvailable(Ed25519::NAME.into()))
+        };
+
+        let mut hasher = Shake256::default();
+        hasher.process(b"ENE-STORAGE");
+        hasher.process(&[context.len() as u8]);
+        hasher.process(context.as_bytes());
+        sk.read_secret(|bytes| hasher.process(bytes));
+        hasher.xof_result().read(output);
+
+        Ok(())
+    }
 }
 
 impl<'a> And<'a> {
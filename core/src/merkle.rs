@@ -0,0 +1,165 @@
+//! Merkle tree of append-only log, RFC 6962 style
+//!
+//! Leaves and nodes are domain separated by prefix byte,
+//! Shake256 with 32 bytes output is used as hash function.
+
+use sha3::Shake256;
+use digest::{ Input, ExtendableOutput, XofReader };
+
+
+pub type Hash = [u8; 32];
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut output = [0; 32];
+    let mut hasher = Shake256::default();
+    hasher.process(&[0x00]);
+    hasher.process(data);
+    hasher.xof_result().read(&mut output);
+    output
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut output = [0; 32];
+    let mut hasher = Shake256::default();
+    hasher.process(&[0x01]);
+    hasher.process(left);
+    hasher.process(right);
+    hasher.xof_result().read(&mut output);
+    output
+}
+
+/// Largest power of two smaller than `n`, `n > 1`.
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// Root hash of leaf hashes, the empty tree hashes empty string.
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => {
+            let mut output = [0; 32];
+            Shake256::default().xof_result().read(&mut output);
+            output
+        },
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+/// Consistency proof that the first `m` leaves are a prefix of `leaves`.
+pub fn consistency_proof(leaves: &[Hash], m: usize) -> Vec<Hash> {
+    fn subproof(m: usize, leaves: &[Hash], complete: bool, proof: &mut Vec<Hash>) {
+        let n = leaves.len();
+
+        if m == n {
+            if !complete {
+                proof.push(root(leaves));
+            }
+        } else {
+            let k = split(n);
+
+            if m <= k {
+                subproof(m, &leaves[..k], complete, proof);
+                proof.push(root(&leaves[k..]));
+            } else {
+                subproof(m - k, &leaves[k..], false, proof);
+                proof.push(root(&leaves[..k]));
+            }
+        }
+    }
+
+    let mut proof = Vec::new();
+    if 0 < m && m < leaves.len() {
+        subproof(m, leaves, true, &mut proof);
+    }
+    proof
+}
+
+/// Verify that the tree of size `m` with `old_root`
+/// is a prefix of the tree of size `n` with `new_root`.
+pub fn verify_consistency(m: usize, n: usize, old_root: &Hash, new_root: &Hash, proof: &[Hash]) -> bool {
+    if m > n {
+        return false;
+    } else if m == n {
+        return proof.is_empty() && old_root == new_root;
+    } else if m == 0 {
+        return proof.is_empty();
+    }
+
+    let mut path = Vec::with_capacity(proof.len() + 1);
+    if m.is_power_of_two() {
+        path.push(*old_root);
+    }
+    path.extend_from_slice(proof);
+
+    let (first, rest) = match path.split_first() {
+        Some(item) => item,
+        None => return false
+    };
+
+    let mut fnode = m - 1;
+    let mut snode = n - 1;
+    while fnode & 1 == 1 {
+        fnode >>= 1;
+        snode >>= 1;
+    }
+
+    let mut fr = *first;
+    let mut sr = *first;
+
+    for c in rest {
+        if snode == 0 {
+            return false;
+        }
+
+        if fnode & 1 == 1 || fnode == snode {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+
+            while fnode & 1 == 0 && fnode != 0 {
+                fnode >>= 1;
+                snode >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+
+        fnode >>= 1;
+        snode >>= 1;
+    }
+
+    snode == 0 && &fr == old_root && &sr == new_root
+}
+
+
+#[test]
+fn test_consistency() {
+    let leaves = (0..20u8)
+        .map(|i| leaf_hash(&[i]))
+        .collect::<Vec<_>>();
+
+    for n in 1..=leaves.len() {
+        let new_root = root(&leaves[..n]);
+
+        for m in 0..=n {
+            let old_root = root(&leaves[..m]);
+            let proof = consistency_proof(&leaves[..n], m);
+            assert!(verify_consistency(m, n, &old_root, &new_root, &proof), "{} {}", m, n);
+
+            if 0 < m && m < n {
+                let mut rewritten = leaves[..n].to_vec();
+                rewritten[m - 1] = leaf_hash(b"rewritten");
+                let rewritten_root = root(&rewritten);
+                let proof = consistency_proof(&rewritten, m);
+                assert!(!verify_consistency(m, n, &old_root, &rewritten_root, &proof), "{} {}", m, n);
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Mnemonic encoding
+//!
+//! Use BIP39 English wordlist, but checksum with SHA3-256,
+//! so it is not compatible with BIP39.
+
+use sha3::{ Digest, Sha3_256 };
+use crate::error::ParseError;
+
+
+const WORDLIST: &str = include_str!("english.txt");
+
+fn words() -> impl Iterator<Item = &'static str> {
+    WORDLIST.lines()
+}
+
+/// Encode entropy (16, 20, 24, 28 or 32 bytes) to words.
+pub fn encode(entropy: &[u8]) -> Result<Vec<&'static str>, ParseError> {
+    if entropy.len() % 4 != 0 || entropy.len() < 16 || entropy.len() > 32 {
+        return Err(ParseError::NotAvailable("entropy length".into()));
+    }
+
+    let checksum = Sha3_256::digest(entropy)[0];
+    let bit = |i: usize| if i < entropy.len() * 8 {
+        (entropy[i / 8] >> (7 - i % 8)) & 1
+    } else {
+        (checksum >> (7 - (i - entropy.len() * 8))) & 1
+    };
+
+    let count = (entropy.len() * 8 + entropy.len() / 4) / 11;
+    let wordlist = words().collect::<Vec<_>>();
+
+    Ok((0..count)
+        .map(|n| (0..11).fold(0, |index, i| (index << 1) | bit(n * 11 + i) as usize))
+        .map(|index| wordlist[index])
+        .collect())
+}
+
+/// Decode words to entropy.
+pub fn decode(phrase: &str) -> Result<Vec<u8>, ParseError> {
+    let wordlist = words().collect::<Vec<_>>();
+    let indexes = phrase.split_whitespace()
+        .map(|word| {
+            let word = word.to_lowercase();
+            wordlist.binary_search(&word.as_str())
+                .map_err(|_| ParseError::Unknown(word.into()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if indexes.len() % 3 != 0 || indexes.len() < 12 || indexes.len() > 24 {
+        return Err(ParseError::NotAvailable("mnemonic length".into()));
+    }
+
+    let total = indexes.len() * 11;
+    let checksum_len = total / 33;
+    let bit = |i: usize| (indexes[i / 11] >> (10 - i % 11)) & 1;
+
+    let mut entropy = vec![0; (total - checksum_len) / 8];
+    for (i, byte) in entropy.iter_mut().enumerate() {
+        *byte = (0..8).fold(0, |b, j| (b << 1) | bit(i * 8 + j) as u8);
+    }
+
+    let checksum = (0..checksum_len)
+        .fold(0, |c, j| (c << 1) | bit(entropy.len() * 8 + j) as u8);
+
+    if Sha3_256::digest(&entropy)[0] >> (8 - checksum_len) == checksum {
+        Ok(entropy)
+    } else {
+        Err(ParseError::InvalidChecksum)
+    }
+}
+
+
+#[test]
+fn test_mnemonic() {
+    use rand::{ Rng, thread_rng };
+
+    let mut rng = thread_rng();
+
+    for &len in &[16, 20, 24, 28, 32] {
+        let mut entropy = vec![0; len];
+        rng.fill(entropy.as_mut_slice());
+
+        let phrase = encode(&entropy).unwrap();
+        assert_eq!(phrase.len(), len * 3 / 4);
+
+        let phrase = phrase.join(" ");
+        assert_eq!(decode(&phrase).unwrap(), entropy);
+        assert_eq!(decode(&phrase.to_uppercase()).unwrap(), entropy);
+    }
+
+    assert!(encode(&[0; 15]).is_err());
+    assert!(decode("abandon abandon").is_err());
+    assert!(decode(&["zoo"; 24].join(" ")).is_err());
+}
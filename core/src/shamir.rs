@@ -0,0 +1,128 @@
+//! Shamir's Secret Sharing
+//!
+//! over GF(2^8), with the AES reduction polynomial.
+
+use rand::{ Rng, CryptoRng };
+use crate::error::ProtoError;
+
+
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut r = 0;
+
+    for _ in 0..8 {
+        r ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+
+    r
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 = a^-1
+    let mut r = 1;
+    let mut base = a;
+    let mut e = 254u8;
+
+    while e > 0 {
+        if e & 1 == 1 {
+            r = gf_mul(r, base);
+        }
+        base = gf_mul(base, base);
+        e >>= 1;
+    }
+
+    r
+}
+
+/// Split secret into `count` shares, any `threshold` of them can recover it.
+pub fn split<RNG: Rng + CryptoRng>(rng: &mut RNG, secret: &[u8], threshold: u8, count: u8)
+    -> Result<Vec<(u8, Vec<u8>)>, ProtoError>
+{
+    if threshold == 0 || threshold > count {
+        return Err(ProtoError::InvalidValue("threshold out of range"));
+    }
+
+    let mut shares = (1..=count)
+        .map(|x| (x, vec![0; secret.len()]))
+        .collect::<Vec<_>>();
+    let mut coeffs = vec![0; threshold as usize];
+
+    for (i, &s) in secret.iter().enumerate() {
+        coeffs[0] = s;
+        rng.fill(&mut coeffs[1..]);
+
+        for (x, share) in &mut shares {
+            share[i] = coeffs.iter()
+                .rev()
+                .fold(0, |y, &c| gf_mul(y, *x) ^ c);
+        }
+    }
+
+    for c in &mut coeffs {
+        *c = 0;
+    }
+
+    Ok(shares)
+}
+
+/// Recover secret from shares.
+pub fn combine(shares: &[(u8, &[u8])]) -> Result<Vec<u8>, ProtoError> {
+    let len = match shares.first() {
+        Some((_, share)) => share.len(),
+        None => return Err(ProtoError::InvalidLength)
+    };
+
+    for (i, (x, share)) in shares.iter().enumerate() {
+        if share.len() != len {
+            return Err(ProtoError::InvalidLength);
+        }
+
+        if *x == 0 || shares[..i].iter().any(|(x2, _)| x2 == x) {
+            return Err(ProtoError::InvalidValue("bad share index"));
+        }
+    }
+
+    let mut secret = vec![0; len];
+
+    for (j, (xj, share)) in shares.iter().enumerate() {
+        // lagrange basis polynomial at zero
+        let basis = shares.iter()
+            .enumerate()
+            .filter(|&(m, _)| m != j)
+            .fold(1, |basis, (_, (xm, _))| gf_mul(basis, gf_mul(*xm, gf_inv(xm ^ xj))));
+
+        for (s, &y) in secret.iter_mut().zip(share.iter()) {
+            *s ^= gf_mul(basis, y);
+        }
+    }
+
+    Ok(secret)
+}
+
+
+#[test]
+fn test_shamir() {
+    use rand::{ Rng, thread_rng };
+
+    let mut rng = thread_rng();
+
+    let mut secret = vec![0; 97];
+    rng.fill(secret.as_mut_slice());
+
+    let shares = split(&mut rng, &secret, 2, 3).unwrap();
+    assert_eq!(shares.len(), 3);
+
+    for (i, j) in &[(0, 1), (0, 2), (2, 1)] {
+        let (xi, si) = &shares[*i];
+        let (xj, sj) = &shares[*j];
+        let secret2 = combine(&[(*xi, &si[..]), (*xj, &sj[..])]).unwrap();
+        assert_eq!(secret2, secret);
+    }
+
+    let (x, s) = &shares[0];
+    assert_ne!(combine(&[(*x, &s[..])]).unwrap(), secret);
+    assert!(combine(&[(*x, &s[..]), (*x, &s[..])]).is_err());
+    assert!(split(&mut rng, &secret, 4, 3).is_err());
+}
@@ -77,3 +77,47 @@ fn test_sigae_ed25519dhaes128colm0() {
 
     assert_eq!(msg2, msg.as_bytes());
 }
+
+#[test]
+fn test_derive() {
+    let mut rng = thread_rng();
+
+    let mut seed = [0; 32];
+    rng.fill(&mut seed);
+
+    let alice = "alice@core.ene";
+    let alice_pk = Builder::default().derive(alice, &seed).as_secret().to_public();
+    let alice_pk2 = Builder::default().derive(alice, &seed).as_secret().to_public();
+    let alice_pk3 = Builder::all().derive(alice, &seed).as_secret().to_public();
+
+    assert!(alice_pk.ed25519.is_some());
+    assert!(alice_pk.ed25519 == alice_pk2.ed25519);
+    assert!(alice_pk.ristrettodh == alice_pk2.ristrettodh);
+    assert!(alice_pk.ed25519 == alice_pk3.ed25519);
+    assert!(alice_pk.ristrettodh == alice_pk3.ristrettodh);
+
+    rng.fill(&mut seed);
+    let bob_pk = Builder::default().derive(alice, &seed).as_secret().to_public();
+    assert!(alice_pk.ed25519 != bob_pk.ed25519);
+}
+
+#[test]
+fn test_derive_key() {
+    let mut rng = thread_rng();
+
+    let alice = Builder::default().generate("alice@core.ene", &mut rng);
+    let bob = Builder::default().generate("bob@core.ene", &mut rng);
+
+    let (mut k1, mut k2, mut k3, mut k4) = ([0; 32], [0; 32], [0; 32], [0; 32]);
+    alice.derive_key("contact", &mut k1).unwrap();
+    alice.derive_key("contact", &mut k2).unwrap();
+    alice.derive_key("other", &mut k3).unwrap();
+    bob.derive_key("contact", &mut k4).unwrap();
+
+    assert_eq!(k1, k2);
+    assert_ne!(k1, k3);
+    assert_ne!(k1, k4);
+
+    let empty = Builder::empty().generate("carol@core.ene", &mut rng);
+    assert!(empty.derive_key("contact", &mut k1).is_err());
+}
@@ -0,0 +1,136 @@
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::os::unix::net::UnixStream;
+use failure::{ Fallible, err_msg };
+use serde_bytes::Bytes;
+use crate::core::key;
+use crate::core::alg::{ self, Protocol };
+use crate::core::format::Message;
+use crate::core::contact::Seal;
+use crate::core::error::StoreError;
+use crate::contact::backend;
+use crate::profile::Settings;
+use super::{ Request, Response, SealOp, read_frame, write_frame };
+
+
+#[derive(Clone)]
+pub struct Client {
+    path: PathBuf,
+    id: String,
+    pk: key::PublicKey,
+    settings: Settings
+}
+
+impl Client {
+    /// Query the Profile of agent, return `None` if agent is not running.
+    pub fn connect(path: &Path) -> Fallible<Option<Client>> {
+        let mut stream = match UnixStream::connect(path) {
+            Ok(stream) => stream,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound
+                || err.kind() == io::ErrorKind::ConnectionRefused => return Ok(None),
+            Err(err) => return Err(err.into())
+        };
+
+        match call(&mut stream, &Request::Info)? {
+            Response::Info(id, pk, settings) =>
+                Ok(Some(Client { path: path.to_path_buf(), id, pk, settings })),
+            _ => Err(err_msg("unexpected agent response"))
+        }
+    }
+
+    fn call(&self, request: &Request<&key::PublicKey, Bytes>) -> Fallible<Response> {
+        call(&mut UnixStream::connect(&self.path)?, request)
+    }
+
+    pub fn info(&self) -> (&str, &key::PublicKey, Settings) {
+        (&self.id, &self.pk, self.settings)
+    }
+
+    pub fn sendto(&self, (id, pk): (&str, &key::PublicKey), proto: &Protocol, aad: &[u8], message: &[u8])
+        -> Fallible<Message>
+    {
+        let request = Request::SendTo(
+            id.to_string(), pk, proto.clone(),
+            Bytes::new(aad), Bytes::new(message)
+        );
+
+        match self.call(&request)? {
+            Response::SendTo(message) => Ok(message),
+            _ => Err(err_msg("unexpected agent response"))
+        }
+    }
+
+    pub fn recvfrom(
+        &self,
+        (id, pk): (&str, &key::PublicKey),
+        proto: &Protocol,
+        aad: &[u8],
+        message: &[u8],
+        plaintext: Option<&[u8]>
+    ) -> Fallible<Vec<u8>> {
+        let request = Request::RecvFrom(
+            id.to_string(), pk, proto.clone(),
+            Bytes::new(aad), Bytes::new(message),
+            plaintext.map(Bytes::new)
+        );
+
+        match self.call(&request)? {
+            Response::RecvFrom(message) => Ok(message.into()),
+            _ => Err(err_msg("unexpected agent response"))
+        }
+    }
+
+    /// Contact store keys, which stay in agent.
+    pub fn sealer(&self, context: &str, enc: alg::Encrypt) -> AgentSealer {
+        AgentSealer { client: self.clone(), context: context.to_string(), enc }
+    }
+
+    pub fn stop(&self) -> Fallible<()> {
+        match self.call(&Request::Stop)? {
+            Response::Stop => Ok(()),
+            _ => Err(err_msg("unexpected agent response"))
+        }
+    }
+}
+
+pub struct AgentSealer {
+    client: Client,
+    context: String,
+    enc: alg::Encrypt
+}
+
+impl AgentSealer {
+    fn call(&self, op: SealOp<Bytes>) -> Result<Vec<u8>, StoreError> {
+        backend(|| match self.client.call(&Request::Seal(self.context.clone(), self.enc, op))? {
+            Response::Seal(output) => Ok(output.into()),
+            _ => Err(err_msg("unexpected agent response"))
+        })
+    }
+}
+
+impl Seal for AgentSealer {
+    fn hash_id(&self, id: &str) -> Result<Vec<u8>, StoreError> {
+        self.call(SealOp::HashId(id.to_string()))
+    }
+
+    fn tag(&self, id: &str, record: &[u8]) -> Result<Vec<u8>, StoreError> {
+        self.call(SealOp::Tag(id.to_string(), Bytes::new(record)))
+    }
+
+    fn seal(&self, aad: &[u8], input: &[u8]) -> Result<Vec<u8>, StoreError> {
+        self.call(SealOp::Seal(Bytes::new(aad), Bytes::new(input)))
+    }
+
+    fn open(&self, aad: &[u8], input: &[u8]) -> Result<Vec<u8>, StoreError> {
+        self.call(SealOp::Open(Bytes::new(aad), Bytes::new(input)))
+    }
+}
+
+fn call(stream: &mut UnixStream, request: &Request<&key::PublicKey, Bytes>) -> Fallible<Response> {
+    write_frame(stream, request)?;
+
+    match read_frame(stream)? {
+        Response::Err(err) => Err(err_msg(err)),
+        response => Ok(response)
+    }
+}
@@ -0,0 +1,277 @@
+#[cfg(unix)] mod server;
+#[cfg(unix)] mod client;
+
+use std::fs::File;
+use std::path::{ Path, PathBuf };
+use failure::Fallible;
+use serde::{ Serialize, de::DeserializeOwned };
+use serde_derive::{ Serialize, Deserialize };
+use serde_bytes::ByteBuf;
+use serde_cbor as cbor;
+use directories::ProjectDirs;
+use seckey::SecKey;
+use crate::core::{ key, Ene };
+use crate::core::contact::{ Seal, Sealer };
+use crate::core::alg::{ self, Protocol };
+use crate::core::format::{ PrivateKey, Message };
+use crate::common::{ Cbor, Stdio, askpass_with };
+use crate::opts::Agent;
+use crate::profile::{ self, Settings, keyring::Keyring };
+#[cfg(unix)] use self::client::Client;
+
+
+/// Socket of agent, in a directory only accessible by the owner
+pub const SOCKET: &str = "agent/agent.sock";
+
+/// Agent Request
+#[derive(Serialize, Deserialize)]
+pub enum Request<PK, B> {
+    /// Query ID and PublicKey
+    Info,
+
+    /// Target ID, PublicKey, Protocol, Associated Data and Message
+    SendTo(String, PK, Protocol, B, B),
+
+    /// Target ID, PublicKey, Protocol, Associated Data, Message and Plaintext
+    RecvFrom(String, PK, Protocol, B, B, Option<B>),
+
+    /// Storage key context, Encrypt algorithm and operation,
+    /// the storage key never leaves agent
+    Seal(String, alg::Encrypt, SealOp<B>),
+
+    /// Stop agent
+    Stop
+}
+
+/// Agent Response
+#[derive(Serialize, Deserialize)]
+pub enum Response {
+    Info(String, key::PublicKey, Settings),
+    SendTo(Message),
+    RecvFrom(ByteBuf),
+    Seal(ByteBuf),
+    Stop,
+    Err(String)
+}
+
+/// Operation of `contact::Seal`
+#[derive(Serialize, Deserialize)]
+pub enum SealOp<B> {
+    HashId(String),
+    Tag(String, B),
+    Seal(B, B),
+    Open(B, B)
+}
+
+/// Unlocked Profile
+///
+/// The secret key is held locally, or by agent.
+pub struct Session {
+    id: String,
+    pk: key::PublicKey,
+    settings: Settings,
+    inner: Inner
+}
+
+enum Inner {
+    Local(SecKey<Ene>),
+    #[cfg(unix)] Agent(Client)
+}
+
+impl Agent {
+    #[cfg(unix)]
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        use std::time::Duration;
+
+        let socket_path = dir.data_local_dir().join(SOCKET);
+
+        if self.start {
+            let sk_path = Keyring::new(dir)?.resolve(
+                self.profile.as_ref().map(PathBuf::as_path),
+                self.as_id.as_ref().map(String::as_str)
+            )?;
+            let (sk, settings) = unlock(&sk_path)?;
+            let sk = sk.read();
+
+            if !quiet {
+                stdio.info(format_args!(
+                    "Agent is listening on {}\n\nuid: {}",
+                    socket_path.display(), sk.get_id()
+                ))?;
+            }
+
+            server::serve(&socket_path, &sk, settings, Duration::from_secs(self.timeout))?;
+        } else if self.stop {
+            Client::connect(&socket_path)?
+                .ok_or_else(|| failure::err_msg("Agent is not running"))?
+                .stop()?;
+
+            if !quiet {
+                stdio.info(format_args!("Agent has been stopped"))?;
+            }
+        } else {
+            unreachable!()
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn exec(self, _dir: &ProjectDirs, _quiet: bool, _stdio: &mut Stdio) -> Fallible<()> {
+        Err(failure::err_msg("Agent is only supported on unix"))
+    }
+}
+
+impl Session {
+    /// Use agent if it is running and holds the wanted Profile,
+    /// otherwise decrypt the Profile locally.
+    pub fn open(dir: &ProjectDirs, profile: Option<&Path>, id: Option<&str>) -> Fallible<Session> {
+        if profile.is_none() {
+            if let Some(session) = Session::agent(dir)? {
+                if id.map(|id| id == session.id).unwrap_or(true) {
+                    return Ok(session);
+                }
+            }
+        }
+
+        let sk_path = Keyring::new(dir)?.resolve(profile, id)?;
+        let (sk, settings) = unlock(&sk_path)?;
+        let (id, pk) = {
+            let sk = sk.read();
+            (sk.get_id().to_string(), sk.as_secret().to_public())
+        };
+
+        Ok(Session { id, pk, settings, inner: Inner::Local(sk) })
+    }
+
+    /// Profile held by agent, `None` if agent is not running.
+    #[cfg(unix)]
+    pub fn agent(dir: &ProjectDirs) -> Fallible<Option<Session>> {
+        Ok(Client::connect(&dir.data_local_dir().join(SOCKET))?
+            .map(|client| {
+                let (id, pk, settings) = client.info();
+                let (id, pk) = (id.to_string(), pk.clone());
+                Session { id, pk, settings, inner: Inner::Agent(client) }
+            }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn agent(_dir: &ProjectDirs) -> Fallible<Option<Session>> {
+        Ok(None)
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn as_public(&self) -> &key::PublicKey {
+        &self.pk
+    }
+
+    /// Settings sealed in the Profile
+    pub fn settings(&self) -> Settings {
+        self.settings
+    }
+
+    pub fn sendto(&self, (id, pk): (&str, &key::PublicKey), proto: &Protocol, aad: &[u8], message: &[u8])
+        -> Fallible<Message>
+    {
+        match &self.inner {
+            Inner::Local(sk) => sk.read()
+                .and(id, pk)
+                .sendto::<Cbor>(proto, aad, message)
+                .map_err(Into::into),
+            #[cfg(unix)]
+            Inner::Agent(client) => client.sendto((id, pk), proto, aad, message)
+        }
+    }
+
+    pub fn recvfrom(
+        &self,
+        (id, pk): (&str, &key::PublicKey),
+        proto: &Protocol,
+        aad: &[u8],
+        message: &[u8],
+        plaintext: Option<&[u8]>
+    ) -> Fallible<Vec<u8>> {
+        match &self.inner {
+            Inner::Local(sk) => sk.read()
+                .and(id, pk)
+                .recvfrom::<Cbor>(proto, aad, message, plaintext)
+                .map_err(Into::into),
+            #[cfg(unix)]
+            Inner::Agent(client) => client.recvfrom((id, pk), proto, aad, message, plaintext)
+        }
+    }
+
+    /// Detached Ed25519 signature over `content`, see `common::verify`.
+    pub fn sign(&self, aad: &[u8], content: &[u8]) -> Fallible<Message> {
+        self.sendto(
+            (&self.id, &self.pk),
+            &Protocol::Sonly(alg::Signature::Ed25519),
+            aad,
+            content
+        )
+    }
+
+    /// Keys of protected contact store, derived from the Profile and bound to `context`.
+    pub fn sealer(&self, context: &str, enc: alg::Encrypt) -> Fallible<Box<dyn Seal>> {
+        match &self.inner {
+            Inner::Local(sk) => Ok(Box::new(derive_sealer(&sk.read(), context, enc)?)),
+            #[cfg(unix)]
+            Inner::Agent(client) => Ok(Box::new(client.sealer(context, enc)))
+        }
+    }
+}
+
+/// The storage key is only held until `Sealer` derives its keys.
+fn derive_sealer(ene: &Ene, context: &str, enc: alg::Encrypt) -> Fallible<Sealer> {
+    let mut key = SecKey::new([0; 32])
+        .map_err(|_| failure::err_msg("Secure alloc fail"))?;
+    ene.derive_key(context, &mut key.write()[..])?;
+    let sealer = Sealer::new(enc, &key.read()[..])?;
+    Ok(sealer)
+}
+
+fn unlock(path: &Path) -> Fallible<(SecKey<Ene>, Settings)> {
+    let sk_packed: PrivateKey = cbor::from_reader(&mut File::open(path)?)?;
+    let (id, ..) = unwrap!(&sk_packed);
+    askpass_with(&format!("Password of {}:", id), |pass| profile::open(pass.as_bytes(), &sk_packed))
+}
+
+#[cfg(unix)]
+fn write_frame<W: std::io::Write, T: Serialize>(w: &mut W, value: &T) -> Fallible<()> {
+    let buf = cbor::to_vec(value)?;
+    let len = buf.len() as u32;
+
+    w.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    w.write_all(&buf)?;
+    w.flush()?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_frame<R: std::io::Read, T: DeserializeOwned>(r: &mut R) -> Fallible<T> {
+    use std::io::Read;
+
+    const MAX_LENGTH: usize = 1 << 28;
+
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = len.iter().fold(0, |sum, &b| (sum << 8) | b as usize);
+
+    if len > MAX_LENGTH {
+        return Err(failure::err_msg("frame too large"));
+    }
+
+    // grow with the received data instead of trusting the length
+    let mut buf = Vec::new();
+    r.take(len as u64).read_to_end(&mut buf)?;
+
+    if buf.len() != len {
+        return Err(failure::err_msg("frame truncated"));
+    }
+
+    cbor::from_slice(&buf).map_err(Into::into)
+}
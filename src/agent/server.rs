@@ -0,0 +1,160 @@
+use std::{ fs, io, thread };
+use std::io::Read;
+use std::path::Path;
+use std::time::{ Duration, Instant };
+use std::os::unix::fs::{ DirBuilderExt, PermissionsExt };
+use std::os::unix::net::{ UnixListener, UnixStream };
+use failure::{ Fallible, err_msg };
+use serde_bytes::ByteBuf;
+use crate::core::{ alg, key, Ene };
+use crate::core::contact::Seal;
+use crate::common::Cbor;
+use crate::profile::Settings;
+use super::{ Request, Response, SealOp, derive_sealer, read_frame, write_frame };
+use super::client::Client;
+
+
+/// A client must send its request and take the response within this time,
+/// so an idle connection can not stall the agent.
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+struct Socket<'a>(&'a Path);
+
+impl<'a> Drop for Socket<'a> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.0);
+    }
+}
+
+/// Serve until idle timeout or stop request.
+///
+/// The socket is bound in a directory only accessible by the owner,
+/// so there is no window in which other users can connect.
+pub fn serve(path: &Path, ene: &Ene, settings: Settings, timeout: Duration) -> Fallible<()> {
+    let parent = path.parent()
+        .ok_or_else(|| err_msg("Agent socket has no parent directory"))?;
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+    fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
+
+    if path.exists() {
+        if Client::connect(path)?.is_some() {
+            return Err(err_msg(format!("Agent is already running: {}", path.display())));
+        }
+
+        // stale socket
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let _socket = Socket(path);
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+
+    let mut last = Instant::now();
+
+    loop {
+        match listener.accept() {
+            Ok((mut stream, _)) => {
+                stream.set_nonblocking(false)?;
+
+                match handle(&mut stream, ene, settings) {
+                    Ok((used, stop)) => {
+                        if used {
+                            last = Instant::now();
+                        }
+
+                        if stop {
+                            break
+                        }
+                    },
+                    Err(_) => ()
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if last.elapsed() > timeout {
+                    break
+                }
+
+                thread::sleep(Duration::from_millis(100));
+            },
+            Err(err) => return Err(err.into())
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle one request, return whether the secret key was used and whether to stop.
+fn handle(stream: &mut UnixStream, ene: &Ene, settings: Settings) -> Fallible<(bool, bool)> {
+    let deadline = Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+    let request: Request<key::PublicKey, ByteBuf> =
+        read_frame(&mut Deadline { stream, deadline })?;
+
+    let (response, used, stop) = match request {
+        Request::Info => {
+            let pk = ene.as_secret().to_public();
+            (Response::Info(ene.get_id().to_string(), pk, settings), false, false)
+        },
+        Request::SendTo(id, pk, proto, aad, message) => {
+            let response = ene.and(&id, &pk)
+                .sendto::<Cbor>(&proto, &aad, &message)
+                .map(Response::SendTo)
+                .unwrap_or_else(|err| Response::Err(err.to_string()));
+            (response, true, false)
+        },
+        Request::RecvFrom(id, pk, proto, aad, message, plaintext) => {
+            let response = ene.and(&id, &pk)
+                .recvfrom::<Cbor>(&proto, &aad, &message, plaintext.as_ref().map(|p| &p[..]))
+                .map(|message| Response::RecvFrom(ByteBuf::from(message)))
+                .unwrap_or_else(|err| Response::Err(err.to_string()));
+            (response, true, false)
+        },
+        Request::Seal(context, enc, op) => {
+            let response = seal(ene, &context, enc, op)
+                .map(|output| Response::Seal(ByteBuf::from(output)))
+                .unwrap_or_else(|err| Response::Err(err.to_string()));
+            (response, true, false)
+        },
+        Request::Stop => (Response::Stop, false, true)
+    };
+
+    write_frame(stream, &response)?;
+
+    Ok((used, stop))
+}
+
+/// Contact store operation, the storage key is derived for each request and never sent.
+fn seal(ene: &Ene, context: &str, enc: alg::Encrypt, op: SealOp<ByteBuf>) -> Fallible<Vec<u8>> {
+    let sealer = derive_sealer(ene, context, enc)?;
+
+    let output = match op {
+        SealOp::HashId(id) => sealer.hash_id(&id)?,
+        SealOp::Tag(id, record) => sealer.tag(&id, &record)?,
+        SealOp::Seal(aad, input) => sealer.seal(&aad, &input)?,
+        SealOp::Open(aad, input) => sealer.open(&aad, &input)?
+    };
+
+    Ok(output)
+}
+
+/// Reader of a stream which fails once the deadline has passed,
+/// a read timeout alone would let a slow client send byte by byte.
+struct Deadline<'a> {
+    stream: &'a mut UnixStream,
+    deadline: Instant
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+
+        if now >= self.deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        self.stream.set_read_timeout(Some(self.deadline - now))?;
+        self.stream.read(buf)
+    }
+}
@@ -1,12 +1,15 @@
 use std::{ env, fmt };
 use std::io::{ self, Write };
 use std::process::{ Command, Termination, ExitCode };
-use failure::{ Fallible, Error };
+use failure::{ Fallible, Error, Fail, err_msg };
 use serde::{ Serialize, Deserialize };
 use serde_cbor as cbor;
 use serde_cbor::error::Error as CborError;
 use termcolor::{ StandardStream, ColorChoice, ColorSpec, Color, WriteColor };
-use crate::core::error;
+use crate::core::{ alg, error, key };
+use crate::core::proto::sonly;
+use crate::core::key::ed25519::Ed25519;
+use crate::core::format::{ Message, Meta };
 use crate::core::define::Serde;
 use crate::opts::ColorChoice as ColorChoice2;
 
@@ -28,6 +31,15 @@ macro_rules! unwrap {
 
 pub struct Exit<E>(pub Result<(), E>, pub Stdio);
 
+/// Contact key has changed, exit with a distinct status
+#[derive(Debug, Fail)]
+#[fail(display = "PublicKey of {} has changed, verify it and run `ene contact {} --accept-change`", _0, _0)]
+pub struct KeyChanged(pub String);
+
+impl KeyChanged {
+    pub const EXIT_CODE: i32 = 3;
+}
+
 impl Termination for Exit<Error> {
     fn report(self) -> i32 {
         let Exit(result, mut stdio) = self;
@@ -57,7 +69,11 @@ impl Termination for Exit<Error> {
                     Ok(())
                 });
 
-                ExitCode::FAILURE.report()
+                if err.downcast_ref::<KeyChanged>().is_some() {
+                    KeyChanged::EXIT_CODE
+                } else {
+                    ExitCode::FAILURE.report()
+                }
             }
         }
     }
@@ -68,11 +84,16 @@ pub fn askpass<F, T>(f: F)
     -> Fallible<T>
     where F: FnOnce(&str) -> Fallible<T>
 {
-    const PROMPT: &str = "Password:";
+    askpass_with("Password:", f)
+}
 
+pub fn askpass_with<F, T>(prompt: &str, f: F)
+    -> Fallible<T>
+    where F: FnOnce(&str) -> Fallible<T>
+{
     if let Ok(bin) = env::var("ENE_ASKPASS") {
         Command::new(bin)
-            .arg(PROMPT)
+            .arg(prompt)
             .output()
             .map_err(Into::into)
             .and_then(|output| {
@@ -80,11 +101,104 @@ pub fn askpass<F, T>(f: F)
                 f(&pw)
             })
     } else {
-        ttyaskpass::askpass(PROMPT, f)
+        ttyaskpass::askpass(prompt, f)
     }
 }
 
 
+/// Ask a yes or no question on the terminal,
+/// always `false` if there is no terminal or `ENE_ASKPASS` is set.
+pub fn confirm(prompt: &str) -> bool {
+    #[cfg(unix)] {
+        use std::fs::OpenOptions;
+        use std::io::{ BufRead, BufReader };
+
+        if env::var_os("ENE_ASKPASS").is_some() {
+            return false;
+        }
+
+        if let Ok(mut tty) = OpenOptions::new().read(true).write(true).open("/dev/tty") {
+            let mut answer = String::new();
+
+            if write!(tty, "{} [y/N] ", prompt).is_ok()
+                && BufReader::new(tty).read_line(&mut answer).is_ok()
+            {
+                let answer = answer.trim().to_lowercase();
+                return answer == "y" || answer == "yes";
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = prompt;
+
+    false
+}
+
+/// Verify a detached signature made by `Session::sign`, return the signer.
+pub fn verify(message: Message, aad: &[u8], content: &[u8]) -> Fallible<(String, key::PublicKey)> {
+    let (Meta { s: (signer_id, signer_pk), .. }, proto, sig) = unwrap!(message);
+
+    if proto != alg::Protocol::Sonly(alg::Signature::Ed25519) {
+        return Err(err_msg(format!("Unsupported signature: {:?}", proto)));
+    }
+
+    let sig: sonly::Message<Ed25519> = cbor::from_slice(&sig)?;
+    let sig_pk = signer_pk.ed25519.as_ref()
+        .ok_or_else(|| err_msg("Signer has no Ed25519 PublicKey"))?;
+    sonly::recv::<Ed25519>((&signer_id, sig_pk), &sig, aad, content)
+        .map_err(|err| err_msg(format!("Signature is invalid: {}", err)))?;
+
+    Ok((signer_id, signer_pk))
+}
+
+/// Format unix timestamp as UTC date time
+pub fn format_time(secs: u64) -> String {
+    if secs == 0 {
+        return "unknown".into();
+    }
+
+    let (y, m, d) = civil_from_days((secs / 86400) as i64);
+
+    let secs = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        y, m, d,
+        secs / 3600, secs % 3600 / 60, secs % 60
+    )
+}
+
+/// Year, month and day of days since unix epoch
+///
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = (if days >= 0 { days } else { days - 146_096 }) / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+    (y, m, d)
+}
+
+/// Days since unix epoch of year, month and day
+///
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+
 pub struct Cbor;
 
 impl Serde for Cbor {
@@ -161,3 +275,17 @@ impl Stdio {
         f(&mut self.stderr)
     }
 }
+
+
+#[test]
+fn test_format_time() {
+    assert_eq!(format_time(0), "unknown");
+    assert_eq!(format_time(1), "1970-01-01 00:00:01 UTC");
+    assert_eq!(format_time(951_782_400), "2000-02-29 00:00:00 UTC");
+    assert_eq!(format_time(4_107_542_399), "2100-02-28 23:59:59 UTC");
+
+    for &days in &[-719_468, -1, 0, 11_016, 2_932_896] {
+        let (y, m, d) = civil_from_days(days);
+        assert_eq!(days_from_civil(y, m, d), days);
+    }
+}
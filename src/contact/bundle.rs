@@ -0,0 +1,185 @@
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use failure::{ Fallible, err_msg };
+use serde_bytes::ByteBuf;
+use serde_cbor as cbor;
+use crate::core::format::{ Bundle, BundleContent, Envelope };
+use crate::core::contact::{ self, ContactStore, Record, Source, Status, check_id };
+use crate::agent::Session;
+use crate::common::{ Stdio, format_time, verify };
+
+
+const BUNDLE_AAD: &[u8] = b"ENE-BUNDLE";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Change {
+    New,
+    Unchanged,
+    Changed,
+    Conflict
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Change::New => "new",
+            Change::Unchanged => "unchanged",
+            Change::Changed => "changed",
+            Change::Conflict => "conflict"
+        })
+    }
+}
+
+/// Export the contacts with ID prefix, optionally signed by `session`.
+pub fn export(
+    stdio: &mut Stdio,
+    db: &dyn ContactStore,
+    prefix: &str,
+    session: Option<&Session>,
+    path: &Path
+) -> Fallible<usize> {
+    let mut entries = Vec::new();
+
+    for item in db.scan(prefix) {
+        match item {
+            Ok((id, record)) => entries.push((id, record.name, record.pk)),
+            Err(err) => stdio.warn(format_args!("{:?}", err))?
+        }
+    }
+
+    let count = entries.len();
+    let content: BundleContent = (contact::now(), entries);
+    let content = cbor::to_vec(&content)?;
+
+    // Sonly signature over the encoded content, the content itself is not embedded twice
+    let sig = match session {
+        Some(session) => Some(session.sign(BUNDLE_AAD, &content)?),
+        None => None
+    };
+
+    let bundle: Bundle = Envelope::from((ByteBuf::from(content), sig));
+    let mut bundle_file = File::create(path)?;
+    cbor::to_writer(&mut bundle_file, &bundle)?;
+    bundle_file.sync_all()?;
+
+    Ok(count)
+}
+
+/// Import a bundle, report new, changed and conflicting entries.
+///
+/// The bundle must be signed by a pinned contact, unless `force`.
+/// Changed keys are never replaced directly,
+/// they are recorded as pending and need `--accept-change`.
+pub fn import(
+    quiet: bool,
+    stdio: &mut Stdio,
+    db: &dyn ContactStore,
+    path: &Path,
+    dry_run: bool,
+    force: bool
+) -> Fallible<()> {
+    let bundle: Bundle = cbor::from_reader(&mut File::open(path)?)?;
+    let (content, sig) = unwrap!(bundle);
+
+    let untrusted = if let Some(message) = sig {
+        let (signer_id, signer_pk) = verify(message, BUNDLE_AAD, &content)?;
+
+        match db.get(&signer_id)? {
+            Some(ref record) if contact::same(&record.pk, &signer_pk) => if record.status == Status::Revoked {
+                Some(format!("bundle is signed by revoked {}", signer_id))
+            } else {
+                if !quiet {
+                    stdio.info(format_args!("signed by: {} ({})", signer_id, record.status))?;
+                }
+                None
+            },
+            Some(_) => Some(format!(
+                "bundle is signed by {}, but the PublicKey is different from the contact store",
+                signer_id
+            )),
+            None => Some(format!("bundle is signed by unknown {}", signer_id))
+        }
+    } else {
+        Some("bundle is not signed".into())
+    };
+
+    if let Some(reason) = untrusted {
+        if force || dry_run {
+            stdio.warn(format_args!("{}", reason))?;
+        } else {
+            return Err(err_msg(format!("{}, use `--force` to import it anyway", reason)));
+        }
+    }
+
+    let (created, entries): BundleContent = cbor::from_slice(&content)?;
+    let source = Source::File(path.display().to_string());
+
+    if !quiet {
+        stdio.info(format_args!("created: {}", format_time(created)))?;
+    }
+
+    // classify every entry before writing, an invalid one must not leave the import half done
+    let mut changes = Vec::with_capacity(entries.len());
+
+    for (id, _, pk) in &entries {
+        check_id(id)?;
+
+        let duplicate = entries.iter()
+            .any(|(id2, _, pk2)| id2 == id && !contact::same(pk2, pk));
+        let record = db.get(id)?;
+
+        let change = match &record {
+            _ if duplicate => Change::Conflict,
+            None => Change::New,
+            Some(record) if contact::same(&record.pk, pk) => Change::Unchanged,
+            Some(record) if record.status == Status::Verified => Change::Conflict,
+            Some(_) => Change::Changed
+        };
+        changes.push((change, record));
+    }
+
+    let mut counts = [0usize; 4];
+
+    for ((id, name, pk), (change, record)) in entries.iter().zip(changes) {
+        counts[change as usize] += 1;
+
+        if change == Change::Conflict {
+            stdio.warn(format_args!("{} {}: {:?}", change, id, pk.to_short()))?;
+        } else if !quiet && change != Change::Unchanged {
+            stdio.info(format_args!("{} {}: {:?}", change, id, pk.to_short()))?;
+        }
+
+        if dry_run {
+            continue
+        }
+
+        match (change, record) {
+            (Change::New, _) => {
+                let mut record = Record::new(pk.clone(), source.clone());
+                record.name = name.clone();
+                db.set(id, &record)?;
+            },
+            (Change::Unchanged, Some(mut record)) | (Change::Changed, Some(mut record)) => {
+                record.observe(pk);
+                db.set(id, &record)?;
+            },
+            _ => ()
+        }
+    }
+
+    let [new, unchanged, changed, conflict] = counts;
+    stdio.info(format_args!(
+        "{}{} new, {} unchanged, {} changed, {} conflicting",
+        if dry_run { "dry run: " } else { "" },
+        new, unchanged, changed, conflict
+    ))?;
+
+    if changed > 0 && !dry_run {
+        stdio.warn(format_args!(
+            "changed PublicKeys are pending, verify them and run `ene contact <ID> --accept-change`"
+        ))?;
+    }
+
+    Ok(())
+}
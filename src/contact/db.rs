@@ -1,76 +1,584 @@
-use std::fs;
+use std::{ fs, io, iter };
+use std::fs::File;
+use std::cmp::Ordering;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::mem::ManuallyDrop;
 use std::path::{ Path, PathBuf };
-use failure::{ Fallible, err_msg };
+use failure::{ Fallible, Error, err_msg };
+use serde_derive::{ Serialize, Deserialize };
+use serde_bytes::ByteBuf;
 use serde_cbor as cbor;
 use sled::{ ConfigBuilder, Tree, Iter };
-use crate::core::key;
+use crate::core::{ alg, key };
+use crate::core::format::Short;
+use crate::core::contact::{ ContactStore, Record, Seal, GROUP_PREFIX, check_id, fingerprint };
+use crate::core::error::StoreError;
+use super::lock::{ Lock, Access };
+use super::{ backend, store_error };
 
 
+/// Sled contact store
+///
+/// ```norun
+/// sled/           - ID => Record, @Group => Members
+///                   or, if authenticated, ID => ((Version, Record), Tag)
+///                   or, if encrypted, H(ID) => Sealed (ID, Version, Record)
+/// sled.index/     - fingerprint || ID => (), unused if encrypted
+/// sled.lock
+/// ```
+///
+/// Protected store has a tagged head which holds the version of every entry,
+/// so an entry can not be replaced by its old version.
 pub struct Db {
+    path: PathBuf,
+    meta_path: PathBuf,
     tree: ManuallyDrop<Tree>,
-    lock: PathBuf
+    index: ManuallyDrop<Option<Tree>>,
+    protected: Option<Protected>,
+    fresh_index: bool,
+    lock: Option<Lock>
 }
 
-macro_rules! check_lock {
-    ( $path:expr ) => {
-        if $path.exists() {
-            return Err(err_msg(format!("lock exist: {}", $path.display())));
-        }
-    }
+/// Protection of store, keyed by a Profile
+struct Protected {
+    id: String,
+    enc: alg::Encrypt,
+    check: ByteBuf,
+    encrypted: bool,
+
+    /// Version of the last write, from the protection record
+    version: u64,
+
+    sealer: Option<Box<dyn Seal>>,
+    head: RefCell<Head>
+}
+
+/// Protection record, the required protection and the version of the last write
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    id: String,
+    enc: alg::Encrypt,
+    check: ByteBuf,
+    encrypted: bool,
+    version: u64
 }
 
+/// Version of store and of every entry, by tree key
+#[derive(Default)]
+#[derive(Serialize, Deserialize)]
+struct Head {
+    version: u64,
+    entries: BTreeMap<Vec<u8>, u64>
+}
+
+/// Marker of encrypted store, value is Profile ID, Encrypt algorithm and sealed check value.
+const SEALED: &[u8] = b"\0sealed";
+
+/// Marker of authenticated store, value is the same as `SEALED`.
+const AUTHED: &[u8] = b"\0authed";
+
+/// Head of protected store, value is (Head, Tag).
+const HEAD: &[u8] = b"\0head";
+
+const HEAD_ID: &str = "\0head";
+
+const CHECK: &[u8] = b"ENE";
+
 impl Db {
-    pub fn new(path: &Path) -> Fallible<Db> {
-        let config = ConfigBuilder::new()
-            .path(path)
-            .build();
+    /// Open the store at `path`, `meta_path` is its protection record.
+    ///
+    /// The protection record is kept apart from the tree, in keyring.
+    /// A store which has one must have the same protection,
+    /// so stripping the marker from the tree does not disable it.
+    /// A store without both opens as plain, the Profile which uses it
+    /// refuses it if protection is required, see `contact::check_protected`.
+    pub fn new(path: &Path, meta_path: &Path, access: Access) -> Fallible<Db> {
+        // sled is not safe to be opened by multiple processes and writes on start and drop,
+        // so readers take the exclusive lock too.
+        let lock = Lock::acquire(&path.with_extension("lock"), Access::Write)?;
 
-        let lock_path = path.with_extension("lock");
-        check_lock!(lock_path);
+        // the new protection record is written once the fresh tree of `protect` is complete
+        if meta_path.with_extension("new").exists() {
+            finish(path, meta_path)?;
+        } else {
+            remove(&path.with_extension("new"))?;
+        }
 
-        fs::File::create(&lock_path)?;
+        let tree = start(path)?;
 
-        Ok(Db {
-            tree: ManuallyDrop::new(Tree::start(config)?),
-            lock: lock_path
-        })
+        let mut marker = None;
+        for &(key, encrypted) in &[(SEALED, true), (AUTHED, false)] {
+            if let Some(value) = tree.get(key)? {
+                let (id, enc, check): (String, alg::Encrypt, ByteBuf) = cbor::from_slice(&value)?;
+                marker = Some((id, enc, check, encrypted));
+                break
+            }
+        }
+
+        let meta: Option<Meta> = if meta_path.is_file() {
+            Some(cbor::from_reader(&mut File::open(meta_path)?)?)
+        } else {
+            None
+        };
+
+        let protected = match (meta, marker) {
+            (None, None) => None,
+            (Some(ref meta), Some((ref id, enc, ref check, encrypted)))
+                if meta.id == *id && meta.enc == enc && meta.check == *check && meta.encrypted == encrypted =>
+                Some(Protected {
+                    id: id.clone(),
+                    enc,
+                    check: check.clone(),
+                    encrypted,
+                    version: meta.version,
+                    sealer: None,
+                    head: RefCell::new(Head::default())
+                }),
+            (Some(meta), _) => return Err(err_msg(format!(
+                "Contact store must be {} for {}, but its protection has been removed or replaced",
+                if meta.encrypted { "encrypted" } else { "authenticated" },
+                meta.id
+            ))),
+            (None, Some((id, ..))) => return Err(err_msg(format!(
+                "Contact store is protected by {}, but its protection record is missing: {}",
+                id, meta_path.display()
+            )))
+        };
+
+        // build index on the first write open, readers fall back to scan until then
+        let index_path = path.with_extension("index");
+        if protected.as_ref().map(|p| p.encrypted).unwrap_or(false) {
+            remove(&index_path)?;
+        }
+        let fresh_index = !index_path.exists();
+        let index = match access {
+            _ if protected.as_ref().map(|p| p.encrypted).unwrap_or(false) => None,
+            Access::Read if fresh_index => None,
+            _ => Some(start(&index_path)?)
+        };
+
+        let db = Db {
+            path: path.to_path_buf(),
+            meta_path: meta_path.to_path_buf(),
+            tree: ManuallyDrop::new(tree),
+            index: ManuallyDrop::new(index),
+            protected,
+            fresh_index,
+            lock: Some(lock)
+        };
+
+        // protected store is indexed after unlock
+        if db.fresh_index && db.protected.is_none() {
+            db.reindex()?;
+        }
+
+        Ok(db)
+    }
+
+    /// The Profile ID and Encrypt algorithm which the store is encrypted or authenticated for.
+    pub fn protected_by(&self) -> Option<(&str, alg::Encrypt)> {
+        self.protected.as_ref().map(|p| (p.id.as_str(), p.enc))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.protected.as_ref().map(|p| p.encrypted).unwrap_or(false)
     }
 
-    pub fn get(&self, id: &str) -> Fallible<Option<key::PublicKey>> {
-        if let Some(value) = self.tree.get(id.as_bytes())? {
-            Ok(Some(cbor::from_slice(&value)?))
+    /// Unlock protected store with the keys of Profile, and verify its head.
+    pub fn unlock(&mut self, sealer: Box<dyn Seal>) -> Fallible<()> {
+        if let Some(protected) = &mut self.protected {
+            let marker = if protected.encrypted { SEALED } else { AUTHED };
+            sealer.open(marker, &protected.check)
+                .map_err(|_| err_msg(format!("Contact store can not be unlocked by {}", protected.id)))?;
+
+            let value = self.tree.get(HEAD)?
+                .ok_or_else(|| err_msg("Contact store head is missing"))?;
+            let (head, tag): (ByteBuf, ByteBuf) = cbor::from_slice(&value)?;
+
+            if !sealer.verify(HEAD_ID, &head, &tag)? {
+                return Err(err_msg("Contact store head has been tampered with"));
+            }
+
+            let head: Head = cbor::from_slice(&head)?;
+
+            // the head is written before the protection record
+            if head.version < protected.version {
+                return Err(err_msg(format!(
+                    "Contact store has been rolled back to version {}, expected {}",
+                    head.version, protected.version
+                )));
+            }
+
+            protected.head = RefCell::new(head);
+            protected.sealer = Some(sealer);
+        }
+
+        if self.fresh_index {
+            self.reindex()?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt or authenticate all records, return the count of records.
+    ///
+    /// Records are written to a fresh tree which replaces the old one,
+    /// since sled keeps overwritten values in its log files.
+    /// Authenticated store can be upgraded to encrypted store.
+    pub fn protect(self, profile_id: &str, enc: alg::Encrypt, sealer: Box<dyn Seal>, encrypted: bool)
+        -> Fallible<usize>
+    {
+        match &self.protected {
+            Some(Protected { encrypted: true, id, .. }) =>
+                return Err(err_msg(format!("Contact store is already encrypted for {}", id))),
+            Some(Protected { encrypted: false, id, .. }) if !encrypted =>
+                return Err(err_msg(format!("Contact store is already authenticated for {}", id))),
+            _ => ()
+        }
+
+        let records = self.scan("").collect::<Result<Vec<_>, _>>()?;
+        let groups = self.groups()?;
+
+        let mut entries = Vec::new();
+        for (id, record) in &records {
+            entries.push((id.clone(), cbor::to_vec(record)?));
+        }
+        for (name, members) in &groups {
+            entries.push((format!("{}{}", GROUP_PREFIX, name), cbor::to_vec(members)?));
+        }
+
+        let marker = if encrypted { SEALED } else { AUTHED };
+        let check = ByteBuf::from(sealer.seal(marker, CHECK)?);
+        let protected = Protected {
+            id: profile_id.to_string(),
+            enc, check, encrypted,
+            version: 1,
+            sealer: Some(sealer),
+            head: RefCell::new(Head { version: 1, entries: BTreeMap::new() })
+        };
+
+        // a crash before the protection record is written leaves the old store intact
+        let fresh_path = self.path.with_extension("new");
+        remove(&fresh_path)?;
+        {
+            let tree = start(&fresh_path)?;
+
+            for (id, payload) in entries {
+                let key = tree_key(Some(&protected), &id)?;
+                let value = encode(&protected, &id, &key, 1, payload)?;
+                tree.set(key.clone(), value)?;
+                protected.head.borrow_mut().entries.insert(key, 1);
+            }
+
+            tree.set(marker.to_vec(), cbor::to_vec(&(profile_id, enc, &protected.check))?)?;
+            tree.set(HEAD.to_vec(), encode_head(&protected)?)?;
+            tree.flush()?;
+        }
+
+        save_meta(&self.meta_path.with_extension("new"), &protected, 1)?;
+
+        let (path, meta_path) = (self.path.clone(), self.meta_path.clone());
+        let _lock = self.close();
+        finish(&path, &meta_path)?;
+
+        Ok(records.len())
+    }
+
+    /// Close trees but keep the lock.
+    fn close(mut self) -> Lock {
+        self.lock.take().expect("lock is held until close")
+    }
+
+    /// Return `Ok(None)` if store is not protected.
+    fn unlocked(&self) -> Fallible<Option<&Protected>> {
+        match &self.protected {
+            Some(protected) if protected.sealer.is_some() => Ok(Some(protected)),
+            Some(protected) => Err(err_msg(format!("Contact store is protected, unlock {} first", protected.id))),
+            None => Ok(None)
+        }
+    }
+
+    /// Decrypt all entries, include groups.
+    fn scan_sealed(&self, protected: &Protected) -> Fallible<Vec<Fallible<(String, Vec<u8>)>>> {
+        let mut entries = Vec::new();
+
+        for item in self.tree.scan(b"") {
+            let (key, value) = item?;
+
+            // markers and head
+            if !key.starts_with(b"\0") {
+                entries.push(open(Some(protected), None, &key, &value));
+            }
+        }
+
+        // keep the ID order of plain store, errors at last
+        entries.sort_by(|x, y| match (x, y) {
+            (Ok((id, _)), Ok((id2, _))) => id.cmp(id2),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => Ordering::Equal
+        });
+        Ok(entries)
+    }
+
+    fn get_payload(&self, id: &str) -> Fallible<Option<Vec<u8>>> {
+        let protected = self.unlocked()?;
+        let key = tree_key(protected, id)?;
+
+        if let Some(value) = self.tree.get(&key)? {
+            let (_, payload) = open(protected, Some(id), &key, &value)?;
+            Ok(Some(payload))
+        } else if protected.map(|p| p.head.borrow().entries.contains_key(&key)).unwrap_or(false) {
+            Err(err_msg(format!("Contact record of {} has been removed", id)))
         } else {
             Ok(None)
         }
     }
 
-    pub fn set(&self, id: &str, pk: &key::PublicKey) -> Fallible<()> {
-        let id = id.to_string().into_bytes();
-        let pk = cbor::to_vec(pk)?;
+    /// Write or remove an entry, protected store bumps its version.
+    fn put(&self, protected: Option<&Protected>, id: &str, payload: Option<Vec<u8>>) -> Fallible<()> {
+        let key = tree_key(protected, id)?;
+
+        let protected = match protected {
+            Some(protected) => protected,
+            None => {
+                match payload {
+                    Some(payload) => { self.tree.set(key, payload)?; },
+                    None => { self.tree.del(&key)?; }
+                }
+
+                return Ok(());
+            }
+        };
+
+        let version = protected.head.borrow().version + 1;
+
+        // the entry is written first, an interrupted write leaves it newer than the head
+        match payload {
+            Some(payload) => {
+                let value = encode(protected, id, &key, version, payload)?;
+                self.tree.set(key.clone(), value)?;
+                protected.head.borrow_mut().entries.insert(key, version);
+            },
+            None => {
+                self.tree.del(&key)?;
+                protected.head.borrow_mut().entries.remove(&key);
+            }
+        }
+
+        protected.head.borrow_mut().version = version;
+        self.tree.set(HEAD.to_vec(), encode_head(protected)?)?;
+        save_meta(&self.meta_path, protected, version)?;
 
-        self.tree.set(id, pk).map_err(Into::into)
+        Ok(())
     }
 
-    pub fn del(&self, id: &str) -> Fallible<()> {
-        self.tree.del(id.as_bytes())
-            .map(drop)
-            .map_err(Into::into)
+    fn reindex(&self) -> Fallible<()> {
+        if let Some(index) = &*self.index {
+            for item in self.scan("") {
+                let (id, record) = match item {
+                    Ok(item) => item,
+                    // tampered records are reported when used
+                    Err(_) => continue
+                };
+
+                for short in record.fingerprints() {
+                    index.set(index_key(short, &id), Vec::new())?;
+                }
+            }
+        }
+
+        Ok(())
     }
+}
 
-    pub fn filter<'a, 'b>(&'a self, start: &'b str) -> Filter<'a, 'b> {
-        let iter = self.tree.scan(start.as_bytes());
-        Filter { iter, start }
+impl ContactStore for Db {
+    fn get(&self, id: &str) -> Result<Option<Record>, StoreError> {
+        check_id(id)?;
+
+        backend(|| match self.get_payload(id)? {
+            Some(payload) => Ok(Some(decode(&payload)?)),
+            None => Ok(None)
+        })
     }
+
+    fn set(&self, id: &str, record: &Record) -> Result<(), StoreError> {
+        check_id(id)?;
+
+        backend(|| {
+            let protected = self.unlocked()?;
+
+            if let Some(index) = &*self.index {
+                // a tampered old record has no trusted fingerprints to remove
+                if let Ok(Some(old)) = self.get(id) {
+                    for short in old.fingerprints() {
+                        index.del(&index_key(short, id))?;
+                    }
+                }
+
+                for short in record.fingerprints() {
+                    index.set(index_key(short, id), Vec::new())?;
+                }
+            }
+
+            self.put(protected, id, Some(cbor::to_vec(record)?))
+        })
+    }
+
+    fn del(&self, id: &str) -> Result<(), StoreError> {
+        check_id(id)?;
+
+        backend(|| {
+            let protected = self.unlocked()?;
+
+            if let Some(index) = &*self.index {
+                if let Ok(Some(old)) = self.get(id) {
+                    for short in old.fingerprints() {
+                        index.del(&index_key(short, id))?;
+                    }
+                }
+            }
+
+            self.put(protected, id, None)
+        })
+    }
+
+    fn scan<'a>(&'a self, start: &'a str)
+        -> Box<dyn Iterator<Item = Result<(String, Record), StoreError>> + 'a>
+    {
+        let iter: Box<dyn Iterator<Item = Fallible<(String, Record)>> + 'a> = match self.unlocked() {
+            Ok(Some(protected)) if protected.encrypted => match self.scan_sealed(protected) {
+                Ok(entries) => Box::new(entries.into_iter()
+                    .filter(move |entry| match entry {
+                        Ok((id, _)) => id.starts_with(start) && !id.starts_with(GROUP_PREFIX),
+                        Err(_) => true
+                    })
+                    .map(|entry| entry.and_then(|(id, payload)| Ok((id, decode(&payload)?))))
+                ),
+                Err(err) => Box::new(iter::once(Err(err)))
+            },
+            Ok(protected) => {
+                let iter = self.tree.scan(start.as_bytes());
+                Box::new(Filter { iter, start, protected })
+            },
+            Err(err) => Box::new(iter::once(Err(err)))
+        };
+
+        Box::new(iter.map(|item| item.map_err(store_error)))
+    }
+
+    fn get_group(&self, name: &str) -> Result<Option<Vec<String>>, StoreError> {
+        backend(|| match self.get_payload(&format!("{}{}", GROUP_PREFIX, name))? {
+            Some(payload) => Ok(Some(cbor::from_slice(&payload)?)),
+            None => Ok(None)
+        })
+    }
+
+    fn set_group(&self, name: &str, members: &[String]) -> Result<(), StoreError> {
+        backend(|| {
+            let protected = self.unlocked()?;
+            let id = format!("{}{}", GROUP_PREFIX, name);
+
+            if members.is_empty() {
+                self.put(protected, &id, None)
+            } else {
+                self.put(protected, &id, Some(cbor::to_vec(members)?))
+            }
+        })
+    }
+
+    fn groups(&self) -> Result<Vec<(String, Vec<String>)>, StoreError> {
+        backend(|| {
+            let entries = match self.unlocked()? {
+                Some(protected) if protected.encrypted => self.scan_sealed(protected)?,
+                protected => {
+                    let prefix = GROUP_PREFIX.to_string();
+                    let mut entries = Vec::new();
+
+                    for item in self.tree.scan(prefix.as_bytes()) {
+                        let (key, value) = item?;
+                        if !key.starts_with(prefix.as_bytes()) {
+                            break
+                        }
+
+                        let id = String::from_utf8(key)?;
+                        entries.push(open(protected, Some(&id), id.as_bytes(), &value));
+                    }
+
+                    entries
+                }
+            };
+
+            let mut groups = Vec::new();
+            for entry in entries {
+                let (id, payload) = entry?;
+
+                if id.starts_with(GROUP_PREFIX) {
+                    groups.push((id[GROUP_PREFIX.len_utf8()..].to_string(), cbor::from_slice(&payload)?));
+                }
+            }
+
+            Ok(groups)
+        })
+    }
+
+    fn find<'a>(&'a self, prefix: &'a str)
+        -> Box<dyn Iterator<Item = Result<(String, Record), StoreError>> + 'a>
+    {
+        let index = match &*self.index {
+            Some(index) => index,
+            None => return Box::new(self.scan("").filter(move |item| match item {
+                Ok((_, record)) => record.matches(prefix),
+                Err(_) => true
+            }))
+        };
+
+        let mut ids = Vec::new();
+        for item in index.scan(prefix.as_bytes()) {
+            let (key, _) = match item {
+                Ok(item) => item,
+                Err(err) => return Box::new(iter::once(Err(store_error(err.into()))))
+            };
+
+            if !key.starts_with(prefix.as_bytes()) {
+                break
+            }
+
+            match String::from_utf8(key[FINGERPRINT_LEN..].to_vec()) {
+                Ok(id) => if !ids.contains(&id) {
+                    ids.push(id);
+                },
+                Err(err) => return Box::new(iter::once(Err(store_error(err.into()))))
+            }
+        }
+
+        // records are verified by `get`, the index is only a hint
+        Box::new(ids.into_iter().filter_map(move |id| match self.get(&id) {
+            Ok(Some(record)) => Some(Ok((id, record))),
+            Ok(None) => None,
+            Err(err) => Some(Err(err))
+        }))
+    }
+}
+
+/// Length of hex fingerprint
+const FINGERPRINT_LEN: usize = 16;
+
+fn index_key(short: Short, id: &str) -> Vec<u8> {
+    let mut key = fingerprint(short).into_bytes();
+    key.extend_from_slice(id.as_bytes());
+    key
 }
 
 pub struct Filter<'a, 'b> {
     iter: Iter<'a>,
-    start: &'b str
+    start: &'b str,
+    protected: Option<&'a Protected>
 }
 
 impl<'a, 'b> Iterator for Filter<'a, 'b> {
-    type Item = Fallible<(String, key::PublicKey)>;
+    type Item = Fallible<(String, Record)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         macro_rules! try_some {
@@ -82,23 +590,159 @@ impl<'a, 'b> Iterator for Filter<'a, 'b> {
             }
         }
 
-        let (id, value) = try_some!(self.iter.next()?);
-        let id = try_some!(String::from_utf8(id));
-        if id.starts_with(self.start) {
-            let pk = try_some!(cbor::from_slice(&value));
-            Some(Ok((id, pk)))
+        let (key, value) = try_some!(self.iter.next()?);
+
+        // markers and head
+        if key.starts_with(b"\0") {
+            return self.next();
+        }
+
+        let id = try_some!(String::from_utf8(key));
+        if id.starts_with(self.start) && !id.starts_with(GROUP_PREFIX) {
+            let (id, payload) = try_some!(open(self.protected, Some(&id), id.as_bytes(), &value));
+            Some(decode(&payload).map(|record| (id, record)))
         } else {
             self.next()
         }
     }
 }
 
+fn tree_key(protected: Option<&Protected>, id: &str) -> Fallible<Vec<u8>> {
+    match protected {
+        Some(Protected { encrypted: true, sealer: Some(sealer), .. }) => Ok(sealer.hash_id(id)?),
+        _ => Ok(id.as_bytes().to_vec())
+    }
+}
+
+fn encode(protected: &Protected, id: &str, key: &[u8], version: u64, payload: Vec<u8>) -> Fallible<Vec<u8>> {
+    let sealer = protected.sealer.as_ref()
+        .ok_or_else(|| err_msg("Contact store is locked"))?;
+
+    if protected.encrypted {
+        Ok(sealer.seal(key, &cbor::to_vec(&(id, version, ByteBuf::from(payload)))?)?)
+    } else {
+        let signed = cbor::to_vec(&(version, ByteBuf::from(payload)))?;
+        let tag = sealer.tag(id, &signed)?;
+        Ok(cbor::to_vec(&(ByteBuf::from(signed), ByteBuf::from(tag)))?)
+    }
+}
+
+fn encode_head(protected: &Protected) -> Fallible<Vec<u8>> {
+    let sealer = protected.sealer.as_ref()
+        .ok_or_else(|| err_msg("Contact store is locked"))?;
+
+    let head = cbor::to_vec(&*protected.head.borrow())?;
+    let tag = sealer.tag(HEAD_ID, &head)?;
+    Ok(cbor::to_vec(&(ByteBuf::from(head), ByteBuf::from(tag)))?)
+}
+
+/// Verify entry and take the ID and payload, `id` is unknown only when scanning encrypted store.
+fn open(protected: Option<&Protected>, id: Option<&str>, key: &[u8], value: &[u8])
+    -> Fallible<(String, Vec<u8>)>
+{
+    fn tampered(id: Option<&str>) -> Error {
+        err_msg(format!("Contact record of {} has been tampered with", id.unwrap_or("unknown ID")))
+    }
+
+    let protected = match (protected, id) {
+        (Some(protected), _) => protected,
+        (None, Some(id)) => return Ok((id.to_string(), value.to_vec())),
+        (None, None) => return Err(err_msg("Contact store is locked"))
+    };
+    let sealer = protected.sealer.as_ref()
+        .ok_or_else(|| err_msg("Contact store is locked"))?;
+
+    let (id, version, payload) = if protected.encrypted {
+        let value = sealer.open(key, value)
+            .map_err(|_| tampered(id))?;
+        let (id2, version, payload): (String, u64, ByteBuf) = cbor::from_slice(&value)?;
+
+        match id {
+            Some(id) if id != id2 => return Err(tampered(Some(id))),
+            _ => (id2, version, payload)
+        }
+    } else {
+        let id = id.ok_or_else(|| tampered(None))?;
+        let (signed, tag): (ByteBuf, ByteBuf) = cbor::from_slice(value)
+            .map_err(|_| tampered(Some(id)))?;
+
+        if !sealer.verify(id, &signed, &tag)? {
+            return Err(tampered(Some(id)));
+        }
+
+        let (version, payload): (u64, ByteBuf) = cbor::from_slice(&signed)?;
+        (id.to_string(), version, payload)
+    };
+
+    // an old version of the entry is authentic, but replayed
+    match protected.head.borrow().entries.get(key) {
+        Some(&expected) if version >= expected => Ok((id, payload.into())),
+        Some(_) => Err(err_msg(format!("Contact record of {} has been replaced by an old version", id))),
+        None => Err(tampered(Some(&id)))
+    }
+}
+
+fn decode(payload: &[u8]) -> Fallible<Record> {
+    match cbor::from_slice::<Record>(payload) {
+        Ok(record) => Ok(record),
+        Err(err) => cbor::from_slice::<key::PublicKey>(payload)
+            .map(Record::from)
+            .map_err(|_| err.into())
+    }
+}
+
+fn save_meta(path: &Path, protected: &Protected, version: u64) -> Fallible<()> {
+    let meta = Meta {
+        id: protected.id.clone(),
+        enc: protected.enc,
+        check: protected.check.clone(),
+        encrypted: protected.encrypted,
+        version
+    };
+
+    let mut meta_file = File::create(path)?;
+    cbor::to_writer(&mut meta_file, &meta)?;
+    meta_file.sync_all()?;
+    Ok(())
+}
+
+/// Replace the store by the fresh tree of `protect`, then its protection record.
+///
+/// The old index has plain IDs too, it is rebuilt after unlock.
+fn finish(path: &Path, meta_path: &Path) -> Fallible<()> {
+    let fresh_path = path.with_extension("new");
+
+    if fresh_path.exists() {
+        remove(path)?;
+        fs::rename(&fresh_path, path)?;
+    }
+
+    remove(&path.with_extension("index"))?;
+    fs::rename(meta_path.with_extension("new"), meta_path)?;
+    Ok(())
+}
+
+fn start(path: &Path) -> Fallible<Tree> {
+    Ok(Tree::start(ConfigBuilder::new().path(path).build())?)
+}
+
+/// Remove a sled tree, it is a directory, or files of older versions.
+fn remove(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else if path.exists() {
+        fs::remove_file(path)
+    } else {
+        Ok(())
+    }
+}
+
 impl Drop for Db {
     fn drop(&mut self) {
+        // close trees before release lock
         unsafe {
+            ManuallyDrop::drop(&mut self.index);
             ManuallyDrop::drop(&mut self.tree);
         }
-
-        let _ = fs::remove_file(&self.lock);
     }
 }
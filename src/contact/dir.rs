@@ -0,0 +1,194 @@
+use std::iter;
+use std::fs::{ self, File };
+use std::path::{ Path, PathBuf };
+use failure::{ Fallible, err_msg };
+use serde_cbor as cbor;
+use crate::core::contact::{ self, ContactStore, Record, Source, GROUP_PREFIX, check_id };
+use crate::core::format::{ PublicKey, Envelope };
+use crate::core::error::StoreError;
+use super::lock::{ Lock, Access };
+use super::{ backend, store_error };
+
+
+/// Plain directory store, easy to version and share
+///
+/// ```norun
+/// <dir>/
+///     <ID>.ene        - PublicKey
+///     <ID>.record     - Contact record, optional
+///     @<Group>.group  - Group members
+///     .lock
+/// ```
+pub struct DirStore {
+    dir: PathBuf,
+    _lock: Lock
+}
+
+impl DirStore {
+    pub fn new(dir: &Path, access: Access) -> Fallible<DirStore> {
+        if !dir.is_dir() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let lock = Lock::acquire(&dir.join(".lock"), access)?;
+
+        Ok(DirStore { dir: dir.to_path_buf(), _lock: lock })
+    }
+
+    fn path(&self, id: &str, ext: &str) -> Result<PathBuf, StoreError> {
+        check_path(id)?;
+        Ok(self.dir.join(format!("{}.{}", id, ext)))
+    }
+
+    fn ids(&self, ext: &str) -> Fallible<Vec<String>> {
+        let mut ids = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+
+            if path.is_file() && path.extension().map(|e| e == ext).unwrap_or(false) {
+                if let Some(id) = path.file_stem().and_then(|id| id.to_str()) {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+
+        ids.sort();
+        Ok(ids)
+    }
+}
+
+impl ContactStore for DirStore {
+    fn get(&self, id: &str) -> Result<Option<Record>, StoreError> {
+        check_id(id)?;
+
+        backend(|| {
+            let pk_path = self.path(id, "ene")?;
+            if !pk_path.is_file() {
+                return Ok(None);
+            }
+
+            let pk_packed: PublicKey = cbor::from_reader(&mut File::open(&pk_path)?)?;
+            let (pk_id, pk) = unwrap!(pk_packed);
+
+            // a key file of another ID may be copied or renamed to this one
+            if pk_id != id {
+                return Err(err_msg(format!("{} holds the PublicKey of {}", pk_path.display(), pk_id)));
+            }
+
+            let record_path = self.path(id, "record")?;
+            let record = if record_path.is_file() {
+                let mut record: Record = cbor::from_reader(&mut File::open(&record_path)?)?;
+
+                // PublicKey file may be replaced by others, keep it as pending until accepted
+                if !contact::same(&record.pk, &pk) {
+                    record.observe(&pk);
+                }
+
+                record
+            } else {
+                Record {
+                    source: Source::File(pk_path.display().to_string()),
+                    ..Record::from(pk)
+                }
+            };
+
+            Ok(Some(record))
+        })
+    }
+
+    fn set(&self, id: &str, record: &Record) -> Result<(), StoreError> {
+        check_id(id)?;
+
+        backend(|| {
+            let pk_packed: PublicKey = Envelope::from((id.to_string(), record.pk.clone()));
+            cbor::to_writer(&mut File::create(self.path(id, "ene")?)?, &pk_packed)?;
+            cbor::to_writer(&mut File::create(self.path(id, "record")?)?, record)?;
+
+            Ok(())
+        })
+    }
+
+    fn del(&self, id: &str) -> Result<(), StoreError> {
+        check_id(id)?;
+
+        backend(|| {
+            fs::remove_file(self.path(id, "ene")?)?;
+
+            let record_path = self.path(id, "record")?;
+            if record_path.is_file() {
+                fs::remove_file(record_path)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn scan<'a>(&'a self, prefix: &'a str)
+        -> Box<dyn Iterator<Item = Result<(String, Record), StoreError>> + 'a>
+    {
+        match self.ids("ene") {
+            Ok(ids) => Box::new(ids.into_iter()
+                .filter(move |id| id.starts_with(prefix))
+                .filter_map(move |id| match self.get(&id) {
+                    Ok(Some(record)) => Some(Ok((id, record))),
+                    Ok(None) => None,
+                    Err(err) => Some(Err(err))
+                })
+            ),
+            Err(err) => Box::new(iter::once(Err(store_error(err))))
+        }
+    }
+
+    fn get_group(&self, name: &str) -> Result<Option<Vec<String>>, StoreError> {
+        let path = self.path(&format!("{}{}", GROUP_PREFIX, name), "group")?;
+
+        backend(|| if path.is_file() {
+            Ok(Some(cbor::from_reader(&mut File::open(&path)?)?))
+        } else {
+            Ok(None)
+        })
+    }
+
+    fn set_group(&self, name: &str, members: &[String]) -> Result<(), StoreError> {
+        let path = self.path(&format!("{}{}", GROUP_PREFIX, name), "group")?;
+
+        backend(|| {
+            if members.is_empty() {
+                if path.is_file() {
+                    fs::remove_file(&path)?;
+                }
+            } else {
+                cbor::to_writer(&mut File::create(&path)?, &members)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn groups(&self) -> Result<Vec<(String, Vec<String>)>, StoreError> {
+        let mut groups = Vec::new();
+
+        for id in self.ids("group").map_err(store_error)? {
+            let name = id.trim_start_matches(GROUP_PREFIX);
+
+            if let Some(members) = self.get_group(name)? {
+                groups.push((name.to_string(), members));
+            }
+        }
+
+        Ok(groups)
+    }
+}
+
+/// Reject IDs which are not a plain file name.
+pub fn check_path(id: &str) -> Result<(), StoreError> {
+    if id.is_empty()
+        || id.starts_with('.')
+        || id.contains(|c: char| c == '/' || c == '\\' || c == '\0')
+    {
+        Err(StoreError::InvalidId(format!("{:?}", id)))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::{ env, thread };
+use std::fs::{ File, OpenOptions };
+use std::path::Path;
+use std::time::{ Duration, Instant };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use failure::{ Fallible, err_msg };
+use fs2::{ FileExt, lock_contended_error };
+
+
+/// Seconds plus one set by `--lock-timeout`, zero if unset
+static TIMEOUT: AtomicUsize = AtomicUsize::new(0);
+
+/// Override `ENE_LOCK_TIMEOUT`.
+pub fn set_timeout(secs: usize) {
+    TIMEOUT.store(secs.saturating_add(1), Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write
+}
+
+/// OS advisory lock, shared for read and exclusive for write.
+///
+/// The lock is released by OS when process exits,
+/// so a crashed process never leaves a stale lock.
+pub struct Lock(File);
+
+impl Lock {
+    /// Wait for the lock until `--lock-timeout` or `ENE_LOCK_TIMEOUT` seconds (default 10).
+    pub fn acquire(path: &Path, access: Access) -> Fallible<Lock> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        let timeout = match TIMEOUT.load(Ordering::Relaxed) {
+            0 => env::var("ENE_LOCK_TIMEOUT").ok()
+                .and_then(|t| t.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| Duration::from_secs(10)),
+            secs => Duration::from_secs(secs as u64 - 1)
+        };
+        let start = Instant::now();
+
+        loop {
+            let result = match access {
+                Access::Read => file.try_lock_shared(),
+                Access::Write => file.try_lock_exclusive()
+            };
+
+            match result {
+                Ok(()) => break,
+                Err(ref err) if err.kind() == lock_contended_error().kind() => {
+                    if start.elapsed() >= timeout {
+                        return Err(err_msg(format!("Wait for lock timeout: {}", path.display())));
+                    }
+
+                    thread::sleep(Duration::from_millis(100));
+                },
+                Err(err) => return Err(err.into())
+            }
+        }
+
+        Ok(Lock(file))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
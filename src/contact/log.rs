@@ -0,0 +1,354 @@
+use std::{ fmt, iter };
+use std::cell::{ Ref, RefCell };
+use std::io::{ Read, Write };
+use std::fs::{ self, File, OpenOptions };
+use std::path::{ Path, PathBuf };
+use failure::{ Fallible, err_msg };
+use serde_derive::{ Serialize, Deserialize };
+use serde_bytes::ByteBuf;
+use serde_cbor as cbor;
+use crate::core::key;
+use crate::core::merkle::{ self, Hash };
+use crate::core::contact::{ self, ContactStore, Record, Seal, Source };
+use crate::core::error::StoreError;
+use super::backend;
+
+
+#[derive(Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize)]
+pub enum Event {
+    Added,
+    Changed
+}
+
+/// Log leaf
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub id: String,
+    pub short: key::ShortPublicKey,
+    pub time: u64,
+    pub event: Event,
+
+    /// Source of contact record
+    pub source: Source
+}
+
+/// Append-only Merkle log of contact keys
+///
+/// ```norun
+/// keylog          - length prefixed Entry, one leaf each,
+///                   or sealed Entry if contact store is encrypted
+/// keylog.head     - (size, root) of the last verified snapshot,
+///                   sealed if contact store is protected
+/// ```
+///
+/// Every open checks the log is consistent with the last snapshot,
+/// so rewritten or removed entries are detected.
+/// Leaves are hashed before sealing, so the root does not change with protection.
+pub struct KeyLog {
+    path: PathBuf,
+    entries: Vec<Entry>,
+    leaves: Vec<Hash>,
+
+    /// Seal of protected store, and whether entries are sealed too
+    sealer: Option<(Box<dyn Seal>, bool)>
+}
+
+/// Associated data of sealed entry
+const ENTRY_AAD: &[u8] = b"\0keylog";
+
+/// Associated data of sealed head
+const HEAD_AAD: &[u8] = b"\0keylog.head";
+
+impl KeyLog {
+    /// Open and verify the log, update the snapshot if `write`.
+    ///
+    /// `sealer` must be given if contact store is protected.
+    pub fn open(path: &Path, write: bool, sealer: Option<(Box<dyn Seal>, bool)>) -> Fallible<KeyLog> {
+        let mut entries = Vec::new();
+        let mut leaves = Vec::new();
+
+        if path.is_file() {
+            let buf = fs::read(path)?;
+            let mut buf = &buf[..];
+
+            while !buf.is_empty() {
+                let mut len = [0; 4];
+                buf.read_exact(&mut len)
+                    .map_err(|_| err_msg("Contact key log is truncated"))?;
+                let len = len.iter().fold(0, |sum, &b| (sum << 8) | b as usize);
+
+                if len > buf.len() {
+                    return Err(err_msg("Contact key log is truncated"));
+                }
+
+                let (leaf, rest) = buf.split_at(len);
+                let leaf = match &sealer {
+                    Some((sealer, true)) => sealer.open(ENTRY_AAD, leaf)
+                        .map_err(|_| err_msg(format!("Contact key log entry {} has been tampered with", leaves.len())))?,
+                    _ => leaf.to_vec()
+                };
+                entries.push(cbor::from_slice(&leaf)?);
+                leaves.push(merkle::leaf_hash(&leaf));
+                buf = rest;
+            }
+        }
+
+        let log = KeyLog { path: path.to_path_buf(), entries, leaves, sealer };
+
+        match log.snapshot()? {
+            Some((size, root)) => if !log.verify(size, &root) {
+                return Err(err_msg(format!(
+                    "Contact key log has been rewritten, it is not consistent with snapshot {}:{}",
+                    size, hex(&root)
+                )));
+            } else if write && size != log.size() {
+                log.save()?;
+            },
+            None => if write {
+                log.save()?;
+            }
+        }
+
+        Ok(log)
+    }
+
+    fn head_path(&self) -> PathBuf {
+        self.path.with_extension("head")
+    }
+
+    fn snapshot(&self) -> Fallible<Option<(usize, Hash)>> {
+        let path = self.head_path();
+
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let head = fs::read(path)?;
+        let head = match &self.sealer {
+            Some((sealer, _)) => sealer.open(HEAD_AAD, &head)
+                .map_err(|_| err_msg("Contact key log snapshot is not sealed by the contact store Profile"))?,
+            None => head
+        };
+        let (size, root): (u64, ByteBuf) = cbor::from_slice(&head)?;
+
+        if root.len() != 32 {
+            return Err(err_msg("Contact key log snapshot is malformed"));
+        }
+
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&root);
+        Ok(Some((size as usize, hash)))
+    }
+
+    fn save(&self) -> Fallible<()> {
+        self.save_to(&self.head_path())
+    }
+
+    fn save_to(&self, path: &Path) -> Fallible<()> {
+        let head = cbor::to_vec(&(self.size() as u64, ByteBuf::from(self.root().to_vec())))?;
+        let head = match &self.sealer {
+            Some((sealer, _)) => sealer.seal(HEAD_AAD, &head)?,
+            None => head
+        };
+
+        let mut head_file = File::create(path)?;
+        head_file.write_all(&head)?;
+        head_file.sync_all()?;
+        Ok(())
+    }
+
+    /// Rewrite the log with the seal of protected store,
+    /// the log and head are written aside and renamed.
+    pub fn reseal(mut self, sealer: Box<dyn Seal>, encrypted: bool) -> Fallible<KeyLog> {
+        self.sealer = Some((sealer, encrypted));
+
+        let log_path = self.path.with_extension("new");
+        let mut log_file = File::create(&log_path)?;
+        for entry in &self.entries {
+            self.write_entry(&mut log_file, &cbor::to_vec(entry)?)?;
+        }
+        log_file.sync_all()?;
+
+        let head_path = self.head_path().with_extension("head.new");
+        self.save_to(&head_path)?;
+
+        fs::rename(&log_path, &self.path)?;
+        fs::rename(&head_path, self.head_path())?;
+
+        Ok(self)
+    }
+
+    fn write_entry(&self, log_file: &mut File, leaf: &[u8]) -> Fallible<()> {
+        let sealed;
+        let leaf = match &self.sealer {
+            Some((sealer, true)) => {
+                sealed = sealer.seal(ENTRY_AAD, leaf)?;
+                &sealed[..]
+            },
+            _ => leaf
+        };
+
+        let len = leaf.len() as u32;
+        log_file.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+        log_file.write_all(leaf)?;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn size(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn root(&self) -> Hash {
+        merkle::root(&self.leaves)
+    }
+
+    /// Consistency proof from snapshot of `size` to the current log.
+    pub fn prove(&self, size: usize) -> Fallible<Vec<Hash>> {
+        if size > self.size() {
+            return Err(err_msg(format!("Snapshot {} is larger than log {}", size, self.size())));
+        }
+
+        Ok(merkle::consistency_proof(&self.leaves, size))
+    }
+
+    /// Whether snapshot of `size` and `root` is a prefix of the current log.
+    pub fn verify(&self, size: usize, root: &Hash) -> bool {
+        match self.prove(size) {
+            Ok(proof) => merkle::verify_consistency(size, self.size(), root, &self.root(), &proof),
+            Err(_) => false
+        }
+    }
+
+    pub fn append(&mut self, entry: Entry) -> Fallible<()> {
+        let leaf = cbor::to_vec(&entry)?;
+
+        let mut log_file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.write_entry(&mut log_file, &leaf)?;
+        log_file.sync_all()?;
+
+        self.leaves.push(merkle::leaf_hash(&leaf));
+        self.entries.push(entry);
+        self.save()
+    }
+}
+
+/// Contact store which logs every new key
+pub struct Logged {
+    inner: Box<dyn ContactStore>,
+    log: RefCell<KeyLog>
+}
+
+impl Logged {
+    pub fn new(inner: Box<dyn ContactStore>, log: KeyLog) -> Logged {
+        Logged { inner, log: RefCell::new(log) }
+    }
+
+    pub fn log(&self) -> Ref<KeyLog> {
+        self.log.borrow()
+    }
+}
+
+impl ContactStore for Logged {
+    fn get(&self, id: &str) -> Result<Option<Record>, StoreError> {
+        self.inner.get(id)
+    }
+
+    fn set(&self, id: &str, record: &Record) -> Result<(), StoreError> {
+        let old = self.inner.get(id)?;
+        self.inner.set(id, record)?;
+
+        let known = |pk: &key::PublicKey| match &old {
+            Some(old) => iter::once(&old.pk)
+                .chain(&old.pending)
+                .chain(old.history.iter().map(|seen| &seen.pk))
+                .any(|pk2| contact::same(pk2, pk)),
+            None => false
+        };
+
+        backend(|| {
+            for pk in iter::once(&record.pk).chain(&record.pending) {
+                if !known(pk) {
+                    self.log.borrow_mut().append(Entry {
+                        id: id.to_string(),
+                        short: pk.to_short(),
+                        time: contact::now(),
+                        event: if old.is_some() { Event::Changed } else { Event::Added },
+                        source: record.source.clone()
+                    })?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn del(&self, id: &str) -> Result<(), StoreError> {
+        self.inner.del(id)
+    }
+
+    fn scan<'a>(&'a self, prefix: &'a str)
+        -> Box<dyn Iterator<Item = Result<(String, Record), StoreError>> + 'a>
+    {
+        self.inner.scan(prefix)
+    }
+
+    fn find<'a>(&'a self, prefix: &'a str)
+        -> Box<dyn Iterator<Item = Result<(String, Record), StoreError>> + 'a>
+    {
+        self.inner.find(prefix)
+    }
+
+    fn get_group(&self, name: &str) -> Result<Option<Vec<String>>, StoreError> {
+        self.inner.get_group(name)
+    }
+
+    fn set_group(&self, name: &str, members: &[String]) -> Result<(), StoreError> {
+        self.inner.set_group(name, members)
+    }
+
+    fn groups(&self) -> Result<Vec<(String, Vec<String>)>, StoreError> {
+        self.inner.groups()
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Event::Added => "added",
+            Event::Changed => "changed"
+        })
+    }
+}
+
+pub fn hex(hash: &[u8]) -> String {
+    hash.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Parse `SIZE:ROOT` snapshot.
+pub fn parse_snapshot(snapshot: &str) -> Fallible<(usize, Hash)> {
+    let invalid = || err_msg(format!("Invalid snapshot, expect SIZE:ROOT: {}", snapshot));
+    let mut parts = snapshot.splitn(2, ':');
+
+    let size = parts.next()
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(invalid)?;
+    let root = parts.next().ok_or_else(invalid)?;
+
+    if root.len() != 64 || !root.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut hash = [0; 32];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&root[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok((size, hash))
+}
@@ -1,59 +1,299 @@
 pub mod db;
-mod sendto;
-mod recvfrom;
+pub mod dir;
+pub mod lock;
+pub mod log;
+mod bundle;
+pub mod wkd;
+pub mod sendto;
+pub mod recvfrom;
 
+use std::env;
+use std::io::{ self, Read };
 use std::fs::File;
+use std::path::{ Path, PathBuf };
 use failure::{ Fallible, err_msg };
 use directories::ProjectDirs;
 use serde_cbor as cbor;
 use crate::core::format::{ PublicKey, Envelope };
-use crate::common::Stdio;
+use crate::core::{ alg, key };
+use crate::core::contact::{ self, ContactStore, Record, Sealer, Source, Status };
+use crate::core::error::StoreError;
+use crate::common::{ Stdio, KeyChanged, format_time };
 use crate::opts::Contact;
+use crate::agent::Session;
+use crate::profile::{ self, keyring::Keyring };
+use crate::keyserver::client::Client;
+use crate::mail::message::Message;
+use crate::mail::autocrypt::{ self, Update };
 use self::db::Db;
+use self::dir::DirStore;
+use self::lock::Access;
+use self::log::{ KeyLog, Logged, hex, parse_snapshot };
 
 
+/// Run an operation of store backend, keep its error and causes in `StoreError`.
+pub fn backend<T, F>(f: F) -> Result<T, StoreError>
+    where F: FnOnce() -> Fallible<T>
+{
+    f().map_err(store_error)
+}
+
+pub fn store_error(err: failure::Error) -> StoreError {
+    match err.downcast::<StoreError>() {
+        Ok(err) => err,
+        Err(err) => StoreError::Backend(
+            err.iter_chain()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(": ")
+        )
+    }
+}
+
+/// Open contact store,
+/// use the plain directory store if `ENE_CONTACT_DIR` is set.
+///
+/// Protected store needs its Profile, from agent or passphrase.
+/// New keys are appended to the key log, which is sealed like the store.
+pub fn open_store(dir: &ProjectDirs, access: Access) -> Fallible<Box<dyn ContactStore>> {
+    open_store_with(dir, access).map(|(store, _)| store)
+}
+
+/// Open contact store, and return the Profile which unlocked it,
+/// so its passphrase is not asked again.
+pub fn open_store_with(dir: &ProjectDirs, access: Access)
+    -> Fallible<(Box<dyn ContactStore>, Option<Session>)>
+{
+    let (store, session) = open_logged(dir, access)?;
+    Ok((Box::new(store), session))
+}
+
+/// Open contact store for the Profile which is going to use it.
+///
+/// The Profile which unlocked the store is reused if it is the wanted one,
+/// and the store is refused if it has lost the protection the Profile requires.
+pub fn open_store_as(dir: &ProjectDirs, access: Access, profile: Option<&Path>, id: Option<&str>)
+    -> Fallible<(Box<dyn ContactStore>, Session)>
+{
+    let (store, unlocked) = open_store_with(dir, access)?;
+
+    let wanted = match (&unlocked, profile) {
+        (Some(_), None) => wanted(dir, id)?,
+        _ => None
+    };
+    let session = match unlocked.filter(|session| wanted.as_ref().map(String::as_str) == Some(session.get_id())) {
+        Some(session) => session,
+        None => Session::open(dir, profile, id)?
+    };
+
+    check_protected(dir, &session)?;
+    Ok((store, session))
+}
+
+/// ID of the Profile `Session::open` takes without a Profile path, `None` if it is held by agent.
+fn wanted(dir: &ProjectDirs, id: Option<&str>) -> Fallible<Option<String>> {
+    match id {
+        Some(id) => Ok(Some(id.to_string())),
+        None if Session::agent(dir)?.is_some() => Ok(None),
+        None => Keyring::new(dir)?.get_default()
+    }
+}
+
+/// Refuse the default store if the Profile requires it to be protected, but it is not.
+///
+/// The protection record in keyring can be deleted along with the store,
+/// the requirement sealed in the Profile can not.
+pub fn check_protected(dir: &ProjectDirs, session: &Session) -> Fallible<()> {
+    if session.settings().contact_protected
+        && env::var_os("ENE_CONTACT_DIR").is_none()
+        && !Keyring::new(dir)?.contact_protection().is_file()
+    {
+        return Err(err_msg(format!(
+            "Contact store must be protected by {}, but its protection has been removed, \
+                run `ene contact --protect` again if the store was reset on purpose",
+            session.get_id()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Open contact store with its key log.
+fn open_logged(dir: &ProjectDirs, access: Access) -> Fallible<(Logged, Option<Session>)> {
+    let mut session = None;
+    let mut log_sealer = None;
+
+    let store: Box<dyn ContactStore> = if let Some(path) = env::var_os("ENE_CONTACT_DIR") {
+        Box::new(DirStore::new(Path::new(&path), access)?)
+    } else {
+        let meta_path = Keyring::new(dir)?.contact_protection();
+        let mut db = Db::new(&dir.data_local_dir().join("sled"), &meta_path, access)?;
+
+        if let Some((id, enc)) = db.protected_by().map(|(id, enc)| (id.to_string(), enc)) {
+            let unlocked = Session::open(dir, None, Some(&id))?;
+            db.unlock(unlocked.sealer(Sealer::CONTEXT, enc)?)?;
+            log_sealer = Some((unlocked.sealer(Sealer::CONTEXT, enc)?, db.is_encrypted()));
+            session = Some(unlocked);
+        }
+
+        Box::new(db)
+    };
+
+    let log = KeyLog::open(&log_path(dir), access == Access::Write, log_sealer)?;
+    Ok((Logged::new(store, log), session))
+}
+
+/// Key log lives beside the contact store.
+fn log_path(dir: &ProjectDirs) -> PathBuf {
+    match env::var_os("ENE_CONTACT_DIR") {
+        Some(path) => Path::new(&path).join(".keylog"),
+        None => dir.data_local_dir().join("keylog")
+    }
+}
+
+/// Encrypt or authenticate the sled contact store with a Profile.
+fn protect(
+    dir: &ProjectDirs,
+    quiet: bool,
+    stdio: &mut Stdio,
+    as_id: Option<&str>,
+    encrypted: bool
+) -> Fallible<()> {
+    if env::var_os("ENE_CONTACT_DIR").is_some() {
+        return Err(err_msg("Only the default contact store can be protected"));
+    }
+
+    let meta_path = Keyring::new(dir)?.contact_protection();
+    let mut db = Db::new(&dir.data_local_dir().join("sled"), &meta_path, Access::Write)?;
+
+    let session = match db.protected_by().map(|(id, enc)| (id.to_string(), enc)) {
+        Some((id, enc)) => {
+            if as_id.map(|as_id| as_id != id).unwrap_or(false) {
+                return Err(err_msg(format!("Contact store is already protected by {}", id)));
+            }
+
+            let session = Session::open(dir, None, Some(&id))?;
+            db.unlock(session.sealer(Sealer::CONTEXT, enc)?)?;
+            session
+        },
+        None => Session::open(dir, None, as_id)?
+    };
+
+    // a missing protection record is refused from now on
+    if !session.settings().contact_protected {
+        profile::require_contact_protection(&Keyring::new(dir)?.path(session.get_id())?)?;
+    }
+
+    // the key log is verified with the old seal before it is resealed
+    let log_sealer = match db.protected_by() {
+        Some((_, enc)) => Some((session.sealer(Sealer::CONTEXT, enc)?, db.is_encrypted())),
+        None => None
+    };
+    let log = KeyLog::open(&log_path(dir), false, log_sealer)?;
+
+    let enc = alg::Encrypt::Aes128Colm0;
+    let count = db.protect(session.get_id(), enc, session.sealer(Sealer::CONTEXT, enc)?, encrypted)?;
+    log.reseal(session.sealer(Sealer::CONTEXT, enc)?, encrypted)?;
+
+    if !quiet {
+        stdio.info(format_args!(
+            "{} contacts have been {} for {}!",
+            count,
+            if encrypted { "encrypted" } else { "authenticated" },
+            session.get_id()
+        ))?;
+    }
+
+    Ok(())
+}
+
 impl Contact {
     pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
-        let db_path = dir.data_local_dir().join("sled");
-        let db = Db::new(&db_path)?;
+        if self.encrypt || self.protect {
+            return protect(dir, quiet, stdio, self.as_id.as_ref().map(String::as_str), self.encrypt);
+        }
+
+        let access = if self.list
+            || self.groups
+            || self.log
+            || self.log_proof.is_some()
+            || self.log_verify.is_some()
+            || self.find.is_some()
+            || self.export.is_some()
+            || self.export_all.is_some()
+            || self.dry_run
+        {
+            Access::Read
+        } else {
+            Access::Write
+        };
+        let logged = open_logged(dir, access)?.0;
+        let db: &dyn ContactStore = &logged;
 
         if self.list {
             let id = self.id.unwrap_or_default();
-            let mut started = false;
-
-            for item in db.filter(&id) {
-                let (id, pk) = match item {
-                    Ok(item) => item,
-                    Err(err) => {
-                        stdio.warn(format_args!("{:?}", err))?;
-                        continue
-                    }
-                };
-
-                if quiet {
-                    stdio.info(format_args!("{}: {:?}", id, pk.to_short()))?;
-                } else {
-                    if started {
-                        stdio.info(format_args!(""))?;
-                    }
+            show_all(quiet, stdio, db.scan(&id))?;
+        } else if let Some(fingerprint) = self.find {
+            let prefix = parse_fingerprint(&fingerprint)?;
 
-                    stdio.info(format_args!("uid: {}\npub: {:#?}", id, pk.to_short()))?;
-                    started = true;
-                }
+            if show_all(quiet, stdio, db.find(&prefix))? == 0 {
+                return Err(err_msg(format!("No contact matches {}", fingerprint)));
             }
         } else if let Some(path) = self.import {
-            let pk: PublicKey = cbor::from_reader(&mut File::open(path)?)?;
-            let (id, pk) = unwrap!(&pk);
+            let pk: PublicKey = cbor::from_reader(&mut File::open(&path)?)?;
+            let (id, pk) = unwrap!(pk);
+
+            import(quiet, stdio, &*db, id, pk, Source::File(path.display().to_string()))?;
+        } else if let Some(query) = self.fetch {
+            let client = Client::new(self.keyserver.as_ref().map(String::as_str))?;
+
+            let (id, pk) = if query.starts_with("0x") {
+                client.fetch_fingerprint(&parse_fingerprint(&query)?)?
+            } else {
+                let pk = client.fetch_id(&query)?;
+                (query, pk)
+            };
+
+            if !quiet {
+                stdio.info(format_args!("fetched {}: {:?}", id, pk.to_short()))?;
+            }
+
+            import(quiet, stdio, &*db, id, pk, Source::KeyServer(client.url().to_string()))?;
+        } else if self.autocrypt {
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input)?;
+            let message = Message::parse(&input)?;
 
-            db.set(&id, &pk)?;
+            let (id, update) = autocrypt::update(&*db, &message, contact::now())?;
+
+            match update {
+                Update::Ignored => if !quiet {
+                    stdio.info(format_args!("{} is not a contact, and has no Autocrypt header", id))?;
+                },
+                Update::New => if !quiet {
+                    stdio.info(format_args!("{} has been imported!", id))?;
+                },
+                Update::Seen => if !quiet {
+                    stdio.info(format_args!("{} is already up to date!", id))?;
+                },
+                Update::Updated => if !quiet {
+                    stdio.info(format_args!("Autocrypt state of {} has been updated!", id))?;
+                },
+                Update::Changed => return Err(KeyChanged(id).into())
+            }
+        } else if let Some(email) = self.discover {
+            // the ID may differ from the typed one in case, keep the published one
+            let (id, pk, source) = wkd::discover(self.wkd_base.as_ref().map(String::as_str), &email)?;
 
             if !quiet {
-                stdio.info(format_args!("{} has been imported!", id))?;
+                stdio.info(format_args!("discovered {}: {:?}", id, pk.to_short()))?;
             }
+
+            import(quiet, stdio, &*db, id, pk, source)?;
         } else if let Some(path) = self.export {
             let id = self.id.unwrap();
 
-            let pk = db.get(&id)?
+            let pk = db.get_pk(&id)?
                 .ok_or_else(|| err_msg("ID does not exist."))?;
             let pk_encoded: PublicKey = Envelope::from((id.to_string(), pk));
 
@@ -62,6 +302,180 @@ impl Contact {
             if !quiet {
                 stdio.info(format_args!("{} has been exported to {}!", id, path.canonicalize()?.display()))?;
             }
+        } else if let Some(path) = self.export_all {
+            let session = if self.sign {
+                let session = Session::open(dir, None, self.as_id.as_ref().map(String::as_str))?;
+                check_protected(dir, &session)?;
+                Some(session)
+            } else {
+                None
+            };
+
+            let count = bundle::export(
+                stdio,
+                &*db,
+                &self.id.unwrap_or_default(),
+                session.as_ref(),
+                &path
+            )?;
+
+            if !quiet {
+                stdio.info(format_args!("{} contacts have been exported to {}!", count, path.canonicalize()?.display()))?;
+            }
+        } else if let Some(path) = self.import_bundle {
+            bundle::import(quiet, stdio, &*db, &path, self.dry_run, self.force)?;
+        } else if !self.group_add.is_empty() {
+            let (name, members) = self.group_add.split_first().unwrap();
+            let name = name.trim_start_matches(contact::GROUP_PREFIX);
+            let mut group = db.get_group(name)?.unwrap_or_default();
+
+            for member in members {
+                if db.get(member)?.is_none() {
+                    stdio.warn(format_args!("{} is not a contact yet", member))?;
+                }
+
+                if !group.contains(member) {
+                    group.push(member.clone());
+                }
+            }
+
+            db.set_group(name, &group)?;
+
+            if !quiet {
+                stdio.info(format_args!("@{}: {}", name, group.join(", ")))?;
+            }
+        } else if !self.group_del.is_empty() {
+            let (name, members) = self.group_del.split_first().unwrap();
+            let name = name.trim_start_matches(contact::GROUP_PREFIX);
+            let mut group = db.get_group(name)?
+                .ok_or_else(|| err_msg(format!("Group does not exist: {}", name)))?;
+
+            if members.is_empty() {
+                group.clear();
+            } else {
+                group.retain(|member| !members.contains(member));
+            }
+
+            db.set_group(name, &group)?;
+
+            if !quiet {
+                if group.is_empty() {
+                    stdio.info(format_args!("@{} has been deleted!", name))?;
+                } else {
+                    stdio.info(format_args!("@{}: {}", name, group.join(", ")))?;
+                }
+            }
+        } else if self.groups {
+            for (name, members) in db.groups()? {
+                stdio.info(format_args!("@{}: {}", name, members.join(", ")))?;
+            }
+        } else if self.log {
+            let log = logged.log();
+            let id = self.id.as_ref();
+            let entries = log.entries()
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| id.map(|id| id == &entry.id).unwrap_or(true));
+
+            for (i, entry) in entries {
+                stdio.info(format_args!(
+                    "{}: {} {} {}: {:?} from {}",
+                    i, format_time(entry.time), entry.event, entry.id, entry.short, entry.source
+                ))?;
+            }
+
+            if !quiet {
+                stdio.info(format_args!("snapshot: {}:{}", log.size(), hex(&log.root())))?;
+            }
+        } else if let Some(size) = self.log_proof {
+            let log = logged.log();
+
+            if !quiet {
+                stdio.info(format_args!("snapshot: {}:{}", log.size(), hex(&log.root())))?;
+            }
+
+            for hash in log.prove(size)? {
+                stdio.info(format_args!("{}", hex(&hash)))?;
+            }
+        } else if let Some(snapshot) = self.log_verify {
+            let log = logged.log();
+            let (size, root) = parse_snapshot(&snapshot)?;
+
+            if !log.verify(size, &root) {
+                return Err(err_msg(format!(
+                    "Key log is not consistent with snapshot {}, it has been rewritten",
+                    snapshot
+                )));
+            }
+
+            if !quiet {
+                stdio.info(format_args!(
+                    "Snapshot {} is consistent with {}:{}",
+                    snapshot, log.size(), hex(&log.root())
+                ))?;
+            }
+        } else if self.edit {
+            let id = self.id.unwrap();
+
+            let mut record = db.get(&id)?
+                .ok_or_else(|| err_msg("ID does not exist."))?;
+
+            if let Some(name) = self.name {
+                record.name = Some(name);
+            }
+
+            if !self.alias.is_empty() {
+                record.aliases = self.alias;
+            }
+
+            if let Some(notes) = self.notes {
+                record.notes = Some(notes);
+            }
+
+            if let Some(verified) = self.verified {
+                // revocation is not undone by verification
+                if let Status::Revoked = record.status {
+                    return Err(err_msg(format!("PublicKey of {} is revoked, it can not be marked as verified", id)));
+                }
+
+                record.status = if verified { Status::Verified } else { Status::Unverified };
+            }
+
+            if let Some(protocol) = self.prefer_protocol {
+                record.prefs.protocol = Some(protocol);
+            }
+
+            if self.revoke {
+                record.status = Status::Revoked;
+            }
+
+            match self.expires_in {
+                Some(0) => record.expires = None,
+                Some(days) => record.expires = Some(contact::now() + days * 24 * 60 * 60),
+                None => ()
+            }
+
+            db.set(&id, &record)?;
+
+            if !quiet {
+                show(stdio, &id, &record)?;
+            }
+        } else if self.accept_change {
+            let id = self.id.unwrap();
+
+            let mut record = db.get(&id)?
+                .ok_or_else(|| err_msg("ID does not exist."))?;
+
+            if !record.accept_change() {
+                return Err(err_msg(format!("PublicKey of {} has not changed", id)));
+            }
+
+            db.set(&id, &record)?;
+
+            if !quiet {
+                stdio.info(format_args!("New PublicKey of {} has been accepted!", id))?;
+                show(stdio, &id, &record)?;
+            }
         } else if self.delete {
             let id = self.id.unwrap();
 
@@ -77,3 +491,125 @@ impl Contact {
         Ok(())
     }
 }
+
+/// Import a PublicKey, a changed key is kept as pending.
+fn import(
+    quiet: bool,
+    stdio: &mut Stdio,
+    db: &dyn ContactStore,
+    id: String,
+    pk: key::PublicKey,
+    source: Source
+) -> Fallible<()> {
+    if let Some(mut record) = db.get(&id)? {
+        let pinned = record.observe(&pk);
+        db.set(&id, &record)?;
+
+        if !pinned {
+            return Err(KeyChanged(id).into());
+        }
+
+        if !quiet {
+            stdio.info(format_args!("{} is already up to date!", id))?;
+        }
+    } else {
+        let record = Record::new(pk, source);
+        db.set(&id, &record)?;
+
+        if !quiet {
+            stdio.info(format_args!("{} has been imported!", id))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lowercase hex fingerprint prefix, `0x` is optional.
+fn parse_fingerprint(fingerprint: &str) -> Fallible<String> {
+    let prefix = fingerprint.trim_start_matches("0x").to_lowercase();
+
+    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(err_msg(format!("Invalid fingerprint: {}", fingerprint)));
+    }
+
+    Ok(prefix)
+}
+
+fn show_all<I>(quiet: bool, stdio: &mut Stdio, iter: I) -> Fallible<usize>
+    where I: Iterator<Item = Result<(String, Record), StoreError>>
+{
+    let mut count = 0;
+
+    for item in iter {
+        let (id, record) = match item {
+            Ok(item) => item,
+            Err(err) => {
+                stdio.warn(format_args!("{:?}", err))?;
+                continue
+            }
+        };
+
+        if quiet {
+            stdio.info(format_args!("{}: {:?}", id, record.pk.to_short()))?;
+        } else {
+            if count > 0 {
+                stdio.info(format_args!(""))?;
+            }
+
+            show(stdio, &id, &record)?;
+        }
+
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn show(stdio: &mut Stdio, id: &str, record: &Record) -> Fallible<()> {
+    stdio.info(format_args!("uid: {}\npub: {:#?}", id, record.pk.to_short()))?;
+
+    if let Some(ref name) = record.name {
+        stdio.info(format_args!("name: {}", name))?;
+    }
+
+    if !record.aliases.is_empty() {
+        stdio.info(format_args!("aliases: {}", record.aliases.join(", ")))?;
+    }
+
+    if let Some(ref notes) = record.notes {
+        stdio.info(format_args!("notes: {}", notes))?;
+    }
+
+    stdio.info(format_args!("added: {}", format_time(record.added)))?;
+    stdio.info(format_args!("source: {}", record.source))?;
+    stdio.info(format_args!("status: {}", record.status))?;
+
+    if let Some(expires) = record.expires {
+        stdio.info(format_args!("expires: {}", format_time(expires)))?;
+    }
+
+    if let Some(ref state) = record.autocrypt {
+        stdio.info(format_args!(
+            "autocrypt: {} (last seen {})",
+            if state.prefer_encrypt { "mutual" } else { "nopreference" },
+            format_time(state.last_seen)
+        ))?;
+    }
+
+    if let Some(ref protocol) = record.prefs.protocol {
+        stdio.info(format_args!("protocol: {:?}", protocol))?;
+    }
+
+    for seen in &record.history {
+        stdio.info(format_args!(
+            "seen: {:?} ({} - {})",
+            seen.pk.to_short(), format_time(seen.first_seen), format_time(seen.last_seen)
+        ))?;
+    }
+
+    if let Some(ref pk) = record.pending {
+        stdio.warn(format_args!("changed: {:#?}", pk.to_short()))?;
+    }
+
+    Ok(())
+}
@@ -1,13 +1,18 @@
 use std::io::Write;
+use std::path::PathBuf;
 use std::fs::{ self, File };
 use failure::{ Fallible, err_msg };
 use serde_cbor as cbor;
 use directories::ProjectDirs;
-use crate::core::alg;
-use crate::core::format::{ PrivateKey, PublicKey, Message, Meta };
-use crate::{ profile, opts::RecvFrom };
-use crate::common::{ Cbor, Stdio, askpass };
-use super::db::Db;
+use crate::core::{ alg, key };
+use crate::core::format::{ PublicKey, Message, Meta };
+use crate::opts::RecvFrom;
+use crate::agent::Session;
+use crate::profile::keyring::Keyring;
+use crate::core::contact::{ self, ContactStore, Record, Source };
+use crate::common::{ Stdio, KeyChanged, confirm };
+use super::{ open_store_with, check_protected };
+use super::lock::Access;
 
 
 impl RecvFrom {
@@ -18,24 +23,29 @@ impl RecvFrom {
         let (meta, proto, message_encrypted) = unwrap!(message_packed);
         let Meta { s: (sender_id, sender_pk), r } = meta;
 
-        // take sender
-        let sender_pk = match (self.force, self.sender, self.sender_pubkey) {
-            (true, _, _) => sender_pk,
-            (_, Some(id), _) => if id == sender_id {
-                let db_path = dir.data_local_dir().join("sled");
-                let db = Db::new(&db_path)?;
-                let pk = db.get(&sender_id)?
-                    .ok_or_else(|| err_msg("not found"))?;
-
-                pk.contains(&sender_pk, |name, pk, send_pk|
-                    stdio.warn(format_args!("sender {} pk different: {:?}, {:?}", name, pk, send_pk))
-                )?;
+        // open contact store once, the Profile which unlocked it is reused to decrypt
+        let ask_import = self.force && !quiet;
+        let (contact, mut unlocked) = if self.sender.is_some() || self.import_sender || ask_import {
+            let (db, session) = open_store_with(dir, Access::Write)?;
+            (Some(db), session)
+        } else {
+            (None, None)
+        };
 
-                pk
-            } else {
-                return Err(err_msg(format!("sender id different: {} {}", id, sender_id)))
-            },
-            (_, _, Some(path)) => {
+        // take sender, the contact is only updated once the message is verified,
+        // the key in message is only taken for an ID which is not a contact yet
+        let known = match &contact {
+            Some(db) => db.get(&sender_id)?.is_some(),
+            None => false
+        };
+        let sender_pk = match (&self.sender, &contact) {
+            (Some(id), _) if *id != sender_id =>
+                return Err(err_msg(format!("sender id different: {} {}", id, sender_id))),
+            (_, Some(db)) if known => pinned_sender(&**db, &sender_id, &sender_pk)?,
+            _ if self.force || self.import_sender => sender_pk,
+            (Some(_), Some(db)) => pinned_sender(&**db, &sender_id, &sender_pk)?,
+            _ => {
+                let path = self.sender_pubkey.as_ref().ok_or_else(|| err_msg("no sender"))?;
                 let pk_packed: PublicKey = cbor::from_reader(&mut File::open(path)?)?;
                 let (id, pk) = unwrap!(pk_packed);
 
@@ -48,53 +58,74 @@ impl RecvFrom {
                 } else {
                     return Err(err_msg(format!("sender id different: {} {}", id, sender_id)))
                 }
-            },
-            (..) => unreachable!()
+            }
         };
 
-        // take receiver
-        let sk_packed: PrivateKey = if let Some(ref sk_path) = self.profile {
-            cbor::from_reader(&mut File::open(sk_path)?)?
-        } else {
-            let sk_path = dir.data_local_dir().join("key.ene");
-            cbor::from_reader(&mut File::open(sk_path)?)?
+        // take plaintext, Sonly protocol
+        let plaintext = match (&proto, self.plaintext) {
+            (alg::Protocol::Sonly(..), Some(path)) => Some(fs::read(path)?),
+            _ => None
         };
 
-        // decrypt sk
-        let sk = askpass(|pass| profile::open(pass.as_bytes(), &sk_packed))?;
-        let sk = sk.read();
+        let decrypt = |session: &Session| session.recvfrom(
+            (&sender_id, &sender_pk),
+            &proto, aad.as_bytes(), &message_encrypted,
+            plaintext.as_ref().map(Vec::as_slice)
+        );
 
-        if let Some((receiver_id, receiver_pk)) = r {
-            let (id, ..) = unwrap!(&sk_packed);
+        // take receiver and decrypt message
+        let (session, message) = if self.profile.is_some() || self.as_id.is_some() {
+            let session = match unlocked.take() {
+                Some(session) if self.profile.is_none() && self.as_id.as_ref()
+                    .map(|id| id == session.get_id())
+                    .unwrap_or(false) => session,
+                _ => Session::open(
+                    dir,
+                    self.profile.as_ref().map(PathBuf::as_path),
+                    self.as_id.as_ref().map(String::as_str)
+                )?
+            };
 
-            if id != &receiver_id {
-                stdio.warn(format_args!("recipient id different: {} {}", id, receiver_id))?;
-            }
+            if let Some((receiver_id, receiver_pk)) = &r {
+                let id = session.get_id();
 
-            let short_pk = sk.as_secret().to_public().to_short();
-            short_pk.contains(&receiver_pk, |name, pk, recv_pk|
-                stdio.warn(format_args!("recipient {} pk different: {:?}, {:?}", name, pk, recv_pk))
-            )?;
-        }
+                if id != receiver_id.as_str() {
+                    stdio.warn(format_args!("recipient id different: {} {}", id, receiver_id))?;
+                }
 
-        // decrypt message
-        let message = if let alg::Protocol::Sonly(..) = proto {
-            let plaintext = if let Some(path) = self.plaintext {
-                Some(fs::read(path)?)
-            } else {
-                None
-            };
+                let short_pk = session.as_public().to_short();
+                short_pk.contains(receiver_pk, |name, pk, recv_pk|
+                    stdio.warn(format_args!("recipient {} pk different: {:?}, {:?}", name, pk, recv_pk))
+                )?;
+            }
 
-            sk.and(&sender_id, &sender_pk)
-                .recvfrom::<Cbor>(&proto, aad.as_bytes(), &message_encrypted, plaintext.as_ref().map(Vec::as_slice))?
+            let message = decrypt(&session)?;
+            (session, message)
+        } else if let alg::Protocol::Sonly(..) = proto {
+            // signature only, any Profile is fine
+            let session = match unlocked.take() {
+                Some(session) => session,
+                None => Session::open(dir, None, None)?
+            };
+            let message = decrypt(&session)?;
+            (session, message)
         } else {
-            sk.and(&sender_id, &sender_pk)
-                .recvfrom::<Cbor>(&proto, aad.as_bytes(), &message_encrypted, None)?
+            select(dir, stdio, r.as_ref(), unlocked, decrypt)?
         };
 
+        if contact.is_some() {
+            check_protected(dir, &session)?;
+        }
+
+        // a changed key of contact is reported before anything is printed
+        match &contact {
+            Some(db) if known => observe_sender(&**db, stdio, &sender_id, &sender_pk)?,
+            _ => ()
+        }
+
         if !quiet {
             stdio.info(format_args!("sender: {}", sender_id))?;
-            stdio.info(format_args!("recipient: {}", sk.get_id()))?;
+            stdio.info(format_args!("recipient: {}", session.get_id()))?;
             stdio.info(format_args!(""))?;
         }
 
@@ -105,6 +136,159 @@ impl RecvFrom {
             stdio.print(|stdout| stdout.lock().write_all(&message))?;
         }
 
+        // trust on first use, only after the message is verified,
+        // and written so a failed import does not lose it
+        match &contact {
+            Some(db) if !known && (
+                self.import_sender
+                    || (ask_import && confirm(&format!("Import {} as unverified contact?", sender_id)))
+            ) => import_sender(&**db, quiet, stdio, &sender_id, &sender_pk)?,
+            _ => ()
+        }
+
         Ok(())
     }
 }
+
+/// Key to verify the message of contact with.
+///
+/// It is the pinned key, or the key in message if it has changed,
+/// nothing is stored until `observe_sender` is called with the verified message.
+pub fn pinned_sender(db: &dyn ContactStore, id: &str, sender_pk: &key::PublicKey)
+    -> Fallible<key::PublicKey>
+{
+    let record = db.get(id)?
+        .ok_or_else(|| err_msg(format!("Sender {} is not a contact", id)))?;
+
+    if contact::same(&record.pk, sender_pk) {
+        Ok(record.pk)
+    } else {
+        Ok(sender_pk.clone())
+    }
+}
+
+/// Record the key of a verified message,
+/// fail with `KeyChanged` if it differs from the pinned key.
+pub fn observe_sender(
+    db: &dyn ContactStore,
+    stdio: &mut Stdio,
+    id: &str,
+    sender_pk: &key::PublicKey
+) -> Fallible<()> {
+    let mut record = db.get(id)?
+        .ok_or_else(|| err_msg(format!("Sender {} is not a contact", id)))?;
+
+    let pinned = record.observe(sender_pk);
+    db.set(id, &record)?;
+
+    if !pinned {
+        record.pk.contains(sender_pk, |name, pk, send_pk|
+            stdio.warn(format_args!("sender {} pk different: {:?}, {:?}", name, pk, send_pk))
+        )?;
+
+        return Err(KeyChanged(id.to_string()).into());
+    }
+
+    Ok(())
+}
+
+/// Store the key of a sender which is not a contact yet as unverified contact.
+fn import_sender(
+    db: &dyn ContactStore,
+    quiet: bool,
+    stdio: &mut Stdio,
+    id: &str,
+    pk: &key::PublicKey
+) -> Fallible<()> {
+    db.set(id, &Record::new(pk.clone(), Source::Message))?;
+
+    if !quiet {
+        stdio.info(format_args!("{} has been imported as unverified contact", id))?;
+    }
+
+    Ok(())
+}
+
+/// Pick the Profile that matches recipient.
+///
+/// The Profile held by agent and the `unlocked` one are tried first, they need no passphrase.
+/// Then the recipient and the default Profile, then the others,
+/// since recipient ID may be hidden or its key may have been replaced.
+pub fn select<F>(
+    dir: &ProjectDirs,
+    stdio: &mut Stdio,
+    r: Option<&(String, key::ShortPublicKey)>,
+    unlocked: Option<Session>,
+    decrypt: F
+) -> Fallible<(Session, Vec<u8>)>
+    where F: Fn(&Session) -> Fallible<Vec<u8>>
+{
+    let keyring = Keyring::new(dir)?;
+    let mut ids = keyring.list()?;
+
+    let first = vec![
+        keyring.get_default()?,
+        r.map(|(receiver_id, _)| receiver_id.clone())
+    ];
+    for first_id in first.into_iter().filter_map(|id| id) {
+        if let Some(pos) = ids.iter().position(|id| id == &first_id) {
+            let first_id = ids.remove(pos);
+            ids.insert(0, first_id);
+        }
+    }
+
+    for session in Session::agent(dir)?.into_iter().chain(unlocked) {
+        ids.retain(|id| id != session.get_id());
+
+        if let Some(message) = attempt(stdio, r, &session, &decrypt)? {
+            return Ok((session, message));
+        }
+    }
+
+    for id in &ids {
+        let session = match Session::open(dir, None, Some(id)) {
+            Ok(session) => session,
+            Err(err) => {
+                stdio.warn(format_args!("skip {}: {}", id, err))?;
+                continue
+            }
+        };
+
+        if let Some(message) = attempt(stdio, r, &session, &decrypt)? {
+            return Ok((session, message));
+        }
+    }
+
+    Err(err_msg("No local Profile can decrypt this message"))
+}
+
+/// Decrypt with `session`, `None` if it is not the recipient.
+///
+/// Once the recipient key matches, the failure is final.
+fn attempt<F>(
+    stdio: &mut Stdio,
+    r: Option<&(String, key::ShortPublicKey)>,
+    session: &Session,
+    decrypt: &F
+) -> Fallible<Option<Vec<u8>>>
+    where F: Fn(&Session) -> Fallible<Vec<u8>>
+{
+    if let Some((_, receiver_pk)) = r {
+        let short_pk = session.as_public().to_short();
+
+        if !short_pk.contains(receiver_pk, |_, _, _| Ok::<_, failure::Error>(()))? {
+            stdio.warn(format_args!("skip {}: recipient pk different", session.get_id()))?;
+            return Ok(None);
+        }
+    }
+
+    match decrypt(session) {
+        Ok(message) => Ok(Some(message)),
+        Err(err) => if r.is_some() {
+            Err(err)
+        } else {
+            stdio.warn(format_args!("skip {}: {}", session.get_id(), err))?;
+            Ok(None)
+        }
+    }
+}
@@ -1,70 +1,151 @@
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::fs::{ self, File };
 use failure::{ Fallible, err_msg };
 use serde_cbor as cbor;
 use directories::ProjectDirs;
-use crate::core::format::{ PrivateKey, PublicKey };
-use crate::{ profile, opts::SendTo };
-use crate::common::{ Cbor, Stdio, askpass };
-use super::db::Db;
+use crate::core::key;
+use crate::core::alg::Protocol;
+use crate::core::format::PublicKey;
+use crate::core::contact::{ self, ContactStore, GROUP_PREFIX };
+use crate::opts::SendTo;
+use crate::agent::Session;
+use crate::common::Stdio;
+use super::open_store_as;
+use super::dir::check_path;
+use super::lock::Access;
 
 
 impl SendTo {
     pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
-        // take receiver
-        let (receiver_id, receiver_pk) = if let Some(ref pk_path) = self.recipient_pubkey {
+        // take message
+        let message = fs::read(&self.input)?;
+
+        // take receivers and sender
+        let profile = self.profile.as_ref().map(PathBuf::as_path);
+        let as_id = self.as_id.as_ref().map(String::as_str);
+        let (receivers, group, session) = if let Some(ref pk_path) = self.recipient_pubkey {
             let pk_packed: PublicKey = cbor::from_reader(&mut File::open(pk_path)?)?;
-            unwrap!(pk_packed)
-        } else if let Some(id) = self.recipient {
-            let db_path = dir.data_local_dir().join("sled");
-            let db = Db::new(&db_path)?;
-            let pk = db.get(&id)?
-                .ok_or_else(|| err_msg("ID does not exist."))?;
-            (id, pk)
-        } else {
-            unreachable!()
-        };
+            let (id, pk) = unwrap!(pk_packed);
+            (vec![(id, pk, None)], false, Session::open(dir, profile, as_id)?)
+        } else if let Some(ref id) = self.recipient {
+            let (db, session) = open_store_as(dir, Access::Read, profile, as_id)?;
 
-        // take sender
-        let sk_packed: PrivateKey = if let Some(ref sk_path) = self.profile {
-            cbor::from_reader(&mut File::open(sk_path)?)?
+            if id.starts_with(GROUP_PREFIX) {
+                (expand(stdio, &*db, &id[GROUP_PREFIX.len_utf8()..])?, true, session)
+            } else {
+                let (pk, protocol) = receiver(stdio, &*db, id)?;
+                (vec![(id.clone(), pk, protocol)], false, session)
+            }
         } else {
-            let sk_path = dir.data_local_dir().join("key.ene");
-            cbor::from_reader(&mut File::open(sk_path)?)?
+            unreachable!()
         };
 
         let SendTo { input, output, protocol, associated_data, .. } = self;
-
-        // take aad and message
         let aad = associated_data.unwrap_or_default();
-        let message = fs::read(&input)?;
-
-        // decrypt sk
-        let sk = askpass(|pass| profile::open(pass.as_bytes(), &sk_packed))?;
-        let sk = sk.read();
-
-        // encrypt message
-        let message_packed = sk.and(&receiver_id, &receiver_pk)
-            .sendto::<Cbor>(&protocol, aad.as_bytes(), &message)?;
-
-        // output
-        let output = if output.is_dir() {
-            if let Some(ext) = input.extension() {
-                let mut ext = ext.to_os_string();
-                ext.push(".ene");
-                input.with_extension(ext)
+
+        if group && !output.is_dir() {
+            return Err(err_msg("Output must be a directory when sending to a group"));
+        }
+
+        for (receiver_id, receiver_pk, prefer_protocol) in &receivers {
+            // encrypt message
+            let protocol = match protocol.clone().or_else(|| prefer_protocol.clone()) {
+                Some(protocol) => protocol,
+                None => Protocol::from_str(Protocol::default_name())?
+            };
+            let message_packed = session
+                .sendto((receiver_id, receiver_pk), &protocol, aad.as_bytes(), &message)?;
+
+            // output
+            let output = if group {
+                let mut name = input.file_name()
+                    .map(|name| name.to_os_string())
+                    .unwrap_or_default();
+                name.push(format!(".{}.ene", receiver_id));
+                output.join(name)
+            } else if output.is_dir() {
+                if let Some(ext) = input.extension() {
+                    let mut ext = ext.to_os_string();
+                    ext.push(".ene");
+                    input.with_extension(ext)
+                } else {
+                    input.with_extension("ene")
+                }
             } else {
-                input.with_extension("ene")
-            }
-        } else {
-            output
-        };
+                output.clone()
+            };
 
-        cbor::to_writer(&mut File::create(&output)?, &message_packed)?;
+            cbor::to_writer(&mut File::create(&output)?, &message_packed)?;
 
-        if !quiet {
-            stdio.info(format_args!("Message is encrypted to {}", output.canonicalize()?.display()))?;
+            if !quiet {
+                stdio.info(format_args!("Message is encrypted to {}", output.canonicalize()?.display()))?;
+            }
         }
 
         Ok(())
     }
 }
+
+/// Take usable key and preferred protocol of contact.
+pub fn receiver(stdio: &mut Stdio, db: &dyn ContactStore, id: &str)
+    -> Fallible<(key::PublicKey, Option<Protocol>)>
+{
+    let record = db.get(id)?
+        .ok_or_else(|| err_msg(format!("ID does not exist: {}", id)))?;
+
+    if let Some(reason) = record.unusable(contact::now()) {
+        return Err(err_msg(format!("PublicKey of {} is {}", id, reason)));
+    }
+
+    if record.pending.is_some() {
+        stdio.warn(format_args!(
+            "PublicKey of {} has changed, still use the pinned key until the change is accepted",
+            id
+        ))?;
+    }
+
+    Ok((record.pk, record.prefs.protocol))
+}
+
+/// Take usable members of group, report the others.
+fn expand(stdio: &mut Stdio, db: &dyn ContactStore, name: &str)
+    -> Fallible<Vec<(String, key::PublicKey, Option<Protocol>)>>
+{
+    let members = db.get_group(name)?
+        .ok_or_else(|| err_msg(format!("Group does not exist: {}", name)))?;
+    let now = contact::now();
+    let mut receivers = Vec::new();
+
+    for id in &members {
+        // IDs are used as output file names
+        if let Err(err) = check_path(id) {
+            stdio.warn(format_args!("skip {}: {}", id, err))?;
+            continue
+        }
+
+        match db.get(id) {
+            Ok(Some(record)) => if let Some(reason) = record.unusable(now) {
+                stdio.warn(format_args!("skip {}: PublicKey is {}", id, reason))?;
+            } else {
+                if record.pending.is_some() {
+                    stdio.warn(format_args!("{}: PublicKey has changed, use the pinned key", id))?;
+                }
+
+                receivers.push((id.clone(), record.pk, record.prefs.protocol));
+            },
+            Ok(None) => stdio.warn(format_args!("skip {}: PublicKey is missing", id))?,
+            Err(err) => stdio.warn(format_args!("skip {}: {}", id, err))?
+        }
+    }
+
+    if receivers.is_empty() {
+        return Err(err_msg(format!("No member of @{} can receive message", name)));
+    }
+
+    if receivers.len() < members.len() {
+        stdio.warn(format_args!("{} of {} members of @{} are skipped", members.len() - receivers.len(), members.len(), name))?;
+    }
+
+    Ok(receivers)
+}
@@ -0,0 +1,97 @@
+use std::env;
+use std::fs::{ self, File };
+use std::path::{ Path, PathBuf };
+use failure::{ Fallible, err_msg };
+use serde_cbor as cbor;
+use crate::core::key;
+use crate::core::contact::{ Source, wkd_hash };
+use crate::core::format::{ PublicKey, Envelope };
+use crate::keyserver::http::{ self, Url };
+
+
+/// Web Key Directory style layout
+///
+/// ```norun
+/// <base>/.well-known/ene/
+///     <domain>/
+///         hu/<wkd_hash(local part)>   - PublicKey
+///         policy                      - empty
+/// ```
+///
+/// Only the layout follows OpenPGP WKD, the hash is `wkd_hash` instead of SHA-1.
+const WELL_KNOWN: &str = ".well-known/ene";
+
+/// Split email ID, return lowercase domain and hashed local part.
+fn split(email: &str) -> Fallible<(String, String)> {
+    let pos = email.rfind('@')
+        .ok_or_else(|| err_msg(format!("ID is not an email: {}", email)))?;
+    let (local, domain) = (&email[..pos], &email[pos + 1..]);
+
+    // domain is used in path and HTTP request line, only host name characters are allowed
+    if local.is_empty()
+        || domain.is_empty()
+        || domain.starts_with('.')
+        || !domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    {
+        return Err(err_msg(format!("ID is not an email: {}", email)));
+    }
+
+    Ok((domain.to_lowercase(), wkd_hash(local)))
+}
+
+/// Resolve email ID under `base`, a directory or `http://` URL,
+/// default to `ENE_WKD_BASE`, return the ID of PublicKey as well.
+pub fn discover(base: Option<&str>, email: &str) -> Fallible<(String, key::PublicKey, Source)> {
+    let base = match base {
+        Some(base) => base.to_string(),
+        None => env::var("ENE_WKD_BASE")
+            .map_err(|_| err_msg("No Web Key Directory, use --wkd-base or set ENE_WKD_BASE"))?
+    };
+    let (domain, hash) = split(email)?;
+
+    let (pk_packed, source): (PublicKey, _) = if base.starts_with("http://") {
+        let url: Url = base.parse()?;
+        let path = format!("/{}/{}/hu/{}", WELL_KNOWN, domain, hash);
+        let (status, body) = http::send(&url, "GET", &path, &[])?;
+
+        if status != 200 {
+            return Err(err_msg(format!("{} is not found in {} ({})", email, base, status)));
+        }
+
+        (cbor::from_slice(&body)?, Source::KeyServer(format!("{}{}", base.trim_end_matches('/'), path)))
+    } else {
+        let path = Path::new(&base).join(WELL_KNOWN).join(&domain).join("hu").join(&hash);
+
+        if !path.is_file() {
+            return Err(err_msg(format!("{} is not found in {}", email, base)));
+        }
+
+        (cbor::from_reader(&mut File::open(&path)?)?, Source::File(path.display().to_string()))
+    };
+
+    let (id, pk) = unwrap!(pk_packed);
+
+    if !id.eq_ignore_ascii_case(email) {
+        return Err(err_msg(format!("Web Key Directory returned {} instead of {}", id, email)));
+    }
+
+    Ok((id, pk, source))
+}
+
+/// Write PublicKey of email ID into the layout under `dir`.
+pub fn export(dir: &Path, id: &str, pk: &key::PublicKey) -> Fallible<PathBuf> {
+    let (domain, hash) = split(id)?;
+    let domain_dir = dir.join(WELL_KNOWN).join(&domain);
+    fs::create_dir_all(domain_dir.join("hu"))?;
+
+    let policy_path = domain_dir.join("policy");
+    if !policy_path.is_file() {
+        File::create(&policy_path)?;
+    }
+
+    let path = domain_dir.join("hu").join(&hash);
+    let pk_packed: PublicKey = Envelope::from((id.to_string(), pk.clone()));
+    cbor::to_writer(&mut File::create(&path)?, &pk_packed)?;
+
+    Ok(path)
+}
@@ -0,0 +1,86 @@
+use std::env;
+use failure::{ Fallible, err_msg };
+use serde_bytes::ByteBuf;
+use serde_cbor as cbor;
+use crate::core::key;
+use crate::core::format::{ PublicKey, Envelope };
+use crate::core::contact::Record;
+use crate::agent::Session;
+use super::http::{ self, Url };
+use super::{ PREFIX, PUBLISH_AAD };
+
+
+pub struct Client {
+    url: String,
+    inner: Url
+}
+
+impl Client {
+    /// Use `keyserver`, or `ENE_KEYSERVER` if not given.
+    pub fn new(keyserver: Option<&str>) -> Fallible<Client> {
+        let url = match keyserver {
+            Some(url) => url.to_string(),
+            None => env::var("ENE_KEYSERVER")
+                .map_err(|_| err_msg("No key server, use --keyserver or set ENE_KEYSERVER"))?
+        };
+        let inner = url.parse()?;
+
+        Ok(Client { url, inner })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> Fallible<Vec<u8>> {
+        let (status, body) = http::send(&self.inner, method, &format!("{}{}", PREFIX, path), body)?;
+
+        if status == 200 {
+            Ok(body)
+        } else {
+            Err(err_msg(format!(
+                "Key server responded {}: {}",
+                status, String::from_utf8_lossy(&body)
+            )))
+        }
+    }
+
+    pub fn fetch_id(&self, id: &str) -> Fallible<key::PublicKey> {
+        let body = self.request("GET", &format!("/id/{}", http::encode(id)), &[])?;
+        let pk_packed: PublicKey = cbor::from_slice(&body)?;
+        let (pk_id, pk) = unwrap!(pk_packed);
+
+        if pk_id != id {
+            return Err(err_msg(format!("Key server returned {} instead of {}", pk_id, id)));
+        }
+
+        Ok(pk)
+    }
+
+    /// Fetch by lowercase hex fingerprint prefix.
+    pub fn fetch_fingerprint(&self, prefix: &str) -> Fallible<(String, key::PublicKey)> {
+        let body = self.request("GET", &format!("/fingerprint/{}", prefix), &[])?;
+        let pk_packed: PublicKey = cbor::from_slice(&body)?;
+        let (id, pk) = unwrap!(pk_packed);
+
+        if !Record::from(pk.clone()).matches(prefix) {
+            return Err(err_msg(format!(
+                "Key server returned {} ({:?}), which does not match {}",
+                id, pk.to_short(), prefix
+            )));
+        }
+
+        Ok((id, pk))
+    }
+
+    /// Publish the PublicKey of Profile, return whether it is new to key server.
+    pub fn publish(&self, session: &Session) -> Fallible<bool> {
+        let pk_packed: PublicKey = Envelope::from((session.get_id().to_string(), session.as_public().clone()));
+        let pk_bytes = cbor::to_vec(&pk_packed)?;
+        let sig = session.sign(PUBLISH_AAD, &pk_bytes)?;
+
+        let body = self.request("POST", "/publish", &cbor::to_vec(&(ByteBuf::from(pk_bytes), sig))?)?;
+        cbor::from_slice(&body).map_err(Into::into)
+    }
+}
+
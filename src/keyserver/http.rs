@@ -0,0 +1,251 @@
+use std::str::FromStr;
+use std::time::Duration;
+use std::net::TcpStream;
+use std::io::{ self, Read, Write, BufRead, BufReader };
+use failure::{ Fallible, Error, err_msg };
+
+
+/// Largest head or body accepted, PublicKey envelopes are small
+const MAX_LENGTH: usize = 1 << 20;
+
+const TIMEOUT_SECS: u64 = 30;
+
+/// `http://host[:port][/path]`, no TLS
+#[derive(Debug, Clone)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String
+}
+
+impl Url {
+    /// Host as written in the `Host` header, IPv6 literal in brackets.
+    fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        }
+    }
+}
+
+impl FromStr for Url {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Url, Self::Err> {
+        // host and path are written into the request as is
+        if s.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err(err_msg(format!("Invalid URL: {:?}", s)));
+        }
+
+        let rest = if s.starts_with("http://") {
+            &s["http://".len()..]
+        } else {
+            return Err(err_msg(format!("Unsupported URL, only http:// is supported: {}", s)));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], rest[pos..].trim_end_matches('/')),
+            None => (rest, "")
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(pos) if !authority.ends_with(']') => (
+                &authority[..pos],
+                authority[pos + 1..].parse()
+                    .map_err(|_| err_msg(format!("Invalid URL port: {}", s)))?
+            ),
+            _ => (authority, 80)
+        };
+
+        // IPv6 literal is bracketed in the URL, but not when connecting
+        let host = if host.starts_with('[') || host.ends_with(']') {
+            if host.len() < 2 || !host.starts_with('[') || !host.ends_with(']') {
+                return Err(err_msg(format!("Invalid URL host: {}", s)));
+            }
+            &host[1..host.len() - 1]
+        } else {
+            host
+        };
+
+        if host.is_empty() {
+            return Err(err_msg(format!("Invalid URL host: {}", s)));
+        }
+
+        Ok(Url { host: host.to_string(), port, path: path.to_string() })
+    }
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>
+}
+
+/// Percent-encode everything but unreserved characters.
+pub fn encode(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+                | b'-' | b'.' | b'_' | b'~' => output.push(b as char),
+            _ => output.push_str(&format!("%{:02X}", b))
+        }
+    }
+
+    output
+}
+
+pub fn decode(s: &str) -> Fallible<String> {
+    let s = s.as_bytes();
+    let mut output = Vec::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < s.len() {
+        if s[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| err_msg("Invalid percent encoding"))?;
+            output.push(hex);
+            i += 3;
+        } else {
+            output.push(s[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(output).map_err(Into::into)
+}
+
+/// Read start line and the body of `Content-Length`.
+fn read_message<R: BufRead>(reader: &mut R) -> Fallible<(String, Vec<u8>)> {
+    let mut start = String::new();
+    let mut length = 0;
+    let mut head_length = 0;
+
+    loop {
+        let mut line = String::new();
+        let n = reader.by_ref().take(MAX_LENGTH as u64).read_line(&mut line)?;
+        head_length += n;
+
+        if n == 0 || head_length > MAX_LENGTH {
+            return Err(err_msg("Malformed HTTP message"));
+        }
+
+        let line = line.trim_end();
+
+        if start.is_empty() {
+            start = line.to_string();
+        } else if line.is_empty() {
+            break
+        } else if let Some(pos) = line.find(':') {
+            if line[..pos].eq_ignore_ascii_case("content-length") {
+                length = line[pos + 1..].trim().parse()
+                    .map_err(|_| err_msg("Invalid Content-Length"))?;
+            }
+        }
+    }
+
+    if length > MAX_LENGTH {
+        return Err(err_msg("HTTP body is too large"));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    Ok((start, body))
+}
+
+pub fn read_request(stream: &mut TcpStream) -> Fallible<Request> {
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+
+    let (start, body) = read_message(&mut BufReader::new(stream))?;
+    let mut parts = start.split(' ');
+
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") =>
+            Ok(Request { method: method.to_string(), path: path.to_string(), body }),
+        _ => Err(err_msg("Malformed HTTP request"))
+    }
+}
+
+pub fn write_response(stream: &mut TcpStream, status: u16, body: &[u8]) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Internal Server Error"
+    };
+
+    let content_type = if status == 200 { "application/cbor" } else { "text/plain" };
+
+    write!(
+        stream,
+        "HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, reason, content_type, body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Send one request, return status and body.
+pub fn send(url: &Url, method: &str, path: &str, body: &[u8]) -> Fallible<(u16, Vec<u8>)> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+
+    write!(
+        stream,
+        "{} {}{} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/cbor\r\nContent-Length: {}\r\n\r\n",
+        method, url.path, path, url.authority(), body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let (start, body) = read_message(&mut BufReader::new(stream))?;
+    let status = start.split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| err_msg("Malformed HTTP response"))?;
+
+    Ok((status, body))
+}
+
+#[test]
+fn test_url() {
+    let url: Url = "http://127.0.0.1:11371/keys/".parse().unwrap();
+    assert_eq!(url.host, "127.0.0.1");
+    assert_eq!(url.port, 11371);
+    assert_eq!(url.path, "/keys");
+
+    let url: Url = "http://keys.example.org".parse().unwrap();
+    assert_eq!(url.port, 80);
+    assert_eq!(url.path, "");
+
+    let url: Url = "http://[::1]:11371/keys".parse().unwrap();
+    assert_eq!(url.host, "::1");
+    assert_eq!(url.port, 11371);
+    assert_eq!(url.authority(), "[::1]");
+
+    let url: Url = "http://[::1]".parse().unwrap();
+    assert_eq!(url.host, "::1");
+    assert_eq!(url.port, 80);
+
+    assert!("http://[::1:11371".parse::<Url>().is_err());
+    assert!("http://[]:11371".parse::<Url>().is_err());
+    assert!("https://keys.example.org".parse::<Url>().is_err());
+    assert!("http://keys.example.org/\r\nHost: evil".parse::<Url>().is_err());
+    assert!("http://keys example.org".parse::<Url>().is_err());
+}
+
+#[test]
+fn test_percent() {
+    let id = "bob+ene@core.ene/1";
+    assert_eq!(encode(id), "bob%2Bene%40core.ene%2F1");
+    assert_eq!(decode(&encode(id)).unwrap(), id);
+    assert!(decode("%zz").is_err());
+}
@@ -0,0 +1,34 @@
+//! Key server
+//!
+//! A small HTTP protocol, every body is CBOR:
+//!
+//! ```norun
+//! GET  /ene/v1/id/<ID>                    => PublicKey
+//! GET  /ene/v1/fingerprint/<hex prefix>   => PublicKey, 409 if ambiguous
+//! POST /ene/v1/publish (PublicKey, Message) => bool, whether it is new
+//! ```
+//!
+//! The published PublicKey is signed by itself with the Sonly protocol.
+//! An ID is bound to the first published PublicKey, later different keys are rejected.
+
+pub mod http;
+mod server;
+pub mod client;
+
+use std::net::TcpListener;
+use failure::Fallible;
+use directories::ProjectDirs;
+use crate::opts::KeyServer;
+use crate::common::Stdio;
+
+
+pub const PREFIX: &str = "/ene/v1";
+pub const PUBLISH_AAD: &[u8] = b"ENE-PUBLISH";
+
+impl KeyServer {
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        let keyserver_dir = self.dir.unwrap_or_else(|| dir.data_local_dir().join("keyserver"));
+        let listener = TcpListener::bind(&self.listen)?;
+        server::serve(quiet, stdio, &listener, &keyserver_dir)
+    }
+}
@@ -0,0 +1,190 @@
+use std::thread;
+use std::path::Path;
+use std::net::{ TcpListener, TcpStream };
+use std::sync::{ Arc, Mutex, mpsc::{ self, Sender } };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use failure::{ Fallible, Error };
+use serde_bytes::ByteBuf;
+use serde_cbor as cbor;
+use crate::core::format::{ PublicKey, Message, Envelope };
+use crate::core::contact::{ self, ContactStore, Record, Source };
+use crate::contact::dir::DirStore;
+use crate::contact::lock::Access;
+use crate::common::{ Stdio, verify };
+use super::http::{ self, Request };
+use super::{ PREFIX, PUBLISH_AAD };
+
+
+type Reply = Result<Vec<u8>, (u16, String)>;
+
+fn bad_request(err: Error) -> (u16, String) {
+    (400, err.to_string())
+}
+
+fn internal(err: Error) -> (u16, String) {
+    (500, err.to_string())
+}
+
+/// Most connections served at once, the others are dropped.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Log line from connection threads, written by the serving thread.
+enum Log {
+    Info(String),
+    Warn(String)
+}
+
+/// Serve the directory store forever, every connection in its own thread.
+///
+/// A slow client only holds its own thread,
+/// the store is locked only while a request is routed.
+pub fn serve(quiet: bool, stdio: &mut Stdio, listener: &TcpListener, dir: &Path) -> Fallible<()> {
+    let db = Arc::new(Mutex::new(DirStore::new(dir, Access::Write)?));
+    let listener = listener.try_clone()?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    // tests bind port 0 and read the address from here
+    stdio.info(format_args!("Key server is listening on http://{}", listener.local_addr()?))?;
+
+    thread::spawn(move || for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                let _ = sender.send(Log::Warn(err.to_string()));
+                continue
+            }
+        };
+
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            let _ = sender.send(Log::Warn(format!("{}: too many connections", peer_addr(&stream))));
+            continue
+        }
+
+        let (db, sender, connections) = (db.clone(), sender.clone(), connections.clone());
+        thread::spawn(move || {
+            handle(quiet, &db, &sender, stream);
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    });
+
+    for log in receiver {
+        match log {
+            Log::Info(line) => stdio.info(format_args!("{}", line))?,
+            Log::Warn(line) => stdio.warn(format_args!("{}", line))?
+        }
+    }
+
+    Ok(())
+}
+
+fn handle(quiet: bool, db: &Mutex<DirStore>, sender: &Sender<Log>, mut stream: TcpStream) {
+    let peer = peer_addr(&stream);
+
+    let (line, reply) = match http::read_request(&mut stream) {
+        Ok(request) => (
+            format!("{} {}", request.method, request.path),
+            match db.lock() {
+                Ok(db) => route(&db, &peer, &request),
+                Err(_) => Err((500, String::from("Key server store is poisoned")))
+            }
+        ),
+        Err(err) => (String::from("-"), Err(bad_request(err)))
+    };
+
+    let (status, body) = match reply {
+        Ok(body) => (200, body),
+        Err((status, reason)) => (status, reason.into_bytes())
+    };
+
+    if !quiet {
+        let _ = sender.send(Log::Info(format!("{} {} {}", peer, line, status)));
+    }
+
+    if let Err(err) = http::write_response(&mut stream, status, &body) {
+        let _ = sender.send(Log::Warn(format!("{}: {}", peer, err)));
+    }
+}
+
+fn peer_addr(stream: &TcpStream) -> String {
+    stream.peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default()
+}
+
+fn route(db: &DirStore, peer: &str, request: &Request) -> Reply {
+    let path = if request.path.starts_with(PREFIX) {
+        &request.path[PREFIX.len()..]
+    } else {
+        return Err((404, format!("Not found: {}", request.path)));
+    };
+
+    match request.method.as_str() {
+        "GET" if path.starts_with("/id/") => get_id(db, &path["/id/".len()..]),
+        "GET" if path.starts_with("/fingerprint/") => get_fingerprint(db, &path["/fingerprint/".len()..]),
+        "POST" if path == "/publish" => publish(db, peer, &request.body),
+        "GET" | "POST" => Err((404, format!("Not found: {}", request.path))),
+        method => Err((405, format!("Method not allowed: {}", method)))
+    }
+}
+
+fn get_id(db: &DirStore, id: &str) -> Reply {
+    let id = http::decode(id).map_err(bad_request)?;
+
+    match db.get(&id).map_err(|err| bad_request(err.into()))? {
+        Some(record) => {
+            let pk_packed: PublicKey = Envelope::from((id, record.pk));
+            cbor::to_vec(&pk_packed).map_err(|err| internal(err.into()))
+        },
+        None => Err((404, format!("ID does not exist: {}", id)))
+    }
+}
+
+fn get_fingerprint(db: &DirStore, prefix: &str) -> Reply {
+    let prefix = prefix.to_lowercase();
+
+    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err((400, format!("Invalid fingerprint: {}", prefix)));
+    }
+
+    let mut found = db.find(&prefix)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| internal(err.into()))?;
+
+    match found.len() {
+        0 => Err((404, format!("No PublicKey matches {}", prefix))),
+        1 => {
+            let (id, record) = found.remove(0);
+            let pk_packed: PublicKey = Envelope::from((id, record.pk));
+            cbor::to_vec(&pk_packed).map_err(|err| internal(err.into()))
+        },
+        n => Err((409, format!("{} PublicKeys match {}, use a longer fingerprint", n, prefix)))
+    }
+}
+
+fn publish(db: &DirStore, peer: &str, body: &[u8]) -> Reply {
+    let (pk_bytes, sig): (ByteBuf, Message) = cbor::from_slice(body)
+        .map_err(|err| bad_request(err.into()))?;
+    let (signer_id, signer_pk) = verify(sig, PUBLISH_AAD, &pk_bytes)
+        .map_err(bad_request)?;
+    let pk_packed: PublicKey = cbor::from_slice(&pk_bytes)
+        .map_err(|err| bad_request(err.into()))?;
+    let (id, pk) = unwrap!(pk_packed);
+
+    if id != signer_id || !contact::same(&pk, &signer_pk) {
+        return Err((400, format!("PublicKey of {} is not signed by itself", id)));
+    }
+
+    let new = match db.get(&id).map_err(|err| bad_request(err.into()))? {
+        Some(ref record) if contact::same(&record.pk, &pk) => false,
+        Some(_) => return Err((409, format!("{} is already bound to another PublicKey", id))),
+        None => {
+            let record = Record::new(pk, Source::KeyServer(peer.to_string()));
+            db.set(&id, &record).map_err(|err| internal(err.into()))?;
+            true
+        }
+    };
+
+    cbor::to_vec(&new).map_err(|err| internal(err.into()))
+}
@@ -0,0 +1,163 @@
+use std::cmp;
+use failure::Fallible;
+use serde_cbor as cbor;
+use crate::core::key;
+use crate::core::format::{ PublicKey, Envelope };
+use crate::core::contact::{ ContactStore, Record, Source, Autocrypt };
+use super::message::{ Message, parse_date };
+
+
+pub const HEADER: &str = "Autocrypt";
+
+/// `Autocrypt: addr=...; [prefer-encrypt=mutual;] keydata=...`
+///
+/// `keydata` is the base64 of the PublicKey envelope.
+pub struct Header {
+    pub addr: String,
+    pub prefer_encrypt: bool,
+    pub pk: key::PublicKey
+}
+
+/// What the message changed
+pub enum Update {
+    /// Unknown sender without Autocrypt header
+    Ignored,
+    New,
+
+    /// Header is not newer, only `last_seen` is recorded
+    Seen,
+
+    /// Newer header with the pinned key, `prefer_encrypt` is taken from it
+    Updated,
+    Changed
+}
+
+impl Header {
+    pub fn new(id: &str, pk: &key::PublicKey, prefer_encrypt: bool) -> Header {
+        Header { addr: id.to_string(), prefer_encrypt, pk: pk.clone() }
+    }
+
+    /// Invalid header, or the key which does not belong to `addr`, is `None`.
+    pub fn parse(value: &str) -> Option<Header> {
+        let mut addr = None;
+        let mut prefer_encrypt = false;
+        let mut keydata = None;
+
+        for attr in value.split(';').map(str::trim).filter(|attr| !attr.is_empty()) {
+            let pos = attr.find('=')?;
+            let value = attr[pos + 1..].trim();
+
+            match attr[..pos].trim() {
+                "addr" => addr = Some(value),
+                "prefer-encrypt" => prefer_encrypt = value == "mutual",
+                "keydata" => keydata = Some(value.split_whitespace().collect::<String>()),
+
+                // non-critical attribute
+                name if name.starts_with('_') => (),
+                _ => return None
+            }
+        }
+
+        let addr = addr?;
+        let keydata = base64::decode(&keydata?).ok()?;
+        let pk_packed: PublicKey = cbor::from_slice(&keydata).ok()?;
+        let (id, pk) = unwrap!(pk_packed);
+
+        if !id.eq_ignore_ascii_case(addr) {
+            return None;
+        }
+
+        Some(Header { addr: id, prefer_encrypt, pk })
+    }
+
+    /// Header value for display, keydata is folded as continuation lines.
+    pub fn to_folded(&self) -> Fallible<String> {
+        let pk_packed: PublicKey = Envelope::from((self.addr.clone(), self.pk.clone()));
+        let keydata = base64::encode(&cbor::to_vec(&pk_packed)?);
+
+        let mut output = format!("addr={};", self.addr);
+        if self.prefer_encrypt {
+            output.push_str(" prefer-encrypt=mutual;");
+        }
+        output.push_str(" keydata=");
+
+        for chunk in keydata.as_bytes().chunks(72) {
+            output.push_str("\n ");
+            output.push_str(std::str::from_utf8(chunk)?);
+        }
+
+        Ok(output)
+    }
+}
+
+/// Update the sender with Autocrypt peer state rules,
+/// the effective date is the message date, but not later than `now`.
+///
+/// Changed keys are kept as pending, like other sources.
+pub fn update(db: &dyn ContactStore, message: &Message, now: u64) -> Fallible<(String, Update)> {
+    let from = message.sender()?;
+    let date = message.get("Date")
+        .and_then(parse_date)
+        .map(|date| cmp::min(date, now))
+        .unwrap_or(now);
+
+    // more than one valid header means none
+    let mut headers = message.get_all(HEADER)
+        .filter_map(Header::parse)
+        .filter(|header| header.addr.eq_ignore_ascii_case(&from))
+        .collect::<Vec<_>>();
+    let header = if headers.len() == 1 { headers.pop() } else { None };
+
+    let id = header.as_ref()
+        .map(|header| header.addr.clone())
+        .unwrap_or(from);
+
+    let update = match (db.get(&id)?, header) {
+        (None, None) => Update::Ignored,
+        (None, Some(header)) => {
+            let mut record = Record::new(header.pk, Source::Autocrypt);
+            record.autocrypt = Some(Autocrypt {
+                last_seen: date,
+                timestamp: date,
+                prefer_encrypt: header.prefer_encrypt
+            });
+            db.set(&id, &record)?;
+            Update::New
+        },
+        (Some(mut record), None) => {
+            {
+                let state = record.autocrypt.get_or_insert_with(Default::default);
+                state.last_seen = cmp::max(state.last_seen, date);
+            }
+            db.set(&id, &record)?;
+            Update::Seen
+        },
+        (Some(mut record), Some(header)) => {
+            let newer = {
+                let state = record.autocrypt.get_or_insert_with(Default::default);
+                state.last_seen = cmp::max(state.last_seen, date);
+
+                if date > state.timestamp {
+                    state.timestamp = date;
+                    state.prefer_encrypt = header.prefer_encrypt;
+                    true
+                } else {
+                    false
+                }
+            };
+
+            let update = if !newer {
+                Update::Seen
+            } else if record.observe(&header.pk) {
+                Update::Updated
+            } else {
+                Update::Changed
+            };
+
+            db.set(&id, &record)?;
+            update
+        }
+    };
+
+    Ok((id, update))
+}
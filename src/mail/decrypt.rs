@@ -0,0 +1,231 @@
+use std::path::PathBuf;
+use failure::{ Fallible, err_msg };
+use serde_cbor as cbor;
+use serde_bytes::ByteBuf;
+use directories::ProjectDirs;
+use crate::core::key;
+use crate::core::alg::Protocol;
+use crate::core::format::{ self, Meta };
+use crate::core::contact::ContactStore;
+use crate::opts::{ MailDecrypt, MailDecryptFolder };
+use crate::agent::Session;
+use crate::common::Stdio;
+use crate::contact::{ open_store_with, open_store_as, check_protected };
+use crate::contact::lock::Access;
+use crate::contact::recvfrom::{ pinned_sender, observe_sender, select };
+use super::message::Message;
+use super::folder::{ self, Maildir };
+use super::{ mime, read_input, write_output };
+
+
+/// Result of a message decrypted with unlocked Profile
+pub enum Outcome {
+    Decrypted(String, Vec<u8>),
+    Plain,
+    Unknown(String)
+}
+
+/// Encrypted payload of ENE mail
+pub struct Encrypted {
+    pub sender: (String, key::PublicKey),
+    pub recipient: Option<(String, key::ShortPublicKey)>,
+    protocol: Protocol,
+    ciphertext: ByteBuf,
+    aad: Vec<u8>
+}
+
+impl Encrypted {
+    /// Take the encrypted payload, the From address must be the sender.
+    ///
+    /// The bound outer headers are the associated data, see `mime::associated_data`.
+    pub fn parse(message: &Message) -> Fallible<Encrypted> {
+        let message_packed: format::Message = cbor::from_slice(&mime::unwrap(message)?)?;
+        let (meta, protocol, ciphertext) = unwrap!(message_packed);
+        let Meta { s: sender, r: recipient } = meta;
+
+        if let Protocol::Sonly(..) = protocol {
+            return Err(err_msg("Mail must be encrypted, signature only is not supported"));
+        }
+
+        let from = message.sender()?;
+        if !from.eq_ignore_ascii_case(&sender.0) {
+            return Err(err_msg(format!("From {} is not the sender {}", from, sender.0)));
+        }
+
+        let aad = mime::associated_data(message);
+
+        Ok(Encrypted { sender, recipient, protocol, ciphertext, aad })
+    }
+
+    /// Decrypt the original message, its bound headers must match the outer ones.
+    pub fn decrypt(&self, session: &Session, sender_pk: &key::PublicKey) -> Fallible<Vec<u8>> {
+        let original = session.recvfrom(
+            (&self.sender.0, sender_pk),
+            &self.protocol, &self.aad, &self.ciphertext,
+            None
+        ).map_err(|err| err_msg(format!(
+            "{}, the message or its {} headers may be modified",
+            err, mime::BOUND_HEADERS.join("/")
+        )))?;
+
+        if mime::associated_data(&Message::parse(&original)?) != self.aad {
+            return Err(err_msg(format!(
+                "Outer {} headers do not match the protected headers",
+                mime::BOUND_HEADERS.join("/")
+            )));
+        }
+
+        Ok(original)
+    }
+}
+
+impl MailDecrypt {
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        let message = Message::parse(&read_input(self.input.as_ref().map(PathBuf::as_path))?)?;
+        let encrypted = Encrypted::parse(&message)?;
+
+        // take sender, only known contacts, nothing is stored until the message is verified
+        let (sender_id, sender_pk) = &encrypted.sender;
+        let (db, unlocked) = open_store_with(dir, Access::Write)?;
+        let sender_pk = pinned_sender(&*db, sender_id, sender_pk)?;
+
+        // take receiver and decrypt message
+        let decrypt = |session: &Session| encrypted.decrypt(session, &sender_pk);
+        let (session, original) = if self.profile.is_some() || self.as_id.is_some() {
+            let session = Session::open(
+                dir,
+                self.profile.as_ref().map(PathBuf::as_path),
+                self.as_id.as_ref().map(String::as_str)
+            )?;
+            let original = decrypt(&session)?;
+            (session, original)
+        } else {
+            select(dir, stdio, encrypted.recipient.as_ref(), unlocked, decrypt)?
+        };
+
+        check_protected(dir, &session)?;
+        observe_sender(&*db, stdio, sender_id, &sender_pk)?;
+
+        write_output(stdio, self.output.as_ref().map(PathBuf::as_path), &original)?;
+
+        if !quiet && self.output.is_some() {
+            stdio.info(format_args!("Message from {} to {} is decrypted", sender_id, session.get_id()))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MailDecryptFolder {
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        let entries = folder::read(&self.input)?;
+
+        // unlock once, the Profile which unlocked contact store is reused
+        let (db, session) = open_store_as(
+            dir, Access::Write,
+            self.profile.as_ref().map(PathBuf::as_path),
+            self.as_id.as_ref().map(String::as_str)
+        )?;
+        let output = Maildir::create(&self.output)?;
+
+        let mut summary = Summary::default();
+        let mut total = 0;
+
+        for entry in entries {
+            total += 1;
+
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    stdio.warn(format_args!("{}: {}", self.input.display(), err))?;
+                    summary.failed += 1;
+                    continue
+                }
+            };
+
+            match decrypt_with(&*db, stdio, &session, &entry.data) {
+                Ok(Outcome::Decrypted(sender_id, original)) => {
+                    output.deliver(entry.subdir, &entry.name, &original)?;
+                    summary.decrypted += 1;
+
+                    if !quiet {
+                        stdio.info(format_args!("{}: decrypted from {}", entry.name, sender_id))?;
+                    }
+                },
+                Ok(Outcome::Plain) => summary.plain += 1,
+                Ok(Outcome::Unknown(sender_id)) => {
+                    stdio.warn(format_args!("{}: unknown sender {}", entry.name, sender_id))?;
+                    summary.unknown(sender_id);
+                },
+                Err(err) => {
+                    stdio.warn(format_args!("{}: {}", entry.name, err))?;
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        summary.finish(quiet, stdio, total)
+    }
+}
+
+/// Counts of a batch of messages
+#[derive(Default)]
+pub struct Summary {
+    pub decrypted: usize,
+    pub failed: usize,
+    pub plain: usize,
+    unknown: Vec<String>
+}
+
+impl Summary {
+    pub fn unknown(&mut self, sender_id: String) {
+        if !self.unknown.contains(&sender_id) {
+            self.unknown.push(sender_id);
+        }
+    }
+
+    /// Print the summary, fail if any message failed.
+    pub fn finish(&self, quiet: bool, stdio: &mut Stdio, total: usize) -> Fallible<()> {
+        if !quiet {
+            stdio.info(format_args!(
+                "{} decrypted, {} failed, {} not encrypted, {} unknown senders",
+                self.decrypted, self.failed, self.plain, self.unknown.len()
+            ))?;
+
+            for sender_id in &self.unknown {
+                stdio.info(format_args!("unknown sender: {}", sender_id))?;
+            }
+        }
+
+        if self.failed > 0 {
+            return Err(err_msg(format!("{} of {} messages failed", self.failed, total)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Decrypt message with unlocked Profile, messages of unknown senders are not decrypted.
+pub fn decrypt_with(db: &dyn ContactStore, stdio: &mut Stdio, session: &Session, input: &[u8])
+    -> Fallible<Outcome>
+{
+    let message = Message::parse(input)?;
+
+    if !mime::is_encrypted(&message) {
+        return Ok(Outcome::Plain);
+    }
+
+    let encrypted = Encrypted::parse(&message)?;
+    let (sender_id, sender_pk) = &encrypted.sender;
+
+    if db.get(sender_id)?.is_none() {
+        return Ok(Outcome::Unknown(sender_id.clone()));
+    }
+
+    // nothing is stored until the message is verified
+    let sender_pk = pinned_sender(db, sender_id, sender_pk)?;
+    let original = encrypted.decrypt(session, &sender_pk)?;
+    observe_sender(db, stdio, sender_id, &sender_pk)?;
+
+    Ok(Outcome::Decrypted(sender_id.clone(), original))
+}
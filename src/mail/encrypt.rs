@@ -0,0 +1,139 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use rand::{ Rng, OsRng };
+use failure::{ Fallible, err_msg };
+use serde_cbor as cbor;
+use directories::ProjectDirs;
+use crate::core::contact::{ self, ContactStore };
+use crate::core::alg::Protocol;
+use crate::opts::MailEncrypt;
+use crate::agent::Session;
+use crate::common::Stdio;
+use crate::contact::open_store_as;
+use crate::contact::lock::Access;
+use crate::contact::sendto::receiver;
+use super::message::{ Message, addresses, format_date };
+use super::{ mime, read_input, write_output };
+
+
+impl MailEncrypt {
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        let mut message = Message::parse(&read_input(self.input.as_ref().map(PathBuf::as_path))?)?;
+
+        // take sender
+        let (db, session) = open_store_as(
+            dir, Access::Read,
+            self.profile.as_ref().map(PathBuf::as_path),
+            self.as_id.as_ref().map(String::as_str)
+        )?;
+
+        // take receiver
+        let receiver_id = match self.recipient {
+            Some(id) => id,
+            None => {
+                let mut recipients = recipients(&message);
+
+                if recipients.len() == 1 {
+                    recipients.remove(0)
+                } else {
+                    return Err(err_msg(format!(
+                        "Message has {} recipients, choose one with --to",
+                        recipients.len()
+                    )));
+                }
+            }
+        };
+
+        prepare(&session, &mut message)?;
+        let outer = encrypt(
+            &session, stdio, &*db,
+            &message, &receiver_id, self.protocol.as_ref()
+        )?;
+
+        write_output(stdio, self.output.as_ref().map(PathBuf::as_path), &outer.to_vec())?;
+
+        if !quiet && self.output.is_some() {
+            stdio.info(format_args!("Message is encrypted to {}", receiver_id))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Addresses of To, Cc and Bcc.
+pub fn recipients(message: &Message) -> Vec<String> {
+    let mut recipients: Vec<String> = Vec::new();
+
+    for addr in ["To", "Cc", "Bcc"].iter()
+        .flat_map(|name| message.get_all(name))
+        .flat_map(addresses)
+    {
+        if !recipients.iter().any(|r| r.eq_ignore_ascii_case(&addr)) {
+            recipients.push(addr);
+        }
+    }
+
+    recipients
+}
+
+/// Check the sender, and add the bound headers which relays would add later.
+///
+/// Bcc is never encrypted, every recipient gets its own copy.
+pub fn prepare(session: &Session, message: &mut Message) -> Fallible<()> {
+    let from = message.sender()?;
+
+    if !from.eq_ignore_ascii_case(session.get_id()) {
+        return Err(err_msg(format!("From {} is not the Profile {}", from, session.get_id())));
+    }
+
+    if message.get("Date").is_none() {
+        message.set("Date", format_date(contact::now()));
+    }
+    if message.get("Message-ID").is_none() {
+        message.set("Message-ID", message_id(&from)?);
+    }
+
+    message.remove("Bcc");
+
+    Ok(())
+}
+
+/// Encrypt prepared message to a contact, output `multipart/encrypted` message.
+pub fn encrypt(
+    session: &Session,
+    stdio: &mut Stdio,
+    db: &dyn ContactStore,
+    message: &Message,
+    receiver_id: &str,
+    protocol: Option<&Protocol>
+) -> Fallible<Message> {
+    let (receiver_pk, prefer_protocol) = receiver(stdio, db, receiver_id)?;
+    let protocol = match protocol.cloned().or(prefer_protocol) {
+        Some(protocol) => protocol,
+        None => Protocol::from_str(Protocol::default_name())?
+    };
+
+    if let Protocol::Sonly(..) = protocol {
+        return Err(err_msg("Mail must be encrypted, signature only is not supported"));
+    }
+
+    let message_packed = session.sendto(
+        (receiver_id, &receiver_pk),
+        &protocol, &mime::associated_data(message), &message.to_vec()
+    )?;
+
+    mime::wrap(message, &cbor::to_vec(&message_packed)?)
+}
+
+/// Random Message-ID in the domain of sender.
+fn message_id(from: &str) -> Fallible<String> {
+    let mut nonce = [0; 16];
+    OsRng::new()?.fill(&mut nonce);
+
+    let domain = from.rsplit('@').next().unwrap_or(from);
+    Ok(format!(
+        "<{}@{}>",
+        nonce.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        domain
+    ))
+}
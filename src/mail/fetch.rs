@@ -0,0 +1,103 @@
+use std::path::PathBuf;
+use failure::{ Fallible, err_msg };
+use directories::ProjectDirs;
+use crate::opts::MailFetch;
+use crate::common::{ Stdio, askpass_with };
+use crate::contact::open_store_as;
+use crate::contact::lock::Access;
+use super::decrypt::{ Outcome, Summary, decrypt_with };
+use super::folder::Maildir;
+use super::imap::Imap;
+
+
+/// IMAP keyword of messages stored in Maildir
+pub const PROCESSED: &str = "$EneProcessed";
+
+impl MailFetch {
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        // unlock once, the Profile which unlocked contact store is reused
+        let (db, session) = open_store_as(
+            dir, Access::Write,
+            self.profile.as_ref().map(PathBuf::as_path),
+            self.as_id.as_ref().map(String::as_str)
+        )?;
+        let output = Maildir::create(&self.output)?;
+
+        let mut imap = Imap::connect(&self.imap)?;
+        askpass_with(&format!("IMAP password of {}:", self.user), |pass| {
+            imap.login(&self.user, pass.trim_end_matches(|c| c == '\r' || c == '\n'))
+        })?;
+        let mailbox = imap.select(&self.mailbox)?;
+
+        if !mailbox.can_keep(PROCESSED) {
+            return Err(err_msg(format!(
+                "IMAP mailbox {} can not keep keyword {}, messages would be fetched again",
+                self.mailbox, PROCESSED
+            )));
+        }
+
+        let uids = imap.search_without(PROCESSED)?;
+
+        let mut summary = Summary::default();
+
+        for &uid in &uids {
+            let name = self.name(mailbox.uid_validity, uid);
+
+            // stored, but the keyword was not added last time
+            if output.contains(&name)? {
+                if !quiet {
+                    stdio.info(format_args!("{}: already stored", uid))?;
+                }
+
+                imap.add_keyword(uid, PROCESSED)?;
+                continue
+            }
+
+            let data = imap.fetch(uid)?;
+
+            // unknown senders and failures are kept unprocessed, to retry later
+            let original = match decrypt_with(&*db, stdio, &session, &data) {
+                Ok(Outcome::Decrypted(sender_id, original)) => {
+                    summary.decrypted += 1;
+
+                    if !quiet {
+                        stdio.info(format_args!("{}: decrypted from {}", uid, sender_id))?;
+                    }
+
+                    original
+                },
+                Ok(Outcome::Plain) => {
+                    summary.plain += 1;
+                    data
+                },
+                Ok(Outcome::Unknown(sender_id)) => {
+                    stdio.warn(format_args!("{}: unknown sender {}", uid, sender_id))?;
+                    summary.unknown(sender_id);
+                    continue
+                },
+                Err(err) => {
+                    stdio.warn(format_args!("{}: {}", uid, err))?;
+                    summary.failed += 1;
+                    continue
+                }
+            };
+
+            output.deliver("new", &name, &original)?;
+            imap.add_keyword(uid, PROCESSED)?;
+        }
+
+        imap.logout()?;
+
+        summary.finish(quiet, stdio, uids.len())
+    }
+
+    /// Maildir name of message, the same on every run,
+    /// so a message is not stored twice.
+    fn name(&self, uid_validity: u32, uid: u32) -> String {
+        let escape = |s: &str| s.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect::<String>();
+
+        format!("{}_{}.{}.{}.ene", uid_validity, uid, escape(&self.user), escape(&self.mailbox))
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs::{ self, File };
+use std::io::{ self, BufRead, BufReader };
+use std::path::{ Path, PathBuf };
+use failure::{ Fallible, err_msg };
+use crate::core::contact;
+
+
+/// Message of a folder
+pub struct Entry {
+    /// `new` or `cur` of Maildir, messages of mbox are `new`
+    pub subdir: &'static str,
+    pub name: String,
+    pub data: Vec<u8>
+}
+
+/// Maildir, messages are written to `tmp` first, then moved to `new` or `cur`.
+pub struct Maildir(PathBuf);
+
+impl Maildir {
+    pub fn is_maildir(path: &Path) -> bool {
+        path.join("new").is_dir() && path.join("cur").is_dir()
+    }
+
+    pub fn create(path: &Path) -> Fallible<Maildir> {
+        for subdir in &["tmp", "new", "cur"] {
+            fs::create_dir_all(path.join(subdir))?;
+        }
+
+        Ok(Maildir(path.to_path_buf()))
+    }
+
+    pub fn deliver(&self, subdir: &str, name: &str, data: &[u8]) -> Fallible<PathBuf> {
+        let tmp = self.0.join("tmp").join(name);
+        let path = self.0.join(subdir).join(name);
+
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(path)
+    }
+
+    /// Whether message `name` is in `new` or `cur`, readers may have moved it to `cur` with flags.
+    pub fn contains(&self, name: &str) -> Fallible<bool> {
+        if self.0.join("new").join(name).is_file() {
+            return Ok(true);
+        }
+
+        let flagged = format!("{}:", name);
+
+        for entry in fs::read_dir(self.0.join("cur"))? {
+            if let Some(entry_name) = entry?.file_name().to_str() {
+                if entry_name == name || entry_name.starts_with(&flagged) {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Read every message of Maildir or mbox, one at a time.
+pub fn read(path: &Path) -> Fallible<Box<dyn Iterator<Item = Fallible<Entry>>>> {
+    if Maildir::is_maildir(path) {
+        let mut names = Vec::new();
+
+        for &subdir in &["new", "cur"] {
+            let mut subdir_names = fs::read_dir(path.join(subdir))?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_file())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| !name.starts_with('.'))
+                .collect::<Vec<_>>();
+            subdir_names.sort();
+
+            names.extend(subdir_names.into_iter().map(|name| (subdir, name)));
+        }
+
+        let path = path.to_path_buf();
+        Ok(Box::new(names.into_iter().map(move |(subdir, name)| -> Fallible<Entry> {
+            let data = fs::read(path.join(subdir).join(&name))?;
+            Ok(Entry { subdir, name, data })
+        })))
+    } else if path.is_file() {
+        let now = contact::now();
+        let mbox = Mbox::new(BufReader::new(File::open(path)?));
+
+        Ok(Box::new(mbox.enumerate().map(move |(i, data)| -> Fallible<Entry> {
+            Ok(Entry { subdir: "new", name: unique_name(now, i), data: data? })
+        })))
+    } else {
+        Err(err_msg(format!("{} is neither Maildir nor mbox", path.display())))
+    }
+}
+
+/// Maildir file name, unique with the index in one run.
+pub fn unique_name(now: u64, index: usize) -> String {
+    format!("{}.{}_{}.ene", now, std::process::id(), index)
+}
+
+/// Messages of mbox, read one at a time, `>From ` quoting of mboxrd is removed.
+pub struct Mbox<R> {
+    input: R,
+
+    /// Whether the first `From ` line has been read
+    started: bool,
+
+    /// Whether the last line is blank
+    blank: bool,
+    done: bool
+}
+
+impl<R: BufRead> Mbox<R> {
+    pub fn new(input: R) -> Mbox<R> {
+        Mbox { input, started: false, blank: true, done: false }
+    }
+}
+
+impl<R: BufRead> Iterator for Mbox<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut current = if self.started { Some(Vec::new()) } else { None };
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+
+            match self.input.read_until(b'\n', &mut line) {
+                Ok(0) => {
+                    self.done = true;
+                    return current.map(|message| Ok(trim_separator(message)));
+                },
+                Ok(_) => (),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+
+            let blank = self.blank;
+            self.blank = line == b"\n" || line == b"\r\n";
+
+            if blank && line.starts_with(b"From ") {
+                self.started = true;
+
+                match current.take() {
+                    Some(message) => return Some(Ok(trim_separator(message))),
+                    None => current = Some(Vec::new())
+                }
+            } else if let Some(message) = &mut current {
+                let quoted = line.iter().take_while(|&&b| b == b'>').count();
+                if quoted > 0 && line[quoted..].starts_with(b"From ") {
+                    message.extend_from_slice(&line[1..]);
+                } else {
+                    message.extend_from_slice(&line);
+                }
+            }
+        }
+    }
+}
+
+/// Drop the blank line before the next `From ` line.
+fn trim_separator(mut message: Vec<u8>) -> Vec<u8> {
+    if message.ends_with(b"\r\n\r\n") {
+        message.truncate(message.len() - 2);
+    } else if message.ends_with(b"\n\n") {
+        message.truncate(message.len() - 1);
+    }
+
+    message
+}
+
+
+#[test]
+fn test_mbox() {
+    let input = b"From alice@core.ene Thu Jan  1 00:00:00 1970\n\
+        From: alice@core.ene\n\
+        \n\
+        >From the start\n\
+        >>From quoted\n\
+        \n\
+        From bob@core.ene Thu Jan  1 00:00:00 1970\n\
+        From: bob@core.ene\n\
+        \n\
+        Hello\n";
+
+    let messages = Mbox::new(&input[..]).collect::<io::Result<Vec<_>>>().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0], &b"From: alice@core.ene\n\nFrom the start\n>From quoted\n"[..]);
+    assert_eq!(messages[1], &b"From: bob@core.ene\n\nHello\n"[..]);
+}
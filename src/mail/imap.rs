@@ -0,0 +1,249 @@
+use std::time::Duration;
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::io::{ Read, Write, BufRead, BufReader };
+use failure::{ Fallible, err_msg };
+
+
+/// Largest message accepted
+const MAX_LITERAL: usize = 1 << 26;
+
+const TIMEOUT_SECS: u64 = 60;
+
+/// Untagged response, literals are taken out of the text.
+pub struct Response {
+    pub text: String,
+    pub literals: Vec<Vec<u8>>
+}
+
+/// Selected mailbox
+pub struct Mailbox {
+    pub uid_validity: u32,
+
+    /// `None` if the server did not send PERMANENTFLAGS, all flags are permanent then
+    permanent_flags: Option<Vec<String>>
+}
+
+/// IMAP4rev1 client of the few commands to archive a mailbox,
+/// plain text, so only loopback servers are accepted, remote ones must be tunneled.
+pub struct Imap {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+    tag: u32
+}
+
+impl Imap {
+    pub fn connect(server: &str) -> Fallible<Imap> {
+        let addrs = server.to_socket_addrs()
+            .map_err(|err| err_msg(format!("Resolve IMAP server {} failed: {}", server, err)))?
+            .collect::<Vec<_>>();
+
+        // LOGIN sends the password as is
+        if addrs.is_empty() || addrs.iter().any(|addr| !addr.ip().is_loopback()) {
+            return Err(err_msg(format!(
+                "IMAP server {} is not loopback, the password would be sent in plain text, use a local TLS tunnel",
+                server
+            )));
+        }
+
+        let stream = TcpStream::connect(&addrs[..])
+            .map_err(|err| err_msg(format!("Connect IMAP server {} failed: {}", server, err)))?;
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+
+        let mut imap = Imap { reader: BufReader::new(stream.try_clone()?), stream, tag: 0 };
+        let greeting = imap.read_response()?;
+
+        if greeting.text.starts_with("* OK") {
+            Ok(imap)
+        } else {
+            Err(err_msg(format!("IMAP server replied: {}", greeting.text)))
+        }
+    }
+
+    pub fn login(&mut self, user: &str, pass: &str) -> Fallible<()> {
+        self.command(&format!("LOGIN {} {}", quote(user)?, quote(pass)?))?;
+        Ok(())
+    }
+
+    pub fn select(&mut self, mailbox: &str) -> Fallible<Mailbox> {
+        let mut uid_validity = None;
+        let mut permanent_flags = None;
+
+        for response in self.command(&format!("SELECT {}", quote(mailbox)?))? {
+            if let Some(value) = code(&response.text, "UIDVALIDITY") {
+                uid_validity = Some(value.parse()?);
+            } else if let Some(value) = code(&response.text, "PERMANENTFLAGS") {
+                permanent_flags = Some(value
+                    .trim_matches(|c| c == '(' || c == ')')
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect());
+            }
+        }
+
+        let uid_validity = uid_validity
+            .ok_or_else(|| err_msg(format!("IMAP server did not send UIDVALIDITY of {}", mailbox)))?;
+
+        Ok(Mailbox { uid_validity, permanent_flags })
+    }
+
+    /// UIDs of messages which are not flagged with `keyword`.
+    pub fn search_without(&mut self, keyword: &str) -> Fallible<Vec<u32>> {
+        let mut uids = Vec::new();
+
+        for response in self.command(&format!("UID SEARCH NOT KEYWORD {}", keyword))? {
+            if response.text.starts_with("* SEARCH") {
+                for uid in response.text["* SEARCH".len()..].split_whitespace() {
+                    uids.push(uid.parse()?);
+                }
+            }
+        }
+
+        Ok(uids)
+    }
+
+    /// Whole message, without setting `\Seen`.
+    pub fn fetch(&mut self, uid: u32) -> Fallible<Vec<u8>> {
+        self.command(&format!("UID FETCH {} BODY.PEEK[]", uid))?
+            .into_iter()
+            .filter(|response| response.text.contains("FETCH"))
+            .flat_map(|response| response.literals)
+            .next()
+            .ok_or_else(|| err_msg(format!("IMAP server returned no message of UID {}", uid)))
+    }
+
+    pub fn add_keyword(&mut self, uid: u32, keyword: &str) -> Fallible<()> {
+        self.command(&format!("UID STORE {} +FLAGS.SILENT ({})", uid, keyword))?;
+        Ok(())
+    }
+
+    pub fn logout(mut self) -> Fallible<()> {
+        self.command("LOGOUT")?;
+        Ok(())
+    }
+
+    /// Send a command, take untagged responses until the tagged status.
+    fn command(&mut self, command: &str) -> Fallible<Vec<Response>> {
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+
+        self.stream.write_all(format!("{} {}\r\n", tag, command).as_bytes())?;
+
+        let mut responses = Vec::new();
+
+        loop {
+            let response = self.read_response()?;
+
+            if response.text.starts_with(&tag) && response.text[tag.len()..].starts_with(' ') {
+                let status = &response.text[tag.len() + 1..];
+
+                return if status.starts_with("OK") {
+                    Ok(responses)
+                } else {
+                    Err(err_msg(format!("IMAP server replied: {}", status)))
+                };
+            }
+
+            responses.push(response);
+        }
+    }
+
+    /// Read a response, a line ends with `{n}` is followed by a literal of `n` bytes.
+    fn read_response(&mut self) -> Fallible<Response> {
+        let mut text = String::new();
+        let mut literals = Vec::new();
+
+        loop {
+            let mut line = Vec::new();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                return Err(err_msg("IMAP server closed the connection"));
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+            text.push_str(line);
+
+            let size = if line.ends_with('}') {
+                line.rfind('{').and_then(|pos| line[pos + 1..line.len() - 1].parse::<usize>().ok())
+            } else {
+                None
+            };
+
+            match size {
+                Some(size) if size > MAX_LITERAL =>
+                    return Err(err_msg(format!("IMAP literal is too large: {}", size))),
+                Some(size) => {
+                    let mut literal = vec![0; size];
+                    self.reader.read_exact(&mut literal)?;
+                    literals.push(literal);
+                },
+                None => break
+            }
+        }
+
+        Ok(Response { text, literals })
+    }
+}
+
+impl Mailbox {
+    /// Whether `keyword` can be stored permanently, either listed or `\*` is allowed.
+    pub fn can_keep(&self, keyword: &str) -> bool {
+        match &self.permanent_flags {
+            Some(flags) => flags.iter()
+                .any(|flag| flag == "\\*" || flag.eq_ignore_ascii_case(keyword)),
+            None => true
+        }
+    }
+}
+
+/// Value of response code `[name value]` in an untagged `OK` response.
+fn code<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let text = text.trim_start_matches("* OK").trim_start();
+
+    if text.starts_with('[') && text[1..].starts_with(name) && text[1 + name.len()..].starts_with(' ') {
+        let value = &text[name.len() + 2..];
+        value.find(']').map(|end| &value[..end])
+    } else {
+        None
+    }
+}
+
+/// IMAP quoted string, CR, LF and NUL can not be quoted.
+pub fn quote(s: &str) -> Fallible<String> {
+    if s.contains(|c| c == '\r' || c == '\n' || c == '\0') {
+        return Err(err_msg(format!("IMAP string can not contain CR, LF or NUL: {:?}", s)));
+    }
+
+    let mut output = String::with_capacity(s.len() + 2);
+    output.push('"');
+
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+
+    output.push('"');
+    Ok(output)
+}
+
+
+#[test]
+fn test_quote() {
+    assert_eq!(quote("INBOX").unwrap(), "\"INBOX\"");
+    assert_eq!(quote("pa\"ss\\").unwrap(), "\"pa\\\"ss\\\\\"");
+    assert!(quote("pass\r\na2 DELETE INBOX").is_err());
+    assert!(quote("pass\n").is_err());
+}
+
+#[test]
+fn test_code() {
+    assert_eq!(code("* OK [UIDVALIDITY 3857529045] UIDs valid", "UIDVALIDITY"), Some("3857529045"));
+    assert_eq!(
+        code("* OK [PERMANENTFLAGS (\\Seen \\*)] Limited", "PERMANENTFLAGS"),
+        Some("(\\Seen \\*)")
+    );
+    assert_eq!(code("* OK [UIDNEXT 4392] Predicted next UID", "UIDVALIDITY"), None);
+    assert_eq!(code("* 172 EXISTS", "UIDVALIDITY"), None);
+}
@@ -0,0 +1,275 @@
+use failure::{ Fallible, err_msg };
+use crate::common::{ civil_from_days, days_from_civil };
+
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun",
+    "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
+
+/// RFC 5322 message
+///
+/// Header fields are unfolded and kept in order, the body is kept as is.
+pub struct Message {
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Message {
+    pub fn parse(input: &[u8]) -> Fallible<Message> {
+        let (head, body) = split_head(input);
+        let head = String::from_utf8_lossy(head);
+        let mut headers: Vec<(String, String)> = Vec::new();
+
+        for line in head.lines() {
+            let line = line.trim_end_matches('\r');
+
+            if line.starts_with(' ') || line.starts_with('\t') {
+                let (_, value) = headers.last_mut()
+                    .ok_or_else(|| err_msg("Malformed message, header starts with whitespace"))?;
+                value.push_str(line);
+            } else if let Some(pos) = line.find(':') {
+                let name = line[..pos].trim_end();
+
+                if name.is_empty() || name.contains(' ') {
+                    return Err(err_msg(format!("Malformed header: {}", line)));
+                }
+
+                headers.push((name.to_string(), line[pos + 1..].trim_start().to_string()));
+            } else if !line.is_empty() {
+                return Err(err_msg(format!("Malformed header: {}", line)));
+            }
+        }
+
+        if headers.is_empty() {
+            return Err(err_msg("Malformed message, no header"));
+        }
+
+        Ok(Message { headers, body: body.to_vec() })
+    }
+
+    /// First header field of name, case-insensitive.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers.iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The single From address.
+    pub fn sender(&self) -> Fallible<String> {
+        match self.get("From").map(addresses) {
+            Some(ref from) if from.len() == 1 => Ok(from[0].clone()),
+            _ => Err(err_msg("Message must have a single From address"))
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// Replace the first header field of name and remove the others, or append it.
+    pub fn set(&mut self, name: &str, value: String) {
+        let mut seen = false;
+        self.headers.retain(|(key, _)| if key.eq_ignore_ascii_case(name) {
+            let first = !seen;
+            seen = true;
+            first
+        } else {
+            true
+        });
+
+        match self.headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case(name)) {
+            Some((_, old)) => *old = value,
+            None => self.headers.push((name.to_string(), value))
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut output = Vec::with_capacity(self.body.len() + 1024);
+
+        for (name, value) in &self.headers {
+            output.extend_from_slice(name.as_bytes());
+            output.extend_from_slice(b": ");
+            output.extend_from_slice(value.as_bytes());
+            output.extend_from_slice(b"\r\n");
+        }
+
+        output.extend_from_slice(b"\r\n");
+        output.extend_from_slice(&self.body);
+        output
+    }
+}
+
+fn split_head(input: &[u8]) -> (&[u8], &[u8]) {
+    let mut i = 0;
+
+    while i < input.len() {
+        if input[i..].starts_with(b"\r\n\r\n") {
+            return (&input[..i + 2], &input[i + 4..]);
+        } else if input[i..].starts_with(b"\n\n") {
+            return (&input[..i + 1], &input[i + 2..]);
+        }
+
+        i += 1;
+    }
+
+    (input, &[])
+}
+
+/// Addresses of an address list, display names, comments and groups are dropped.
+pub fn addresses(value: &str) -> Vec<String> {
+    let mut output = Vec::new();
+    let mut current = String::new();
+    let mut angle: Option<String> = None;
+    let mut found: Option<String> = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut comment = 0usize;
+
+    let mut flush = |current: &mut String, found: &mut Option<String>| {
+        let addr = found.take().unwrap_or_else(|| current.trim().to_string());
+        if addr.contains('@') && !addr.contains(char::is_whitespace) {
+            output.push(addr);
+        }
+        current.clear();
+    };
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+            continue
+        }
+
+        match c {
+            '\\' if quoted || comment > 0 => escaped = true,
+            '"' if comment == 0 => quoted = !quoted,
+            _ if quoted => (),
+            '(' => comment += 1,
+            ')' if comment > 0 => comment -= 1,
+            _ if comment > 0 => (),
+            '<' => angle = Some(String::new()),
+            '>' => found = angle.take().map(|addr| addr.trim().to_string()),
+            ',' | ';' if angle.is_none() => flush(&mut current, &mut found),
+            ':' if angle.is_none() => current.clear(),
+            _ => match &mut angle {
+                Some(addr) => addr.push(c),
+                None => current.push(c)
+            }
+        }
+    }
+    flush(&mut current, &mut found);
+
+    output
+}
+
+/// Parse RFC 5322 date time as unix timestamp.
+pub fn parse_date(value: &str) -> Option<u64> {
+    let value = match value.find(',') {
+        Some(pos) => &value[pos + 1..],
+        None => value
+    };
+    let mut parts = value.split_whitespace();
+
+    let d: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let m = MONTHS.iter().position(|name| name.eq_ignore_ascii_case(month))? as i64 + 1;
+    let y: i64 = match parts.next()?.parse().ok()? {
+        y @ 0..=49 => y + 2000,
+        y @ 50..=999 => y + 1900,
+        y => y
+    };
+
+    let mut time = parts.next()?.split(':');
+    let hh: i64 = time.next()?.parse().ok()?;
+    let mm: i64 = time.next()?.parse().ok()?;
+    let ss: i64 = time.next().map(str::parse).unwrap_or(Ok(0)).ok()?;
+
+    let zone = match parts.next() {
+        Some(zone) if zone.len() == 5 && (zone.starts_with('+') || zone.starts_with('-')) => {
+            let n: i64 = zone[1..].parse().ok()?;
+            let offset = (n / 100) * 3600 + (n % 100) * 60;
+            if zone.starts_with('-') { -offset } else { offset }
+        },
+        _ => 0
+    };
+
+    // bounded year keeps `days_from_civil` from overflow
+    if y < 1970 || y > 9999
+        || d < 1 || d > 31
+        || hh < 0 || hh > 23
+        || mm < 0 || mm > 59
+        || ss < 0 || ss > 60
+    {
+        return None;
+    }
+
+    let secs = days_from_civil(y, m, d) * 86400 + hh * 3600 + mm * 60 + ss - zone;
+
+    if secs < 0 {
+        None
+    } else {
+        Some(secs as u64)
+    }
+}
+
+/// Format unix timestamp as RFC 5322 date time in UTC.
+pub fn format_date(secs: u64) -> String {
+    let days = secs / 86400;
+    let (y, m, d) = civil_from_days(days as i64);
+
+    let secs = secs % 86400;
+    format!(
+        "{}, {} {} {:04} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize], d, MONTHS[m as usize - 1], y,
+        secs / 3600, secs % 3600 / 60, secs % 60
+    )
+}
+
+
+#[test]
+fn test_message() {
+    let input = b"From: Alice <alice@core.ene>\r\n\
+        To: bob@core.ene,\r\n \"Carol, C\" <carol@core.ene> (work)\r\n\
+        Subject: Hello\r\n\
+        \r\n\
+        Body\r\n";
+    let mut message = Message::parse(input).unwrap();
+
+    assert_eq!(message.get("from"), Some("Alice <alice@core.ene>"));
+    assert_eq!(
+        addresses(message.get("To").unwrap()),
+        vec!["bob@core.ene", "carol@core.ene"]
+    );
+    assert_eq!(message.body, b"Body\r\n");
+    assert_eq!(Message::parse(&message.to_vec()).unwrap().headers, message.headers);
+
+    message.set("Subject", "...".into());
+    message.remove("To");
+    assert_eq!(message.get("subject"), Some("..."));
+    assert_eq!(message.get("to"), None);
+
+    assert_eq!(addresses("undisclosed-recipients:;"), Vec::<String>::new());
+    assert_eq!(addresses("team: alice@core.ene, bob@core.ene;"), vec!["alice@core.ene", "bob@core.ene"]);
+}
+
+#[test]
+fn test_date() {
+    assert_eq!(parse_date("Thu, 1 Jan 1970 00:00:00 +0000"), Some(0));
+    assert_eq!(parse_date("Tue, 1 Jul 2003 10:52:37 +0200"), Some(1_057_049_557));
+    assert_eq!(parse_date("1 Jul 03 08:52 GMT"), Some(1_057_049_520));
+    assert_eq!(parse_date("garbage"), None);
+    assert_eq!(parse_date("1 Jan 9223372036854775807 00:00:00 +0000"), None);
+    assert_eq!(parse_date("1 Jan 1969 00:00:00 +0000"), None);
+    assert_eq!(parse_date("1 Jan 2000 -1:00:00 +0000"), None);
+    assert_eq!(parse_date("1 Jan 2000 00:00:00 +9999999999999999"), None);
+
+    assert_eq!(format_date(0), "Thu, 1 Jan 1970 00:00:00 +0000");
+    assert_eq!(format_date(1_057_049_557), "Tue, 1 Jul 2003 08:52:37 +0000");
+    assert_eq!(parse_date(&format_date(1_057_049_557)), Some(1_057_049_557));
+}
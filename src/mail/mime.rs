@@ -0,0 +1,238 @@
+use rand::{ Rng, OsRng };
+use failure::{ Fallible, err_msg };
+use super::message::{ Message, addresses, parse_date };
+
+
+/// Content type of ENE encrypted mail, like `application/pgp-encrypted` of RFC 3156
+pub const CONTENT_TYPE: &str = "application/ene-encrypted";
+
+/// Routing headers copied to the outer message
+pub const OUTER_HEADERS: [&str; 9] = [
+    "From", "Sender", "Reply-To", "To", "Cc",
+    "Date", "Message-ID", "In-Reply-To", "References"
+];
+
+/// Outer headers authenticated as associated data
+pub const BOUND_HEADERS: [&str; 4] = ["From", "To", "Date", "Message-ID"];
+
+/// Placeholder of the protected Subject
+pub const SUBJECT: &str = "...";
+
+/// Build `multipart/encrypted` message around the encrypted payload.
+///
+/// ```norun
+/// Content-Type: multipart/encrypted; protocol="application/ene-encrypted"; boundary=...
+///
+/// --boundary
+/// Content-Type: application/ene-encrypted
+///
+/// Version: 1
+///
+/// --boundary
+/// Content-Type: application/octet-stream; name="encrypted.ene"
+/// Content-Transfer-Encoding: base64
+///
+/// <base64 of Message envelope>
+/// --boundary--
+/// ```
+pub fn wrap(original: &Message, payload: &[u8]) -> Fallible<Message> {
+    let mut nonce = [0; 12];
+    OsRng::new()?.fill(&mut nonce);
+    let boundary = format!(
+        "ene-{}",
+        nonce.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+    );
+
+    let mut headers = original.headers.iter()
+        .filter(|(name, _)| OUTER_HEADERS.iter().any(|outer| outer.eq_ignore_ascii_case(name)))
+        .cloned()
+        .collect::<Vec<_>>();
+    headers.push(("Subject".into(), SUBJECT.into()));
+    headers.push(("MIME-Version".into(), "1.0".into()));
+    headers.push((
+        "Content-Type".into(),
+        format!("multipart/encrypted; protocol=\"{}\"; boundary=\"{}\"", CONTENT_TYPE, boundary)
+    ));
+
+    let mut body = format!(
+        "This is an ENE encrypted message.\r\n\
+        \r\n\
+        --{0}\r\n\
+        Content-Type: {1}\r\n\
+        Content-Description: ENE version identification\r\n\
+        \r\n\
+        Version: 1\r\n\
+        \r\n\
+        --{0}\r\n\
+        Content-Type: application/octet-stream; name=\"encrypted.ene\"\r\n\
+        Content-Disposition: inline; filename=\"encrypted.ene\"\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n",
+        boundary, CONTENT_TYPE
+    );
+
+    let encoded = base64::encode(payload);
+    for line in encoded.as_bytes().chunks(76) {
+        body.push_str(std::str::from_utf8(line)?);
+        body.push_str("\r\n");
+    }
+    body.push_str(&format!("--{}--\r\n", boundary));
+
+    Ok(Message { headers, body: body.into_bytes() })
+}
+
+/// Canonical form of the bound headers, which survives refolding by relays.
+///
+/// ```norun
+/// ENE-MAIL-V1
+/// from:<lowercase addresses, comma separated>
+/// to:<lowercase addresses, comma separated>
+/// date:<unix timestamp>
+/// message-id:<message id without whitespace>
+/// ```
+///
+/// Display names and comments are dropped, a missing header is empty.
+pub fn associated_data(message: &Message) -> Vec<u8> {
+    let mut output = String::from("ENE-MAIL-V1\n");
+
+    for &name in &BOUND_HEADERS {
+        let values = message.get_all(name).collect::<Vec<_>>();
+        let value = match name {
+            "From" | "To" => values.iter()
+                .flat_map(|value| addresses(value))
+                .map(|addr| addr.to_lowercase())
+                .collect::<Vec<_>>()
+                .join(","),
+            "Date" => values.iter()
+                .map(|value| parse_date(value)
+                    .map(|date| date.to_string())
+                    .unwrap_or_else(|| value.split_whitespace().collect::<Vec<_>>().join(" "))
+                )
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => values.iter()
+                .map(|value| value.split_whitespace().collect::<String>())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        output.push_str(&name.to_lowercase());
+        output.push(':');
+        output.push_str(&value);
+        output.push('\n');
+    }
+
+    output.into_bytes()
+}
+
+/// Whether the message is ENE encrypted.
+pub fn is_encrypted(message: &Message) -> bool {
+    message.get("Content-Type")
+        .map(|value| {
+            let value = value.trim_start().to_lowercase();
+            value.starts_with("multipart/encrypted")
+                && param(&value, "protocol").map(|p| p == CONTENT_TYPE).unwrap_or(false)
+        })
+        .unwrap_or(false)
+}
+
+/// Take the encrypted payload of `multipart/encrypted` message.
+pub fn unwrap(message: &Message) -> Fallible<Vec<u8>> {
+    if !is_encrypted(message) {
+        return Err(err_msg(format!("Message is not {} multipart/encrypted", CONTENT_TYPE)));
+    }
+
+    let boundary = message.get("Content-Type")
+        .and_then(|value| param(value, "boundary"))
+        .ok_or_else(|| err_msg("Multipart boundary is missing"))?;
+    let delimiter = format!("--{}", boundary);
+
+    let body = String::from_utf8_lossy(&message.body);
+    let mut parts = Vec::new();
+    let mut part: Option<String> = None;
+
+    for line in body.lines() {
+        let line = line.trim_end_matches('\r');
+
+        if line.starts_with(&delimiter) {
+            parts.extend(part.take());
+
+            if line[delimiter.len()..].starts_with("--") {
+                break
+            }
+
+            part = Some(String::new());
+        } else if let Some(part) = &mut part {
+            part.push_str(line);
+            part.push('\n');
+        }
+    }
+
+    let part = match parts.get(1) {
+        Some(part) => Message::parse(part.as_bytes())?,
+        None => return Err(err_msg("Encrypted part is missing"))
+    };
+
+    let encoded = String::from_utf8_lossy(&part.body)
+        .split_whitespace()
+        .collect::<String>();
+
+    base64::decode(&encoded).map_err(Into::into)
+}
+
+/// Parameter of structured header field, case-insensitive name, unquoted value.
+fn param(value: &str, name: &str) -> Option<String> {
+    value.split(';')
+        .skip(1)
+        .filter_map(|item| {
+            let pos = item.find('=')?;
+            if item[..pos].trim().eq_ignore_ascii_case(name) {
+                Some(item[pos + 1..].trim().trim_matches('"').to_string())
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+
+#[test]
+fn test_mime() {
+    let original = Message::parse(b"From: alice@core.ene\r\nTo: bob@core.ene\r\nSubject: Secret\r\n\r\nHello\r\n").unwrap();
+    let payload = vec![42; 1000];
+
+    let outer = wrap(&original, &payload).unwrap();
+    assert_eq!(outer.get("Subject"), Some(SUBJECT));
+    assert_eq!(outer.get("To"), Some("bob@core.ene"));
+    assert!(is_encrypted(&outer));
+
+    let outer = Message::parse(&outer.to_vec()).unwrap();
+    assert_eq!(unwrap(&outer).unwrap(), payload);
+    assert!(unwrap(&original).is_err());
+}
+
+#[test]
+fn test_associated_data() {
+    let original = Message::parse(b"From: Alice <alice@core.ene>\r\n\
+        To: bob@core.ene, carol@core.ene\r\n\
+        Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n\
+        Message-ID: <1@core.ene>\r\n\
+        \r\n").unwrap();
+    let refolded = Message::parse(b"FROM: alice@Core.ene (Alice)\r\n\
+        To: Bob <bob@core.ene>,\r\n carol@core.ene\r\n\
+        Date: Tue, 01 Jul 2003 08:52:37 +0000\r\n\
+        Message-ID:\r\n <1@core.ene>\r\n\
+        \r\n").unwrap();
+    let redirected = Message::parse(b"From: Alice <alice@core.ene>\r\n\
+        To: bob@core.ene, mallory@core.ene\r\n\
+        Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n\
+        Message-ID: <1@core.ene>\r\n\
+        \r\n").unwrap();
+
+    assert_eq!(
+        associated_data(&original),
+        &b"ENE-MAIL-V1\nfrom:alice@core.ene\nto:bob@core.ene,carol@core.ene\ndate:1057049557\nmessage-id:<1@core.ene>\n"[..]
+    );
+    assert_eq!(associated_data(&original), associated_data(&refolded));
+    assert_ne!(associated_data(&original), associated_data(&redirected));
+}
@@ -0,0 +1,52 @@
+pub mod message;
+pub mod autocrypt;
+pub mod mime;
+pub mod encrypt;
+pub mod decrypt;
+pub mod folder;
+pub mod smtp;
+pub mod sendmail;
+pub mod imap;
+pub mod fetch;
+
+use std::fs;
+use std::io::{ self, Read, Write };
+use std::path::Path;
+use failure::Fallible;
+use directories::ProjectDirs;
+use crate::opts::{ Mail, MailCommand };
+use crate::common::Stdio;
+
+
+impl Mail {
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        match self.command {
+            MailCommand::Encrypt(encrypt) => encrypt.exec(dir, quiet, stdio),
+            MailCommand::Decrypt(decrypt) => decrypt.exec(dir, quiet, stdio),
+            MailCommand::DecryptFolder(decrypt) => decrypt.exec(dir, quiet, stdio),
+            MailCommand::Fetch(fetch) => fetch.exec(dir, quiet, stdio)
+        }
+    }
+}
+
+/// Read the file, or stdin if there is no path.
+fn read_input(input: Option<&Path>) -> Fallible<Vec<u8>> {
+    match input {
+        Some(path) => Ok(fs::read(path)?),
+        None => {
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input)?;
+            Ok(input)
+        }
+    }
+}
+
+/// Write the file, or stdout if there is no path.
+fn write_output(stdio: &mut Stdio, output: Option<&Path>, data: &[u8]) -> Fallible<()> {
+    match output {
+        Some(path) => fs::write(path, data)?,
+        None => stdio.print(|stdout| stdout.lock().write_all(data))?
+    }
+
+    Ok(())
+}
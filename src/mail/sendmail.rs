@@ -0,0 +1,71 @@
+use std::io::{ self, Read };
+use std::path::PathBuf;
+use failure::{ Fallible, err_msg };
+use directories::ProjectDirs;
+use crate::opts::SendMail;
+use crate::common::Stdio;
+use crate::contact::open_store_as;
+use crate::contact::lock::Access;
+use super::message::{ Message, addresses };
+use super::encrypt::{ recipients, prepare, encrypt };
+use super::smtp::Smtp;
+
+
+impl SendMail {
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input)?;
+        let mut message = Message::parse(&input)?;
+
+        // take recipients
+        let mut receivers = self.recipients.iter()
+            .flat_map(|value| addresses(value))
+            .collect::<Vec<_>>();
+        if self.read_recipients || receivers.is_empty() {
+            for addr in recipients(&message) {
+                if !receivers.iter().any(|r| r.eq_ignore_ascii_case(&addr)) {
+                    receivers.push(addr);
+                }
+            }
+        }
+
+        if receivers.is_empty() {
+            return Err(err_msg("Message has no recipient"));
+        }
+
+        // take sender
+        let (db, session) = open_store_as(
+            dir, Access::Read,
+            self.profile.as_ref().map(PathBuf::as_path),
+            self.as_id.as_ref().map(String::as_str)
+        )?;
+        prepare(&session, &mut message)?;
+        let envelope_from = match self.envelope_from {
+            Some(from) => from,
+            None => message.sender()?
+        };
+
+        // encrypt every copy before sending anything, never send plaintext
+        let mut outers = Vec::with_capacity(receivers.len());
+        for receiver_id in &receivers {
+            let outer = encrypt(&session, stdio, &*db, &message, receiver_id, self.protocol.as_ref())
+                .map_err(|err| err_msg(format!("Encrypt to {} failed: {}", receiver_id, err)))?;
+            outers.push((receiver_id, outer));
+        }
+
+        // the store is not held while talking to the relay
+        drop(db);
+
+        let mut smtp = Smtp::connect(self.relay.as_ref().map(String::as_str))?;
+
+        for (receiver_id, outer) in &outers {
+            smtp.send(&envelope_from, &[receiver_id.to_string()], &outer.to_vec())?;
+
+            if !quiet {
+                stdio.info(format_args!("Message is sent to {}", receiver_id))?;
+            }
+        }
+
+        smtp.quit()
+    }
+}
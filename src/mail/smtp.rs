@@ -0,0 +1,117 @@
+use std::env;
+use std::time::Duration;
+use std::net::TcpStream;
+use std::io::{ Write, BufRead, BufReader };
+use failure::{ Fallible, err_msg };
+
+
+const DEFAULT_RELAY: &str = "127.0.0.1:25";
+
+const TIMEOUT_SECS: u64 = 60;
+
+/// SMTP client of a relay, plain text without AUTH,
+/// the relay should be a local or otherwise trusted MTA.
+pub struct Smtp {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream
+}
+
+impl Smtp {
+    /// Connect to `relay`, or `ENE_SMTP_RELAY`, or `127.0.0.1:25`.
+    pub fn connect(relay: Option<&str>) -> Fallible<Smtp> {
+        let relay = match relay {
+            Some(relay) => relay.to_string(),
+            None => env::var("ENE_SMTP_RELAY").unwrap_or_else(|_| DEFAULT_RELAY.into())
+        };
+
+        let stream = TcpStream::connect(&relay)
+            .map_err(|err| err_msg(format!("Connect SMTP relay {} failed: {}", relay, err)))?;
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+
+        let mut smtp = Smtp { reader: BufReader::new(stream.try_clone()?), stream };
+        smtp.expect(220)?;
+        smtp.command("EHLO localhost", 250)?;
+
+        Ok(smtp)
+    }
+
+    /// Submit one message, `data` is dot-stuffed with CRLF line endings.
+    pub fn send(&mut self, from: &str, recipients: &[String], data: &[u8]) -> Fallible<()> {
+        self.command(&format!("MAIL FROM:<{}>", from), 250)?;
+
+        for recipient in recipients {
+            self.command(&format!("RCPT TO:<{}>", recipient), 250)?;
+        }
+
+        self.command("DATA", 354)?;
+        self.stream.write_all(&dot_stuff(data))?;
+        self.command(".", 250)
+    }
+
+    pub fn quit(mut self) -> Fallible<()> {
+        self.command("QUIT", 221)
+    }
+
+    fn command(&mut self, line: &str, code: u16) -> Fallible<()> {
+        self.stream.write_all(line.as_bytes())?;
+        self.stream.write_all(b"\r\n")?;
+        self.expect(code)
+    }
+
+    /// Read a reply, continuation lines are `250-...`.
+    fn expect(&mut self, code: u16) -> Fallible<()> {
+        let mut reply = String::new();
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(err_msg("SMTP relay closed the connection"));
+            }
+
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            reply.push_str(&line);
+
+            if last {
+                break
+            }
+        }
+
+        match reply.get(..3).and_then(|status| status.parse::<u16>().ok()) {
+            Some(status) if status == code => Ok(()),
+            _ => Err(err_msg(format!("SMTP relay replied: {}", reply.trim_end())))
+        }
+    }
+}
+
+/// Normalize line endings to CRLF, and escape leading dots.
+pub fn dot_stuff(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() + 128);
+    let mut line_start = true;
+
+    for (i, &b) in data.iter().enumerate() {
+        if line_start && b == b'.' {
+            output.push(b'.');
+        }
+
+        match b {
+            b'\n' if i == 0 || data[i - 1] != b'\r' => output.extend_from_slice(b"\r\n"),
+            _ => output.push(b)
+        }
+
+        line_start = b == b'\n';
+    }
+
+    if !output.ends_with(b"\r\n") {
+        output.extend_from_slice(b"\r\n");
+    }
+
+    output
+}
+
+
+#[test]
+fn test_dot_stuff() {
+    assert_eq!(dot_stuff(b".a\n..b\r\nc"), &b"..a\r\n...b\r\nc\r\n"[..]);
+    assert_eq!(dot_stuff(b"a\r\n"), &b"a\r\n"[..]);
+}
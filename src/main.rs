@@ -4,8 +4,13 @@
 mod opts;
 mod profile;
 mod contact;
+mod agent;
+mod keyserver;
+mod mail;
 
-use std::fs;
+use std::{ fs, env };
+use std::path::Path;
+use std::ffi::OsString;
 use failure::{ Fallible, Error, err_msg };
 use structopt::StructOpt;
 use directories::ProjectDirs;
@@ -27,15 +32,40 @@ fn start(quiet: bool, subcommand: SubCommand, stdio: &mut Stdio) -> Fallible<()>
         SubCommand::Profile(profile) => profile.exec(&dir, quiet, stdio)?,
         SubCommand::Contact(contact) => contact.exec(&dir, quiet, stdio)?,
         SubCommand::SendTo(sendto) => sendto.exec(&dir, quiet, stdio)?,
-        SubCommand::RecvFrom(recvfrom) => recvfrom.exec(&dir, quiet, stdio)?
+        SubCommand::RecvFrom(recvfrom) => recvfrom.exec(&dir, quiet, stdio)?,
+        SubCommand::Agent(agent) => agent.exec(&dir, quiet, stdio)?,
+        SubCommand::KeyServer(keyserver) => keyserver.exec(&dir, quiet, stdio)?,
+        SubCommand::Mail(mail) => mail.exec(&dir, quiet, stdio)?,
+        SubCommand::SendMail(sendmail) => sendmail.exec(&dir, quiet, stdio)?
     }
 
     Ok(())
 }
 
+/// Invoked as `ene-sendmail`, it is `ene -q sendmail`.
+fn args() -> Vec<OsString> {
+    let mut args = env::args_os().collect::<Vec<_>>();
+    let is_sendmail = args.first()
+        .and_then(|arg0| Path::new(arg0).file_stem())
+        .map(|name| name == "ene-sendmail")
+        .unwrap_or(false);
+
+    if is_sendmail {
+        args.insert(1, OsString::from("sendmail"));
+        args.insert(1, OsString::from("-q"));
+    }
+
+    args
+}
+
 fn main() -> Exit<Error> {
-    let options = Options::from_args();
+    let options = Options::from_iter(args());
     let mut stdio = Stdio::new(options.color.into());
+
+    if let Some(secs) = options.lock_timeout {
+        contact::lock::set_timeout(secs);
+    }
+
     let result = start(options.quiet, options.subcommand, &mut stdio);
     Exit(result, stdio)
 }
@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 use structopt::clap::{ arg_enum, _clap_count_exprs, ArgGroup };
 use crate::core::alg::{ self, Protocol };
@@ -20,7 +21,12 @@ pub struct Options {
 
     /// Try to be as quiet as possible
     #[structopt(short = "q", long = "quiet")]
-    pub quiet: bool
+    pub quiet: bool,
+
+    /// Wait for the contact store lock at most SECONDS, default to `ENE_LOCK_TIMEOUT` or 10,
+    /// readers share the lock of `ENE_CONTACT_DIR`, but the default store is locked exclusively
+    #[structopt(long = "lock-timeout", value_name = "SECONDS")]
+    pub lock_timeout: Option<usize>
 }
 
 arg_enum! {
@@ -61,7 +67,26 @@ pub enum SubCommand {
         name = "recvfrom", display_order = 4,
         raw(group = "arg_group(\"sender\")")
     )]
-    RecvFrom(RecvFrom)
+    RecvFrom(RecvFrom),
+
+    /// Hold unlocked Profile
+    #[structopt(
+        name = "agent", display_order = 5,
+        raw(group = "arg_group(\"agent\")")
+    )]
+    Agent(Agent),
+
+    /// Serve PublicKeys of a directory over HTTP
+    #[structopt(name = "keyserver", display_order = 6)]
+    KeyServer(KeyServer),
+
+    /// Encrypt and decrypt mail
+    #[structopt(name = "mail", display_order = 7)]
+    Mail(Mail),
+
+    /// Sendmail compatible, encrypt mail to every recipient and submit it by SMTP
+    #[structopt(name = "sendmail", display_order = 8)]
+    SendMail(SendMail)
 }
 
 #[derive(Debug, StructOpt)]
@@ -74,10 +99,25 @@ pub struct Profile {
     )]
     pub init: bool,
 
+    /// Restore a Profile from mnemonic, need the same algorithms as init
+    #[structopt(
+        long = "restore",
+        group = "operate", requires = "id"
+    )]
+    pub restore: bool,
+
     /// The ID of Profile
     #[structopt(name = "id", value_name = "ID")]
     pub id: Option<String>,
 
+    /// List all Profiles
+    #[structopt(short = "l", long = "list", group = "operate")]
+    pub list: bool,
+
+    /// Set the default Profile
+    #[structopt(long = "default", group = "operate", requires = "id")]
+    pub default: bool,
+
     /// Choose PublicKey algorithm
     #[structopt(short = "a", long = "choose-pubkey", value_name = "STRING")]
     pub choose_pubkey: Option<String>,
@@ -89,7 +129,11 @@ pub struct Profile {
     )]
     pub choose_encrypt: Option<alg::Encrypt>,
 
-    /// Import a Profile
+    /// Move the Profile of old version into keyring
+    #[structopt(long = "migrate", group = "operate")]
+    pub migrate: bool,
+
+    /// Import a Profile, its passphrase is required to check it
     #[structopt(
         short = "i", long = "import",
         value_name = "PATH", group = "operate",
@@ -113,6 +157,56 @@ pub struct Profile {
     )]
     pub export_pubkey: Option<PathBuf>,
 
+    /// Export a PublicKey into Web Key Directory layout
+    #[structopt(
+        long = "export-wkd",
+        value_name = "PATH", group = "operate",
+        parse(from_os_str)
+    )]
+    pub export_wkd: Option<PathBuf>,
+
+    /// Print an Autocrypt header carrying the PublicKey
+    #[structopt(long = "autocrypt", group = "operate")]
+    pub autocrypt: bool,
+
+    /// Set prefer-encrypt=mutual in Autocrypt header
+    #[structopt(long = "prefer-encrypt", requires = "autocrypt")]
+    pub prefer_encrypt: bool,
+
+    /// Change the passphrase of Profile
+    #[structopt(long = "passwd", group = "operate")]
+    pub passwd: bool,
+
+    /// Publish the PublicKey to key server
+    #[structopt(long = "publish", group = "operate")]
+    pub publish: bool,
+
+    /// Key server URL, default to `ENE_KEYSERVER`
+    #[structopt(long = "keyserver", value_name = "URL")]
+    pub keyserver: Option<String>,
+
+    /// Split Profile into secret shares
+    #[structopt(
+        long = "split", value_name = "N-of-M",
+        group = "operate", requires = "output"
+    )]
+    pub split: Option<Threshold>,
+
+    /// Combine secret shares into a Profile
+    #[structopt(
+        long = "combine", value_name = "PATH",
+        group = "operate",
+        parse(from_os_str)
+    )]
+    pub combine: Vec<PathBuf>,
+
+    /// Output directory of secret shares
+    #[structopt(
+        short = "o", long = "output", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub output: Option<PathBuf>,
+
     /// Profile path
     #[structopt(
         short = "p", long = "profile", value_name = "PATH",
@@ -131,10 +225,34 @@ pub struct Contact {
     #[structopt(name = "id", value_name = "ID")]
     pub id: Option<String>,
 
+    /// Find contacts by fingerprint prefix of any key
+    #[structopt(long = "find", value_name = "FINGERPRINT", group = "contact")]
+    pub find: Option<String>,
+
     /// Import a contact
     #[structopt(short = "i", long = "import", parse(from_os_str), group = "contact")]
     pub import: Option<PathBuf>,
 
+    /// Fetch a contact from key server by ID or 0xFINGERPRINT
+    #[structopt(long = "fetch", value_name = "ID", group = "contact")]
+    pub fetch: Option<String>,
+
+    /// Key server URL, default to `ENE_KEYSERVER`
+    #[structopt(long = "keyserver", value_name = "URL", requires = "fetch")]
+    pub keyserver: Option<String>,
+
+    /// Update the sender from Autocrypt header of the message on stdin
+    #[structopt(long = "autocrypt", group = "contact")]
+    pub autocrypt: bool,
+
+    /// Discover a contact by email ID from Web Key Directory
+    #[structopt(long = "discover", value_name = "EMAIL", group = "contact")]
+    pub discover: Option<String>,
+
+    /// Web Key Directory base, a directory or http:// URL, default to `ENE_WKD_BASE`
+    #[structopt(long = "wkd-base", value_name = "PATH|URL", requires = "discover")]
+    pub wkd_base: Option<String>,
+
     /// Export a contact
     #[structopt(
         short = "e", long = "export",
@@ -143,14 +261,122 @@ pub struct Contact {
     )]
     pub export: Option<PathBuf>,
 
+    /// Export all contacts, or the contacts with ID prefix, as an address book bundle
+    #[structopt(
+        long = "export-all", value_name = "PATH",
+        group = "contact",
+        parse(from_os_str)
+    )]
+    pub export_all: Option<PathBuf>,
+
+    /// Sign the exported address book bundle
+    #[structopt(long = "sign", requires = "export_all")]
+    pub sign: bool,
+
+    /// Encrypt the contact store at rest, it can only be opened by the Profile afterwards
+    #[structopt(long = "encrypt", group = "contact")]
+    pub encrypt: bool,
+
+    /// Authenticate every contact record with the Profile, tampered records are rejected
+    #[structopt(long = "protect", group = "contact")]
+    pub protect: bool,
+
+    /// Sign, encrypt or authenticate with the Profile of ID, default to the default Profile
+    #[structopt(long = "as", value_name = "ID")]
+    pub as_id: Option<String>,
+
+    /// Import an address book bundle
+    #[structopt(
+        long = "import-bundle", value_name = "PATH",
+        group = "contact",
+        parse(from_os_str)
+    )]
+    pub import_bundle: Option<PathBuf>,
+
+    /// Show what the import would change without importing
+    #[structopt(long = "dry-run", requires = "import_bundle")]
+    pub dry_run: bool,
+
+    /// Import the address book bundle even if it is not signed by a pinned contact
+    #[structopt(long = "force", requires = "import_bundle")]
+    pub force: bool,
+
     /// Delete a contact
     #[structopt(short = "d", long = "delete", requires = "id", group = "contact")]
     pub delete: bool,
+
+    /// Edit a contact
+    #[structopt(long = "edit", requires = "id", group = "contact")]
+    pub edit: bool,
+
+    /// Accept the changed PublicKey of a contact
+    #[structopt(long = "accept-change", requires = "id", group = "contact")]
+    pub accept_change: bool,
+
+    /// Add contacts to group, create it if not exists
+    #[structopt(
+        long = "group-add", value_name = "GROUP MEMBER",
+        group = "contact",
+        raw(min_values = "2")
+    )]
+    pub group_add: Vec<String>,
+
+    /// Remove contacts from group, or remove the whole group if no member is given
+    #[structopt(
+        long = "group-del", value_name = "GROUP MEMBER",
+        group = "contact",
+        raw(min_values = "1")
+    )]
+    pub group_del: Vec<String>,
+
+    /// List all groups
+    #[structopt(long = "groups", group = "contact")]
+    pub groups: bool,
+
+    /// Show the key log, or the entries of ID
+    #[structopt(long = "log", group = "contact")]
+    pub log: bool,
+
+    /// Print the consistency proof from the key log snapshot of SIZE to the current one
+    #[structopt(long = "log-proof", value_name = "SIZE", group = "contact")]
+    pub log_proof: Option<usize>,
+
+    /// Verify that a saved key log snapshot is a prefix of the current one
+    #[structopt(long = "log-verify", value_name = "SIZE:ROOT", group = "contact")]
+    pub log_verify: Option<String>,
+
+    /// Set display name
+    #[structopt(long = "name", value_name = "STRING")]
+    pub name: Option<String>,
+
+    /// Set aliases
+    #[structopt(long = "alias", value_name = "STRING")]
+    pub alias: Vec<String>,
+
+    /// Set notes
+    #[structopt(long = "notes", value_name = "STRING")]
+    pub notes: Option<String>,
+
+    /// Set verification status
+    #[structopt(long = "verified", value_name = "BOOL", conflicts_with = "revoke")]
+    pub verified: Option<bool>,
+
+    /// Mark the key as revoked
+    #[structopt(long = "revoke")]
+    pub revoke: bool,
+
+    /// Set the key to expire in DAYS, 0 means never
+    #[structopt(long = "expires-in", value_name = "DAYS")]
+    pub expires_in: Option<u64>,
+
+    /// Set preferred encryption protocol
+    #[structopt(long = "prefer-protocol", value_name = "PROTOCOL")]
+    pub prefer_protocol: Option<Protocol>
 }
 
 #[derive(Debug, StructOpt)]
 pub struct SendTo {
-    /// Contact ID
+    /// Contact ID, or @GROUP for all members of group
     #[structopt(name = "id", value_name = "ID", group = "recipient")]
     pub recipient: Option<String>,
 
@@ -168,12 +394,10 @@ pub struct SendTo {
     )]
     pub output: PathBuf,
 
-    /// Specifies encryption protocol
-    #[structopt(
-        long = "protocol", value_name = "PROTOCOL",
-        raw(default_value = "Protocol::default_name()")
-    )]
-    pub protocol: Protocol,
+    /// Specifies encryption protocol,
+    /// default to the preference of contact or ooake-ristrettodh-aes128colm0
+    #[structopt(long = "protocol", value_name = "PROTOCOL")]
+    pub protocol: Option<Protocol>,
 
     /// Associated Data
     #[structopt(short = "a", long = "associated-data", value_name = "STRING")]
@@ -186,6 +410,13 @@ pub struct SendTo {
     )]
     pub profile: Option<PathBuf>,
 
+    /// Use the Profile of ID
+    #[structopt(
+        long = "as", value_name = "ID",
+        conflicts_with = "profile"
+    )]
+    pub as_id: Option<String>,
+
     /// Contact PublicKey path
     #[structopt(
         short = "t", long = "recipient-pubkey",
@@ -227,6 +458,11 @@ pub struct RecvFrom {
     #[structopt(short = "f", long = "force", group = "sender")]
     pub force: bool,
 
+    /// Use the sender PublicKey in message if the sender is not a contact yet,
+    /// and import it as unverified contact after decryption
+    #[structopt(long = "import-sender", group = "sender")]
+    pub import_sender: bool,
+
     /// Profile path
     #[structopt(
         short = "p", long = "profile", value_name = "PATH",
@@ -234,6 +470,13 @@ pub struct RecvFrom {
     )]
     pub profile: Option<PathBuf>,
 
+    /// Use the Profile of ID
+    #[structopt(
+        long = "as", value_name = "ID",
+        conflicts_with = "profile"
+    )]
+    pub as_id: Option<String>,
+
     /// Contact PublicKey path
     #[structopt(
         short = "t", long = "sender-pubkey",
@@ -243,6 +486,278 @@ pub struct RecvFrom {
     pub sender_pubkey: Option<PathBuf>
 }
 
+#[derive(Debug, StructOpt)]
+pub struct Agent {
+    /// Start agent in foreground
+    #[structopt(long = "start", group = "agent")]
+    pub start: bool,
+
+    /// Stop agent
+    #[structopt(long = "stop", group = "agent")]
+    pub stop: bool,
+
+    /// Exit after being idle for the specified seconds
+    #[structopt(
+        short = "t", long = "timeout", value_name = "SECONDS",
+        default_value = "600"
+    )]
+    pub timeout: u64,
+
+    /// Profile path
+    #[structopt(
+        short = "p", long = "profile", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub profile: Option<PathBuf>,
+
+    /// Use the Profile of ID
+    #[structopt(
+        long = "as", value_name = "ID",
+        conflicts_with = "profile"
+    )]
+    pub as_id: Option<String>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct KeyServer {
+    /// Listen address
+    #[structopt(long = "listen", value_name = "ADDR", default_value = "127.0.0.1:11371")]
+    pub listen: String,
+
+    /// Directory of published PublicKeys, default is `keyserver` in data directory
+    #[structopt(long = "dir", value_name = "PATH", parse(from_os_str))]
+    pub dir: Option<PathBuf>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Mail {
+    #[structopt(subcommand)]
+    pub command: MailCommand
+}
+
+#[derive(Debug, StructOpt)]
+pub enum MailCommand {
+    /// Encrypt RFC 5322 message as multipart/encrypted
+    #[structopt(name = "encrypt", display_order = 1)]
+    Encrypt(MailEncrypt),
+
+    /// Decrypt multipart/encrypted message, reconstruct the original
+    #[structopt(name = "decrypt", display_order = 2)]
+    Decrypt(MailDecrypt),
+
+    /// Decrypt every message of Maildir or mbox with one unlock
+    #[structopt(name = "decrypt-folder", display_order = 3)]
+    DecryptFolder(MailDecryptFolder),
+
+    /// Fetch new messages from IMAP, store them decrypted in Maildir
+    #[structopt(name = "fetch", display_order = 4)]
+    Fetch(MailFetch)
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MailEncrypt {
+    /// Contact ID, default to the only To address
+    #[structopt(long = "to", value_name = "ID")]
+    pub recipient: Option<String>,
+
+    /// Input file, default to stdin
+    #[structopt(
+        short = "i", long = "input", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub input: Option<PathBuf>,
+
+    /// Output file, default to stdout
+    #[structopt(
+        short = "o", long = "output", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub output: Option<PathBuf>,
+
+    /// Specifies encryption protocol,
+    /// default to the preference of contact or ooake-ristrettodh-aes128colm0
+    #[structopt(long = "protocol", value_name = "PROTOCOL")]
+    pub protocol: Option<Protocol>,
+
+    /// Profile path
+    #[structopt(
+        short = "p", long = "profile", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub profile: Option<PathBuf>,
+
+    /// Use the Profile of ID
+    #[structopt(
+        long = "as", value_name = "ID",
+        conflicts_with = "profile"
+    )]
+    pub as_id: Option<String>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MailDecrypt {
+    /// Input file, default to stdin
+    #[structopt(
+        short = "i", long = "input", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub input: Option<PathBuf>,
+
+    /// Output file, default to stdout
+    #[structopt(
+        short = "o", long = "output", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub output: Option<PathBuf>,
+
+    /// Profile path
+    #[structopt(
+        short = "p", long = "profile", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub profile: Option<PathBuf>,
+
+    /// Use the Profile of ID
+    #[structopt(
+        long = "as", value_name = "ID",
+        conflicts_with = "profile"
+    )]
+    pub as_id: Option<String>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MailDecryptFolder {
+    /// Maildir or mbox file
+    #[structopt(value_name = "MAILDIR|MBOX", parse(from_os_str))]
+    pub input: PathBuf,
+
+    /// Maildir of decrypted messages
+    #[structopt(
+        short = "o", long = "output", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub output: PathBuf,
+
+    /// Profile path
+    #[structopt(
+        short = "p", long = "profile", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub profile: Option<PathBuf>,
+
+    /// Use the Profile of ID
+    #[structopt(
+        long = "as", value_name = "ID",
+        conflicts_with = "profile"
+    )]
+    pub as_id: Option<String>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MailFetch {
+    /// IMAP server on loopback, plain text without TLS, remote servers must be tunneled
+    #[structopt(long = "imap", value_name = "HOST:PORT")]
+    pub imap: String,
+
+    /// IMAP user, password is asked by askpass
+    #[structopt(long = "user", value_name = "NAME")]
+    pub user: String,
+
+    /// IMAP mailbox
+    #[structopt(long = "mailbox", value_name = "NAME", default_value = "INBOX")]
+    pub mailbox: String,
+
+    /// Maildir of decrypted messages
+    #[structopt(
+        short = "o", long = "output", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub output: PathBuf,
+
+    /// Profile path
+    #[structopt(
+        short = "p", long = "profile", value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub profile: Option<PathBuf>,
+
+    /// Use the Profile of ID
+    #[structopt(
+        long = "as", value_name = "ID",
+        conflicts_with = "profile"
+    )]
+    pub as_id: Option<String>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SendMail {
+    /// Recipient addresses, default to To/Cc/Bcc of message
+    #[structopt(value_name = "ADDRESS")]
+    pub recipients: Vec<String>,
+
+    /// Read recipients from To/Cc/Bcc of message
+    #[structopt(short = "t")]
+    pub read_recipients: bool,
+
+    /// Envelope sender, default to From of message
+    #[structopt(short = "f", value_name = "ADDRESS")]
+    pub envelope_from: Option<String>,
+
+    /// Ignored, a single dot line never ends the message
+    #[structopt(short = "i")]
+    pub ignore_dots: bool,
+
+    /// Ignored sendmail options, such as -oi
+    #[structopt(short = "o", value_name = "OPTION", raw(number_of_values = "1"))]
+    pub options: Vec<String>,
+
+    /// SMTP relay, default to ENE_SMTP_RELAY or 127.0.0.1:25
+    #[structopt(long = "relay", value_name = "HOST:PORT")]
+    pub relay: Option<String>,
+
+    /// Specifies encryption protocol,
+    /// default to the preference of contact or ooake-ristrettodh-aes128colm0
+    #[structopt(long = "protocol", value_name = "PROTOCOL")]
+    pub protocol: Option<Protocol>,
+
+    /// Profile path
+    #[structopt(long = "profile", value_name = "PATH", parse(from_os_str))]
+    pub profile: Option<PathBuf>,
+
+    /// Use the Profile of ID
+    #[structopt(
+        long = "as", value_name = "ID",
+        conflicts_with = "profile"
+    )]
+    pub as_id: Option<String>
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Threshold {
+    pub threshold: u8,
+    pub count: u8
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut s = s.splitn(2, "-of-");
+
+        let threshold = s.next()
+            .and_then(|n| n.trim().parse().ok());
+        let count = s.next()
+            .and_then(|n| n.trim().parse().ok());
+
+        match (threshold, count) {
+            // a share of 1-of-M is the whole secret key
+            (Some(threshold), Some(count)) if 2 <= threshold && threshold <= count =>
+                Ok(Threshold { threshold, count }),
+            _ => Err("expect N-of-M, and 2 <= N <= M <= 255".into())
+        }
+    }
+}
+
 fn arg_group(name: &'static str) -> ArgGroup<'static> {
     ArgGroup::with_name(name).required(true)
 }
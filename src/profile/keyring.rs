@@ -0,0 +1,176 @@
+use std::fs::{ self, File };
+use std::path::{ Path, PathBuf };
+use failure::{ Fallible, err_msg };
+use serde_cbor as cbor;
+use directories::ProjectDirs;
+use crate::core::contact;
+use crate::core::format::PrivateKey;
+
+
+/// Profile directory, indexed by ID
+///
+/// ```norun
+/// profiles/
+///     default         - ID of the default Profile
+///     <ID>.ene        - Profile
+///     contact         - protection record of contact store, see `contact::db::Db`
+/// key.ene             - the single Profile of old version, see `migrate`
+/// ```
+pub struct Keyring {
+    dir: PathBuf,
+    legacy_path: PathBuf
+}
+
+impl Keyring {
+    pub fn new(dir: &ProjectDirs) -> Fallible<Keyring> {
+        let keyring = Keyring {
+            dir: dir.data_local_dir().join("profiles"),
+            legacy_path: dir.data_local_dir().join("key.ene")
+        };
+
+        if !keyring.dir.is_dir() {
+            fs::create_dir_all(&keyring.dir)?;
+        }
+
+        Ok(keyring)
+    }
+
+    /// Move the single Profile used by old version into keyring,
+    /// return its ID, or `None` if there is nothing to migrate.
+    pub fn migrate(&self) -> Fallible<Option<String>> {
+        if !self.legacy_path.is_file() {
+            return Ok(None);
+        }
+
+        let sk_packed = read(&self.legacy_path)?;
+        let (id, ..) = unwrap!(&sk_packed);
+        let sk_path = self.path(id)?;
+
+        if sk_path.exists() {
+            return Err(err_msg(format!(
+                "Profile {} already exists, remove {} or {} first",
+                id, self.legacy_path.display(), sk_path.display()
+            )));
+        }
+
+        fs::rename(&self.legacy_path, &sk_path)?;
+        self.init_default(id)?;
+
+        Ok(Some(id.to_string()))
+    }
+
+    pub fn path(&self, id: &str) -> Fallible<PathBuf> {
+        if id.is_empty()
+            || id.starts_with('.')
+            || id.contains(|c: char| c == '/' || c == '\\' || c == '\0')
+        {
+            return Err(err_msg(format!("Invalid ID: {:?}", id)));
+        }
+
+        Ok(self.dir.join(format!("{}.ene", id)))
+    }
+
+    /// Protection record of contact store.
+    ///
+    /// It can be deleted like the store, so the requirement is also sealed in the Profile,
+    /// see `contact::check_protected`.
+    pub fn contact_protection(&self) -> PathBuf {
+        self.dir.join("contact")
+    }
+
+    /// Take the specified Profile path, or the Profile of ID, or the default Profile.
+    pub fn resolve(&self, profile: Option<&Path>, id: Option<&str>) -> Fallible<PathBuf> {
+        if let Some(path) = profile {
+            return Ok(path.to_path_buf());
+        }
+
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => match self.get_default()? {
+                Some(id) => id,
+                None if self.legacy_path.is_file() => return Err(err_msg(format!(
+                    "Found the Profile of old version {}, run `ene profile --migrate` first.",
+                    self.legacy_path.display()
+                ))),
+                None => return Err(err_msg("No default Profile, initialize or import one first."))
+            }
+        };
+
+        let sk_path = self.path(&id)?;
+        if sk_path.is_file() {
+            Ok(sk_path)
+        } else {
+            Err(err_msg(format!("Profile does not exist: {}", id)))
+        }
+    }
+
+    pub fn list(&self) -> Fallible<Vec<String>> {
+        let mut ids = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+
+            if path.is_file() && path.extension().map(|ext| ext == "ene").unwrap_or(false) {
+                if let Some(id) = path.file_stem().and_then(|id| id.to_str()) {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn get_default(&self) -> Fallible<Option<String>> {
+        let default_path = self.dir.join("default");
+
+        if default_path.is_file() {
+            let id = fs::read_to_string(default_path)?;
+            Ok(Some(id.trim().to_string()))
+        } else {
+            let mut ids = self.list()?;
+
+            // only one Profile, take it as default
+            if ids.len() == 1 {
+                Ok(ids.pop())
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn set_default(&self, id: &str) -> Fallible<()> {
+        if !self.path(id)?.is_file() {
+            return Err(err_msg(format!("Profile does not exist: {}", id)));
+        }
+
+        fs::write(self.dir.join("default"), id)?;
+        Ok(())
+    }
+
+    /// Set default if it has not been set.
+    pub fn init_default(&self, id: &str) -> Fallible<()> {
+        if !self.dir.join("default").is_file() {
+            self.set_default(id)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read and check the shape of Profile,
+/// `open` it to be sure it is intact.
+pub fn read(path: &Path) -> Fallible<PrivateKey> {
+    let sk_packed: PrivateKey = cbor::from_reader(&mut File::open(path)?)
+        .map_err(|err| err_msg(format!("Invalid Profile {}: {}", path.display(), err)))?;
+    let (id, enc, salt, c) = unwrap!(&sk_packed);
+
+    if salt.len() != 16 || c.len() <= enc.take().tag_length() {
+        return Err(err_msg(format!("Invalid Profile: {}", path.display())));
+    }
+
+    contact::check_id(id)
+        .map_err(|err| err_msg(format!("Invalid Profile {}: {}", path.display(), err)))?;
+
+    Ok(sk_packed)
+}
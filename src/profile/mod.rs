@@ -0,0 +1,386 @@
+pub mod keyring;
+mod share;
+
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::fs::{ self, File };
+use rand::{ Rng, OsRng };
+use failure::{ Fallible, err_msg };
+use argon2rs::{ Argon2, Variant };
+use serde_derive::{ Serialize, Deserialize };
+use serde_bytes::ByteBuf;
+use serde_cbor as cbor;
+use directories::ProjectDirs;
+use seckey::{ SecKey, TempKey, free };
+use crate::core::{ alg, key, mnemonic, Builder, Ene };
+use crate::core::format::{ PrivateKey, PublicKey, Envelope };
+use crate::opts::Profile;
+use crate::common::{ Stdio, askpass, askpass_with };
+use crate::agent::Session;
+use crate::keyserver::client::Client;
+use crate::contact::wkd;
+use crate::mail::autocrypt;
+use self::keyring::Keyring;
+
+
+/// Settings sealed along with the secret key, so they can not be changed without the passphrase.
+#[derive(Default, Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// The default contact store must be protected, see `contact::check_protected`
+    pub contact_protected: bool
+}
+
+impl Profile {
+    pub fn exec(self, dir: &ProjectDirs, quiet: bool, stdio: &mut Stdio) -> Fallible<()> {
+        let keyring = Keyring::new(dir)?;
+
+        if self.init || self.restore {
+            let id = self.id.unwrap();
+            let sk_path = match self.profile {
+                Some(path) => path,
+                None => keyring.path(&id)?
+            };
+
+            check!(is_file sk_path);
+
+            init(
+                quiet, stdio,
+                &id,
+                self.choose_pubkey.as_ref().map(String::as_str),
+                self.choose_encrypt.unwrap_or(alg::Encrypt::Aes128Colm0),
+                self.restore,
+                &sk_path
+            )?;
+
+            if sk_path == keyring.path(&id)? {
+                keyring.init_default(&id)?;
+            }
+        } else if self.list {
+            let default_id = keyring.get_default()?;
+
+            for id in keyring.list()? {
+                if quiet {
+                    stdio.info(format_args!("{}", id))?;
+                } else if Some(&id) == default_id.as_ref() {
+                    stdio.info(format_args!("* {}", id))?;
+                } else {
+                    stdio.info(format_args!("  {}", id))?;
+                }
+            }
+        } else if self.default {
+            let id = self.id.unwrap();
+            keyring.set_default(&id)?;
+
+            if !quiet {
+                stdio.info(format_args!("{} is the default Profile now", id))?;
+            }
+        } else if self.migrate {
+            match keyring.migrate()? {
+                Some(id) => if !quiet {
+                    stdio.info(format_args!("{} has been migrated!", id))?;
+                },
+                None => if !quiet {
+                    stdio.info(format_args!("Nothing to migrate"))?;
+                }
+            }
+        } else if let Some(path) = self.import {
+            let sk_packed = keyring::read(&path)?;
+            let (id, ..) = unwrap!(&sk_packed);
+            let sk_path = keyring.path(id)?;
+
+            check!(is_file sk_path);
+
+            // a damaged Profile fails to decrypt
+            askpass_with(&format!("Password of {}:", id), |pass| open(pass.as_bytes(), &sk_packed))?;
+            fs::copy(path, sk_path)?;
+            keyring.init_default(id)?;
+
+            if !quiet {
+                stdio.info(format_args!("{} has been imported!", id))?;
+            }
+        } else if !self.combine.is_empty() {
+            share::combine(
+                quiet, stdio,
+                self.choose_encrypt.unwrap_or(alg::Encrypt::Aes128Colm0),
+                &self.combine,
+                &keyring,
+                self.profile
+            )?;
+        } else {
+            let sk_path = keyring.resolve(
+                self.profile.as_ref().map(PathBuf::as_path),
+                self.id.as_ref().map(String::as_str)
+            )?;
+
+            if let Some(mut path) = self.export_pubkey {
+                let sk_packed: PrivateKey = cbor::from_reader(&mut File::open(&sk_path)?)?;
+                let (sk, _) = askpass(|pass| open(pass.as_bytes(), &sk_packed))?;
+                let sk = sk.read();
+                let (id, ..) = unwrap!(&sk_packed);
+
+                if path.is_dir() {
+                    path = path.join(format!("{}.ene", id));
+                }
+
+                let pk = sk.as_secret().to_public();
+                let pk_packed: PublicKey = Envelope::from((id.to_owned(), pk));
+                cbor::to_writer(&mut File::create(&path)?, &pk_packed)?;
+
+                if !quiet {
+                    stdio.info(format_args!(
+                        "PublicKey has been exported to {}",
+                        path.canonicalize()?.display()
+                    ))?;
+                }
+            } else if let Some(mut path) = self.export_privkey {
+                if path.is_dir() {
+                    let sk_packed: PrivateKey = cbor::from_reader(&mut File::open(&sk_path)?)?;
+                    let (id, ..) = unwrap!(&sk_packed);
+
+                    path = path.join(format!("{}.ene", id));
+                }
+
+                check!(is_file path);
+                fs::copy(sk_path, &path)?;
+
+                if !quiet {
+                    stdio.info(format_args!(
+                        "PrivateKey has been exported to {}",
+                        path.canonicalize()?.display()
+                    ))?;
+                }
+            } else if let Some(path) = self.export_wkd {
+                let session = Session::open(
+                    dir,
+                    self.profile.as_ref().map(PathBuf::as_path),
+                    self.id.as_ref().map(String::as_str)
+                )?;
+
+                let path = wkd::export(&path, session.get_id(), session.as_public())?;
+
+                if !quiet {
+                    stdio.info(format_args!(
+                        "PublicKey has been exported to {}",
+                        path.canonicalize()?.display()
+                    ))?;
+                }
+            } else if self.autocrypt {
+                let session = Session::open(
+                    dir,
+                    self.profile.as_ref().map(PathBuf::as_path),
+                    self.id.as_ref().map(String::as_str)
+                )?;
+
+                let header = autocrypt::Header::new(session.get_id(), session.as_public(), self.prefer_encrypt);
+                stdio.info(format_args!("{}: {}", autocrypt::HEADER, header.to_folded()?))?;
+            } else if self.publish {
+                let client = Client::new(self.keyserver.as_ref().map(String::as_str))?;
+                let session = Session::open(
+                    dir,
+                    self.profile.as_ref().map(PathBuf::as_path),
+                    self.id.as_ref().map(String::as_str)
+                )?;
+
+                let new = client.publish(&session)?;
+
+                if !quiet {
+                    if new {
+                        stdio.info(format_args!("{} has been published to {}", session.get_id(), client.url()))?;
+                    } else {
+                        stdio.info(format_args!("{} is already published to {}", session.get_id(), client.url()))?;
+                    }
+                }
+            } else if self.passwd {
+                passwd(quiet, stdio, self.choose_encrypt, &sk_path)?;
+            } else if let Some(threshold) = self.split {
+                share::split(quiet, stdio, threshold, &sk_path, &self.output.unwrap())?;
+            } else {
+                unreachable!()
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn init(
+    quiet: bool,
+    stdio: &mut Stdio,
+    id: &str,
+    algorithms: Option<&str>, enc: alg::Encrypt,
+    restore: bool,
+    output: &Path
+) -> Fallible<()> {
+    let builder = if let Some(algorithms) = algorithms {
+        Builder::from_str(algorithms)?
+    } else {
+        Builder::default()
+    };
+
+    let mut rng = OsRng::new()?;
+    let mut seed = if restore {
+        askpass_with("Mnemonic:", |phrase| mnemonic::decode(phrase).map_err(Into::into))?
+    } else {
+        let mut seed = vec![0; 32];
+        rng.fill(seed.as_mut_slice());
+        seed
+    };
+    let seed = TempKey::from(&mut seed[..]);
+
+    let ene = SecKey::new(builder.derive(id, &seed))
+        .map_err(|_| err_msg("Secure alloc fail"))?;
+    let ene = ene.read();
+    let sk_packed = askpass(|pass| seal(&mut rng, enc, id, pass.as_bytes(), ene.as_secret(), Settings::default()))?;
+
+    let mut sk_file = File::create(output)?;
+    cbor::to_writer(&mut sk_file, &sk_packed)?;
+    sk_file.sync_all()?;
+
+    if restore {
+        if !quiet {
+            stdio.info(format_args!(
+                "Profile successfully restored\n\nuid: {}\npub: {:#?}",
+                ene.get_id(), ene.as_secret().to_public().to_short()
+            ))?;
+        }
+    } else {
+        let words = mnemonic::encode(&seed)?;
+
+        if quiet {
+            stdio.info(format_args!("{}", words.join(" ")))?;
+        } else {
+            stdio.info(format_args!(
+                "Profile successfully initialized\n\nuid: {}\npub: {:#?}",
+                ene.get_id(), ene.as_secret().to_public().to_short()
+            ))?;
+            stdio.warn(format_args!(
+                "\nWrite down the mnemonic and keep it safe, it will not be shown again:"
+            ))?;
+            stdio.info(format_args!("{}", words.join(" ")))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn passwd(
+    quiet: bool,
+    stdio: &mut Stdio,
+    enc: Option<alg::Encrypt>,
+    path: &Path
+) -> Fallible<()> {
+    let sk_packed: PrivateKey = cbor::from_reader(&mut File::open(path)?)?;
+    let (sk, settings) = askpass(|pass| open(pass.as_bytes(), &sk_packed))?;
+    let sk = sk.read();
+    let (id, old_enc, ..) = unwrap!(&sk_packed);
+    let enc = enc.unwrap_or(*old_enc);
+
+    let mut rng = OsRng::new()?;
+    let sk_packed = askpass_with("New Password:", |pass|
+        askpass_with("Repeat Password:", |pass2|
+            if pass == pass2 {
+                seal(&mut rng, enc, id, pass.as_bytes(), sk.as_secret(), settings)
+            } else {
+                Err(err_msg("Passwords do not match"))
+            }
+        )
+    )?;
+
+    replace(path, &sk_packed)?;
+
+    if !quiet {
+        stdio.info(format_args!("Passphrase of {} has been changed", id))?;
+    }
+
+    Ok(())
+}
+
+/// Record in the Profile that the default contact store must be protected.
+///
+/// It is sealed with the secret key, so unlike the protection record in keyring,
+/// it can not be removed without the passphrase.
+pub fn require_contact_protection(path: &Path) -> Fallible<()> {
+    let sk_packed: PrivateKey = cbor::from_reader(&mut File::open(path)?)?;
+    let (id, enc, ..) = unwrap!(&sk_packed);
+
+    let mut rng = OsRng::new()?;
+    let sk_packed = askpass_with(&format!("Password of {}:", id), |pass| {
+        let (sk, mut settings) = open(pass.as_bytes(), &sk_packed)?;
+        settings.contact_protected = true;
+        seal(&mut rng, *enc, id, pass.as_bytes(), sk.read().as_secret(), settings)
+    })?;
+
+    replace(path, &sk_packed)
+}
+
+/// Write to a temporary file first, then atomically replace the Profile.
+///
+/// A temporary file left by an interrupted write is overwritten.
+fn replace(path: &Path, sk_packed: &PrivateKey) -> Fallible<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut tmp_file = File::create(&tmp_path)?;
+    cbor::to_writer(&mut tmp_file, sk_packed)?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+pub fn seal(
+    rng: &mut OsRng,
+    enc: alg::Encrypt,
+    id: &str,
+    key: &[u8],
+    sk: &key::SecretKey,
+    settings: Settings
+) -> Fallible<PrivateKey> {
+    let aead = enc.take();
+
+    let mut salt = vec![0; 16];
+    let mut tmpkey = vec![0; aead.key_length() + aead.nonce_length()];
+    rng.fill(salt.as_mut_slice());
+    Argon2::default(Variant::Argon2d)
+        .hash(&mut tmpkey, key, &salt, &[], &[]);
+    let (aekey, nonce) = tmpkey.split_at(aead.key_length());
+
+    let sk_encoded = cbor::to_vec(&(sk, settings))?;
+    let mut output = vec![0; sk_encoded.len() + aead.tag_length()];
+    aead.seal(aekey, nonce, &salt, &sk_encoded, &mut output)?;
+
+    Ok(Envelope::from((
+        id.to_string(),
+        enc,
+        ByteBuf::from(salt),
+        ByteBuf::from(output)
+    )))
+}
+
+/// Decrypt Profile, the Profile of old version has only the secret key.
+pub fn open(key: &[u8], sk_packed: &PrivateKey) -> Fallible<(SecKey<Ene>, Settings)> {
+    let (id, enc, salt, c) = unwrap!(sk_packed);
+    let aead = enc.take();
+
+    let mut tmpkey = vec![0; aead.key_length() + aead.nonce_length()];
+    Argon2::default(Variant::Argon2d)
+        .hash(&mut tmpkey, key, &salt, &[], &[]);
+    let (aekey, nonce) = tmpkey.split_at(aead.key_length());
+
+    let mut sk_encoded = vec![0; c.len() - aead.tag_length()];
+    aead.open(aekey, nonce, salt, c, &mut sk_encoded)?;
+
+    let (sk, settings) = match cbor::from_slice::<(key::SecretKey, Settings)>(&sk_encoded) {
+        Ok(sk) => sk,
+        Err(_) => (cbor::from_slice(&sk_encoded)?, Settings::default())
+    };
+    let sk = Ene::from(id, sk);
+    let sk = SecKey::new(sk).map_err(|ene| {
+        free(ene);
+        err_msg("Secure alloc fail")
+    })?;
+
+    Ok((sk, settings))
+}
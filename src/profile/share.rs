@@ -0,0 +1,151 @@
+use std::path::{ Path, PathBuf };
+use std::io;
+use std::fs::{ File, OpenOptions };
+use rand::OsRng;
+use failure::{ Fallible, err_msg };
+use serde_bytes::ByteBuf;
+use serde_cbor as cbor;
+use seckey::{ SecKey, TempKey, free };
+use crate::core::{ alg, shamir, Ene };
+use crate::core::format::{ PrivateKey, Share, Envelope };
+use crate::opts::Threshold;
+use crate::common::{ Stdio, askpass };
+use super::{ Settings, open, seal };
+use super::keyring::Keyring;
+
+
+pub fn split(
+    quiet: bool,
+    stdio: &mut Stdio,
+    Threshold { threshold, count }: Threshold,
+    sk_path: &Path,
+    output: &Path
+) -> Fallible<()> {
+    if threshold < 2 || threshold > count {
+        return Err(err_msg(format!("invalid threshold {}-of-{}", threshold, count)));
+    }
+
+    let sk_packed: PrivateKey = cbor::from_reader(&mut File::open(sk_path)?)?;
+    let (id, ..) = unwrap!(&sk_packed);
+
+    let paths = (1..=count)
+        .map(|index| output.join(format!("{}.{}.share.ene", id, index)))
+        .collect::<Vec<_>>();
+    for path in &paths {
+        check!(is_file path);
+    }
+
+    let (sk, _) = askpass(|pass| open(pass.as_bytes(), &sk_packed))?;
+    let sk = sk.read();
+
+    let mut rng = OsRng::new()?;
+    let mut sk_encoded = cbor::to_vec(sk.as_secret())?;
+    let sk_encoded = TempKey::from(&mut sk_encoded[..]);
+    let shares = shamir::split(&mut rng, &sk_encoded, threshold, count)?;
+
+    for ((index, share), path) in shares.into_iter().zip(&paths) {
+        let share_packed: Share = Envelope::from((
+            id.to_string(),
+            threshold,
+            index,
+            ByteBuf::from(share)
+        ));
+
+        let mut share_file = create(path)?;
+        cbor::to_writer(&mut share_file, &share_packed)?;
+        share_file.sync_all()?;
+    }
+
+    if !quiet {
+        stdio.info(format_args!(
+            "Profile has been split into {} shares, any {} of them can recover it:",
+            count, threshold
+        ))?;
+
+        for path in &paths {
+            stdio.info(format_args!("{}", path.canonicalize()?.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Share is not encrypted, so only the owner can read it.
+#[cfg(unix)]
+fn create(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+pub fn combine(
+    quiet: bool,
+    stdio: &mut Stdio,
+    enc: alg::Encrypt,
+    paths: &[PathBuf],
+    keyring: &Keyring,
+    output: Option<PathBuf>
+) -> Fallible<()> {
+    let mut shares_packed = Vec::with_capacity(paths.len());
+    for path in paths {
+        let share_packed: Share = cbor::from_reader(&mut File::open(path)?)?;
+        shares_packed.push(unwrap!(share_packed));
+    }
+
+    let (id, threshold, ..) = shares_packed.first()
+        .ok_or_else(|| err_msg("no share"))?;
+
+    if shares_packed.iter().any(|(id2, threshold2, ..)| id2 != id || threshold2 != threshold) {
+        return Err(err_msg("shares do not belong to the same profile"));
+    }
+
+    if shares_packed.len() < *threshold as usize {
+        return Err(err_msg(format!("at least {} shares are required", threshold)));
+    }
+
+    let output = match output {
+        Some(path) => path,
+        None => keyring.path(id)?
+    };
+    check!(is_file output);
+
+    let shares = shares_packed.iter()
+        .map(|(_, _, index, share)| (*index, &share[..]))
+        .collect::<Vec<_>>();
+    let mut sk_encoded = shamir::combine(&shares)?;
+    let sk_encoded = TempKey::from(&mut sk_encoded[..]);
+    let sk = cbor::from_slice(&sk_encoded)
+        .map_err(|_| err_msg("shares are invalid or corrupted"))?;
+
+    let ene = SecKey::new(Ene::from(id, sk))
+        .map_err(|ene| {
+            free(ene);
+            err_msg("Secure alloc fail")
+        })?;
+    let ene = ene.read();
+
+    let mut rng = OsRng::new()?;
+    let sk_packed = askpass(|pass| seal(&mut rng, enc, id, pass.as_bytes(), ene.as_secret(), Settings::default()))?;
+
+    let mut sk_file = File::create(&output)?;
+    cbor::to_writer(&mut sk_file, &sk_packed)?;
+    sk_file.sync_all()?;
+
+    if output == keyring.path(id)? {
+        keyring.init_default(id)?;
+    }
+
+    if !quiet {
+        stdio.info(format_args!(
+            "Profile successfully recovered\n\nuid: {}\npub: {:#?}",
+            ene.get_id(), ene.as_secret().to_public().to_short()
+        ))?;
+    }
+
+    Ok(())
+}
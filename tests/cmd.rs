@@ -1,18 +1,51 @@
+extern crate fs2;
+extern crate ene_core;
+extern crate serde_cbor;
 extern crate rand;
 extern crate failure;
 extern crate escargot;
 extern crate assert_fs;
 extern crate assert_cmd;
 
-use std::{ fs, env };
+use std::{ fs, thread };
+use std::net::TcpListener;
+use std::io::{ Write, BufRead, BufReader };
 use std::process::Command;
 use rand::{ Rng, thread_rng, distributions::Alphanumeric };
 use failure::Fallible;
-use escargot::CargoBuild;
+use escargot::{ CargoBuild, CargoRun };
 use assert_fs::TempDir;
 use assert_cmd::prelude::*;
 
 
+/// `ene` binary, every command asks passphrase by the dummy askpass
+///
+/// `ENE_ASKPASS` is set per command instead of process wide, since tests run in parallel.
+struct Bin {
+    ene: CargoRun,
+    askpass: CargoRun
+}
+
+impl Bin {
+    fn command(&self) -> Command {
+        let mut command = self.ene.command();
+        command.env("ENE_ASKPASS", self.askpass.path());
+        command
+    }
+}
+
+/// Build binaries, and take a temporary directory for one test.
+fn setup() -> Fallible<(Bin, TempDir)> {
+    let askpass = CargoBuild::new()
+        .example("dummy_askpass")
+        .run()?;
+    let ene = CargoBuild::new()
+        .bin("ene")
+        .run()?;
+
+    Ok((Bin { ene, askpass }, TempDir::new()?))
+}
+
 #[test]
 fn test_askpass() -> Fallible<()> {
     Command::cargo_example("dummy_askpass")?
@@ -25,15 +58,7 @@ fn test_askpass() -> Fallible<()> {
 
 #[test]
 fn test_cmd() -> Fallible<()> {
-    let askpass = CargoBuild::new()
-        .example("dummy_askpass")
-        .run()?;
-    env::set_var("ENE_ASKPASS", askpass.path());
-
-    let tempdir = TempDir::new()?;
-    let bin = CargoBuild::new()
-        .bin("ene")
-        .run()?;
+    let (bin, tempdir) = setup()?;
 
     // bob generate privkey
     bin.command()
@@ -51,6 +76,55 @@ fn test_cmd() -> Fallible<()> {
         .assert()
         .success();
 
+    // bob split profile, every share of 1-of-M would be the whole key
+    fs::create_dir(tempdir.path().join("shares"))?;
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--split").arg("1-of-3")
+        .arg("--output").arg(tempdir.path().join("shares"))
+        .assert()
+        .failure();
+    assert_eq!(fs::read_dir(tempdir.path().join("shares"))?.count(), 0);
+
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--split").arg("2-of-3")
+        .arg("--output").arg(tempdir.path().join("shares"))
+        .assert()
+        .success();
+
+    #[cfg(unix)] {
+        use std::os::unix::fs::PermissionsExt;
+
+        for entry in fs::read_dir(tempdir.path().join("shares"))? {
+            assert_eq!(entry?.metadata()?.permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    // bob combine shares
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg(tempdir.path().join("bob2.ene"))
+        .arg("--combine")
+        .arg(tempdir.path().join("shares").join("bob@core.ene.1.share.ene"))
+        .arg(tempdir.path().join("shares").join("bob@core.ene.3.share.ene"))
+        .assert()
+        .success();
+
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg(tempdir.path().join("bob2.ene"))
+        .arg("--export-pubkey").arg(tempdir.path().join("bob2.pk.ene"))
+        .assert()
+        .success();
+
+    assert_eq!(
+        fs::read(tempdir.path().join("bob.pk.ene"))?,
+        fs::read(tempdir.path().join("bob2.pk.ene"))?
+    );
+
     // write mail
     let title = "Bob to Alice Mail";
     let msg = thread_rng().sample_iter(&Alphanumeric)
@@ -111,3 +185,2168 @@ fn test_cmd() -> Fallible<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_keyring() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+
+    // Profile of old version is not moved as a side effect
+    fs::create_dir_all(data.join("ene"))?;
+    fs::copy("./tests/common/alice.ene", data.join("ene/key.ene"))?;
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("profile").arg("--list")
+        .assert()
+        .success()
+        .stdout("");
+    assert!(data.join("ene/key.ene").is_file());
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("profile").arg("--migrate")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("profile").arg("--list")
+        .assert()
+        .success()
+        .stdout("alice@core.ene\n");
+    assert!(!data.join("ene/key.ene").exists());
+
+    // damaged or undecryptable Profile is refused
+    bin.command()
+        .arg("profile")
+        .arg("bob@core.ene").arg("--init")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .assert()
+        .success();
+
+    let mut damaged = fs::read(tempdir.path().join("bob.ene"))?;
+    let len = damaged.len();
+    damaged[len - 1] ^= 1;
+    fs::write(tempdir.path().join("damaged.ene"), &damaged)?;
+    fs::write(tempdir.path().join("garbage.ene"), "garbage")?;
+
+    for path in &["damaged.ene", "garbage.ene"] {
+        bin.command()
+            .env("XDG_DATA_HOME", &data)
+            .arg("profile")
+            .arg("--import").arg(tempdir.path().join(path))
+            .assert()
+            .failure();
+    }
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .env("ENE_ASKPASS", "false")
+        .arg("profile")
+        .arg("--import").arg(tempdir.path().join("bob.ene"))
+        .assert()
+        .failure();
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("profile")
+        .arg("--import").arg(tempdir.path().join("bob.ene"))
+        .assert()
+        .success();
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("profile").arg("--list")
+        .assert()
+        .success()
+        .stdout("alice@core.ene\nbob@core.ene\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_contact_edit() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+    let contacts = tempdir.path().join("contacts");
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact")
+        .arg("--import").arg("./tests/common/alice.pk.ene")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("alice@core.ene")
+        .arg("--edit")
+        .arg("--name").arg("Alice")
+        .arg("--alias").arg("a").arg("--alias").arg("al")
+        .arg("--notes").arg("met at core")
+        .arg("--verified").arg("true")
+        .arg("--expires-in").arg("30")
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("--list")
+        .assert()
+        .success();
+    let output = String::from_utf8(assert.get_output().stdout.clone())?;
+
+    assert!(output.contains("uid: alice@core.ene\n"));
+    assert!(output.contains("name: Alice\n"));
+    assert!(output.contains("aliases: a, al\n"));
+    assert!(output.contains("notes: met at core\n"));
+    assert!(output.contains("status: verified\n"));
+    assert!(!output.contains("added: unknown"));
+
+    // expires is formatted as UTC date time
+    let expires = output.lines()
+        .find(|line| line.starts_with("expires: "))
+        .unwrap();
+    assert_eq!(expires.len(), "expires: 2000-01-01 00:00:00 UTC".len());
+    assert!(expires.ends_with(" UTC"));
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("alice@core.ene")
+        .arg("--edit")
+        .arg("--expires-in").arg("0")
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("--list")
+        .assert()
+        .success();
+    let output = String::from_utf8(assert.get_output().stdout.clone())?;
+
+    assert!(output.contains("name: Alice\n"));
+    assert!(!output.contains("expires: "));
+
+    Ok(())
+}
+
+#[test]
+fn test_key_changed() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+    let contacts = tempdir.path().join("contacts");
+
+    // bob pinned another key as alice
+    for (id, name) in &[("alice@core.ene", "alice2"), ("bob@core.ene", "bob")] {
+        bin.command()
+            .arg("profile")
+            .arg(id).arg("--init")
+            .arg("--profile").arg(tempdir.path().join(format!("{}.ene", name)))
+            .assert()
+            .success();
+
+        bin.command()
+            .arg("profile")
+            .arg("--profile").arg(tempdir.path().join(format!("{}.ene", name)))
+            .arg("--export-pubkey").arg(tempdir.path().join(format!("{}.pk.ene", name)))
+            .assert()
+            .success();
+    }
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact")
+        .arg("--import").arg(tempdir.path().join("alice2.pk.ene"))
+        .assert()
+        .success();
+
+    fs::write(tempdir.path().join("plaintext.txt"), "Hello")?;
+    bin.command()
+        .arg("sendto")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--recipient-pubkey").arg(tempdir.path().join("bob.pk.ene"))
+        .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+        .arg("--output").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success();
+
+    // forged message does not touch the contact
+    let mut forged = fs::read(tempdir.path().join("ciphertext.msg.ene"))?;
+    let len = forged.len();
+    forged[len - 1] ^= 1;
+    fs::write(tempdir.path().join("forged.msg.ene"), &forged)?;
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("recvfrom").arg("alice@core.ene")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--input").arg(tempdir.path().join("forged.msg.ene"))
+        .assert()
+        .failure()
+        .code(1);
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("alice@core.ene")
+        .arg("--accept-change")
+        .assert()
+        .failure();
+
+    // verified message with another key
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("recvfrom").arg("alice@core.ene")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--input").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .arg("--output").arg(tempdir.path().join("output.txt"))
+        .assert()
+        .failure()
+        .code(3);
+    assert!(!tempdir.path().join("output.txt").exists());
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("alice@core.ene")
+        .arg("--accept-change")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("recvfrom").arg("alice@core.ene")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--input").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .arg("--output").arg(tempdir.path().join("output.txt"))
+        .assert()
+        .success();
+    assert_eq!(fs::read(tempdir.path().join("output.txt"))?, b"Hello");
+
+    // replaced PublicKey file is not trusted, it is kept as pending
+    fs::copy(tempdir.path().join("alice2.pk.ene"), contacts.join("alice@core.ene.ene"))?;
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("--list").arg("alice@core.ene")
+        .assert()
+        .success();
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("changed: "));
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("-q")
+        .arg("recvfrom").arg("alice@core.ene")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--input").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success()
+        .stdout("Hello");
+
+    // key file of bob renamed to alice is refused
+    fs::copy(tempdir.path().join("bob.pk.ene"), contacts.join("alice@core.ene.ene"))?;
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("--list").arg("alice@core.ene")
+        .assert()
+        .success();
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("holds the PublicKey of bob@core.ene"));
+    assert!(!String::from_utf8(assert.get_output().stdout.clone())?.contains("uid: alice@core.ene"));
+
+    fs::write(tempdir.path().join("plaintext.txt"), "Hello")?;
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("sendto").arg("alice@core.ene")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+        .arg("--output").arg(tempdir.path().join("renamed.msg.ene"))
+        .assert()
+        .failure();
+    assert!(!tempdir.path().join("renamed.msg.ene").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_bundle() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+    let alice_contacts = tempdir.path().join("alice_contacts");
+    let bob_contacts = tempdir.path().join("bob_contacts");
+    let other_contacts = tempdir.path().join("other_contacts");
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("profile")
+        .arg("--import").arg("./tests/common/alice.ene")
+        .assert()
+        .success();
+
+    for (id, name) in &[
+        ("carol@core.ene", "carol"), ("carol@core.ene", "carol2"),
+        ("dave@core.ene", "dave"), ("dave@core.ene", "dave2"),
+        ("eve@core.ene", "eve")
+    ] {
+        bin.command()
+            .arg("profile")
+            .arg(id).arg("--init")
+            .arg("--profile").arg(tempdir.path().join(format!("{}.ene", name)))
+            .assert()
+            .success();
+
+        bin.command()
+            .arg("profile")
+            .arg("--profile").arg(tempdir.path().join(format!("{}.ene", name)))
+            .arg("--export-pubkey").arg(tempdir.path().join(format!("{}.pk.ene", name)))
+            .assert()
+            .success();
+    }
+
+    for name in &["carol", "dave", "eve"] {
+        bin.command()
+            .env("ENE_CONTACT_DIR", &alice_contacts)
+            .arg("contact")
+            .arg("--import").arg(tempdir.path().join(format!("{}.pk.ene", name)))
+            .assert()
+            .success();
+    }
+
+    for (signed, name) in &[(true, "signed.bundle"), (false, "unsigned.bundle")] {
+        let mut command = bin.command();
+        command
+            .env("XDG_DATA_HOME", &data)
+            .env("ENE_CONTACT_DIR", &alice_contacts)
+            .arg("contact")
+            .arg("--export-all").arg(tempdir.path().join(name));
+        if *signed {
+            command.arg("--sign").arg("--as").arg("alice@core.ene");
+        }
+        command.assert().success();
+    }
+
+    // bob pinned alice, another carol, and verified another dave
+    for name in &["carol2", "dave2"] {
+        bin.command()
+            .env("ENE_CONTACT_DIR", &bob_contacts)
+            .arg("contact")
+            .arg("--import").arg(tempdir.path().join(format!("{}.pk.ene", name)))
+            .assert()
+            .success();
+    }
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &bob_contacts)
+        .arg("contact")
+        .arg("--import").arg("./tests/common/alice.pk.ene")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &bob_contacts)
+        .arg("contact").arg("dave@core.ene")
+        .arg("--edit").arg("--verified").arg("true")
+        .assert()
+        .success();
+
+    // dry run reports every change and imports nothing
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &bob_contacts)
+        .arg("contact")
+        .arg("--import-bundle").arg(tempdir.path().join("signed.bundle"))
+        .arg("--dry-run")
+        .assert()
+        .success();
+    let stdout = String::from_utf8(assert.get_output().stdout.clone())?;
+    let stderr = String::from_utf8(assert.get_output().stderr.clone())?;
+
+    assert!(stdout.contains("signed by: alice@core.ene (unverified)"));
+    assert!(stdout.contains("new eve@core.ene: "));
+    assert!(stdout.contains("changed carol@core.ene: "));
+    assert!(stderr.contains("conflict dave@core.ene: "));
+    assert!(stdout.contains("dry run: 1 new, 0 unchanged, 1 changed, 1 conflicting"));
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &bob_contacts)
+        .arg("-q")
+        .arg("contact").arg("--list").arg("eve@core.ene")
+        .assert()
+        .success()
+        .stdout("");
+
+    // unsigned bundle, or bundle signed by unknown contact, requires --force
+    bin.command()
+        .env("ENE_CONTACT_DIR", &bob_contacts)
+        .arg("contact")
+        .arg("--import-bundle").arg(tempdir.path().join("unsigned.bundle"))
+        .assert()
+        .failure();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &other_contacts)
+        .arg("contact")
+        .arg("--import-bundle").arg(tempdir.path().join("signed.bundle"))
+        .assert()
+        .failure();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &other_contacts)
+        .arg("contact")
+        .arg("--import-bundle").arg(tempdir.path().join("signed.bundle"))
+        .arg("--force")
+        .assert()
+        .success();
+
+    // changed key is pending, verified key is kept
+    bin.command()
+        .env("ENE_CONTACT_DIR", &bob_contacts)
+        .arg("contact")
+        .arg("--import-bundle").arg(tempdir.path().join("signed.bundle"))
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &bob_contacts)
+        .arg("contact").arg("--list")
+        .assert()
+        .success();
+    assert!(String::from_utf8(assert.get_output().stdout.clone())?.contains("uid: eve@core.ene\n"));
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("changed: "));
+
+    // an entry with invalid ID rejects the whole bundle, nothing is imported
+    {
+        use serde_cbor::{ self as cbor, Value };
+
+        let bundle: Value = cbor::from_slice(&fs::read(tempdir.path().join("unsigned.bundle"))?)?;
+        let mut bundle = match bundle { Value::Array(bundle) => bundle, _ => unreachable!() };
+        let mut inner = match bundle.pop() { Some(Value::Array(inner)) => inner, _ => unreachable!() };
+        let content = match &inner[0] { Value::Bytes(content) => content.clone(), _ => unreachable!() };
+
+        let mut content = match cbor::from_slice(&content)? { Value::Array(content) => content, _ => unreachable!() };
+        if let Value::Array(entries) = &mut content[1] {
+            let mut entry = match entries.last() { Some(Value::Array(entry)) => entry.clone(), _ => unreachable!() };
+            entry[0] = Value::String("@evil".into());
+            entries.push(Value::Array(entry));
+        }
+
+        inner[0] = Value::Bytes(cbor::to_vec(&Value::Array(content))?);
+        bundle.push(Value::Array(inner));
+        fs::write(tempdir.path().join("invalid.bundle"), cbor::to_vec(&Value::Array(bundle))?)?;
+    }
+
+    let invalid_contacts = tempdir.path().join("invalid_contacts");
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &invalid_contacts)
+        .arg("contact")
+        .arg("--import-bundle").arg(tempdir.path().join("invalid.bundle"))
+        .arg("--force")
+        .assert()
+        .failure();
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("@evil"));
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &invalid_contacts)
+        .arg("-q")
+        .arg("contact").arg("--list")
+        .assert()
+        .success()
+        .stdout("");
+
+    Ok(())
+}
+
+#[test]
+fn test_find() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+
+    // sled store, found by the fingerprint index
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact")
+        .arg("--import").arg("./tests/common/alice.pk.ene")
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list")
+        .assert()
+        .success();
+    let output = String::from_utf8(assert.get_output().stdout.clone())?;
+    let fingerprints = output
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| word.starts_with("0x"))
+        .collect::<Vec<_>>();
+    assert!(!fingerprints.is_empty());
+
+    // every printed fingerprint is found, as typed and as prefix
+    for fingerprint in fingerprints {
+        assert_eq!(fingerprint.len(), "0x".len() + 16);
+
+        for query in &[fingerprint, &fingerprint[..6], &fingerprint[2..]] {
+            let assert = bin.command()
+                .env("XDG_DATA_HOME", &data)
+                .arg("-q")
+                .arg("contact").arg("--find").arg(query)
+                .assert()
+                .success();
+            assert!(String::from_utf8(assert.get_output().stdout.clone())?.starts_with("alice@core.ene: "));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_passwd() -> Fallible<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (bin, tempdir) = setup()?;
+    let sk_path = tempdir.path().join("bob.ene");
+
+    // askpass which answers the new passphrase when asked for it, and the one which always does
+    let passwd = tempdir.path().join("passwd.sh");
+    let askpass2 = tempdir.path().join("askpass2.sh");
+    fs::write(&passwd, "#!/bin/sh\ncase \"$1\" in\n    New*|Repeat*) printf password2 ;;\n    *) printf password ;;\nesac\n")?;
+    fs::write(&askpass2, "#!/bin/sh\nprintf password2\n")?;
+    for path in &[&passwd, &askpass2] {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+
+    bin.command()
+        .arg("profile")
+        .arg("bob@core.ene").arg("--init")
+        .arg("--profile").arg(&sk_path)
+        .assert()
+        .success();
+
+    // a temporary file left by an interrupted change does not block it
+    fs::write(sk_path.with_extension("tmp"), "stale")?;
+
+    bin.command()
+        .env("ENE_ASKPASS", &passwd)
+        .arg("profile")
+        .arg("--profile").arg(&sk_path)
+        .arg("--passwd")
+        .assert()
+        .success();
+    assert!(!sk_path.with_extension("tmp").exists());
+
+    // the old passphrase is rejected, the new one opens the Profile
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg(&sk_path)
+        .arg("--export-pubkey").arg(tempdir.path().join("bob.pk.ene"))
+        .assert()
+        .failure();
+
+    bin.command()
+        .env("ENE_ASKPASS", &askpass2)
+        .arg("profile")
+        .arg("--profile").arg(&sk_path)
+        .arg("--export-pubkey").arg(tempdir.path().join("bob.pk.ene"))
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_restore() -> Fallible<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (bin, tempdir) = setup()?;
+
+    // quiet init prints only the mnemonic
+    let assert = bin.command()
+        .arg("-q")
+        .arg("profile")
+        .arg("bob@core.ene").arg("--init")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .assert()
+        .success();
+    let words = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert_eq!(words.split_whitespace().count(), 24);
+
+    // askpass which answers the mnemonic when asked for it
+    let askpass = tempdir.path().join("askpass.sh");
+    fs::write(&askpass, format!(
+        "#!/bin/sh\ncase \"$1\" in\n    Mnemonic*) printf '{}' ;;\n    *) printf password ;;\nesac\n",
+        words.trim()
+    ))?;
+    fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755))?;
+
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .arg("profile")
+        .arg("bob@core.ene").arg("--restore")
+        .arg("--profile").arg(tempdir.path().join("bob2.ene"))
+        .assert()
+        .success();
+
+    for name in &["bob", "bob2"] {
+        bin.command()
+            .arg("profile")
+            .arg("--profile").arg(tempdir.path().join(format!("{}.ene", name)))
+            .arg("--export-pubkey").arg(tempdir.path().join(format!("{}.pk.ene", name)))
+            .assert()
+            .success();
+    }
+
+    // restored Profile has the same PublicKey
+    assert_eq!(
+        fs::read(tempdir.path().join("bob.pk.ene"))?,
+        fs::read(tempdir.path().join("bob2.pk.ene"))?
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_protect() -> Fallible<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+
+    // askpass which records every prompt
+    let askpass = tempdir.path().join("askpass.sh");
+    let prompts = tempdir.path().join("prompts");
+    fs::write(&askpass, format!("#!/bin/sh\necho \"$1\" >> {}\nprintf password\n", prompts.display()))?;
+    fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755))?;
+
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .env("XDG_DATA_HOME", &data)
+        .arg("profile")
+        .arg("--import").arg("./tests/common/alice.ene")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .arg("profile")
+        .arg("bob@core.ene").arg("--init")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .arg("profile")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--export-pubkey").arg(tempdir.path().join("bob.pk.ene"))
+        .assert()
+        .success();
+
+    fs::write(tempdir.path().join("plaintext.txt"), "Hello")?;
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .arg("sendto")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--recipient-pubkey").arg("./tests/common/alice.pk.ene")
+        .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+        .arg("--output").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success();
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact")
+        .arg("--import").arg(tempdir.path().join("bob.pk.ene"))
+        .assert()
+        .success();
+
+    for op in &["--protect", "--encrypt"] {
+        bin.command()
+            .env("ENE_ASKPASS", &askpass)
+            .env("XDG_DATA_HOME", &data)
+            .arg("contact").arg(op)
+            .arg("--as").arg("alice@core.ene")
+            .assert()
+            .success();
+
+        let assert = bin.command()
+            .env("ENE_ASKPASS", &askpass)
+            .env("XDG_DATA_HOME", &data)
+            .arg("-q")
+            .arg("contact").arg("--list")
+            .assert()
+            .success();
+        assert!(String::from_utf8(assert.get_output().stdout.clone())?.starts_with("bob@core.ene: "));
+    }
+
+    // no plain ID is left in sled files
+    fn contains(path: &std::path::Path, needle: &[u8]) -> Fallible<bool> {
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                if contains(&entry?.path(), needle)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        } else {
+            Ok(fs::read(path)?.windows(needle.len()).any(|w| w == needle))
+        }
+    }
+
+    for entry in fs::read_dir(data.join("ene"))? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+        if name.starts_with("sled") || name.starts_with("keylog") {
+            assert!(!contains(&path, b"bob@core.ene")?, "{} has plain ID", name);
+        }
+    }
+
+    // key log is still readable with the Profile
+    let assert = bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("contact").arg("--log")
+        .assert()
+        .success();
+    assert!(String::from_utf8(assert.get_output().stdout.clone())?.contains("added bob@core.ene"));
+
+    // passphrase is asked once for both contact store and message
+    fs::write(&prompts, "")?;
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("recvfrom").arg("bob@core.ene")
+        .arg("--input").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success()
+        .stdout("Hello");
+    assert_eq!(fs::read_to_string(&prompts)?.lines().count(), 1);
+
+    // and once for both contact store and folder
+    let inbox = tempdir.path().join("inbox");
+    for subdir in &["tmp", "new", "cur"] {
+        fs::create_dir_all(inbox.join(subdir))?;
+    }
+
+    fs::write(&prompts, "")?;
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .env("XDG_DATA_HOME", &data)
+        .arg("mail").arg("decrypt-folder")
+        .arg(&inbox)
+        .arg("--output").arg(tempdir.path().join("decrypted"))
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&prompts)?.lines().count(), 1);
+
+    // and once for both contact store and fetch, besides the IMAP password
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let server = listener.local_addr()?.to_string();
+    let imap = fake_imap(listener, "\\Seen \\*", Vec::new());
+
+    fs::write(&prompts, "")?;
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .env("XDG_DATA_HOME", &data)
+        .arg("mail").arg("fetch")
+        .arg("--imap").arg(&server)
+        .arg("--user").arg("alice")
+        .arg("--output").arg(tempdir.path().join("archive"))
+        .assert()
+        .success();
+    imap.join().unwrap();
+    let asked = fs::read_to_string(&prompts)?;
+    assert_eq!(asked.lines().count(), 2);
+    assert_eq!(asked.lines().filter(|line| line.starts_with("IMAP password")).count(), 1);
+
+    // wrong passphrase can not open the store
+    bin.command()
+        .env("ENE_ASKPASS", "false")
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list")
+        .assert()
+        .failure();
+
+    // plain snapshot (0, [0; 32]) can not replace the sealed one
+    let mut head = vec![0x82, 0x00, 0x58, 0x20];
+    head.extend_from_slice(&[0; 32]);
+    fs::write(data.join("ene").join("keylog.head"), head)?;
+    bin.command()
+        .env("ENE_ASKPASS", &askpass)
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list")
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_protect_tamper() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+    let sled = data.join("ene").join("sled");
+
+    fn copy(from: &std::path::Path, to: &std::path::Path) -> Fallible<()> {
+        if to.exists() {
+            fs::remove_dir_all(to)?;
+        }
+        fs::create_dir_all(to)?;
+
+        for entry in fs::read_dir(from)? {
+            let path = entry?.path();
+            let to = to.join(path.file_name().unwrap());
+
+            if path.is_dir() {
+                copy(&path, &to)?;
+            } else {
+                fs::copy(&path, &to)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn replace(path: &std::path::Path, from: &[u8], to: &[u8]) -> Fallible<()> {
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                replace(&entry?.path(), from, to)?;
+            }
+        } else {
+            let mut buf = fs::read(path)?;
+            for i in 0..buf.len().saturating_sub(from.len() - 1) {
+                if &buf[i..][..from.len()] == from {
+                    buf[i..][..to.len()].copy_from_slice(to);
+                }
+            }
+            fs::write(path, buf)?;
+        }
+
+        Ok(())
+    }
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("profile")
+        .arg("--import").arg("./tests/common/alice.ene")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact")
+        .arg("--import").arg("./tests/common/alice.pk.ene")
+        .assert()
+        .success();
+
+    let plain = tempdir.path().join("sled.plain");
+    copy(&sled, &plain)?;
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--protect")
+        .arg("--as").arg("alice@core.ene")
+        .assert()
+        .success();
+
+    let protected = tempdir.path().join("sled.protected");
+    copy(&sled, &protected)?;
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("alice@core.ene")
+        .arg("--edit")
+        .arg("--name").arg("Alice")
+        .assert()
+        .success();
+
+    let edited = tempdir.path().join("sled.edited");
+    copy(&sled, &edited)?;
+
+    // the old store is authentic, but replayed
+    copy(&protected, &sled)?;
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list")
+        .assert()
+        .failure();
+
+    // plain store can not replace protected store
+    copy(&plain, &sled)?;
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list")
+        .assert()
+        .failure();
+
+    // tampered record is never shown
+    copy(&edited, &sled)?;
+    replace(&sled, b"Alice", b"Mallo")?;
+    let output = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list")
+        .output()?;
+    assert!(!String::from_utf8(output.stdout)?.contains("Mallo"));
+    assert!(!output.status.success() || !output.stderr.is_empty());
+
+    // the protection record can not be stripped
+    copy(&edited, &sled)?;
+    fs::remove_file(data.join("ene").join("profiles").join("contact"))?;
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list")
+        .assert()
+        .failure();
+
+    // a plain store without protection record and key log is refused by the Profile
+    copy(&plain, &sled)?;
+    fs::remove_file(data.join("ene").join("keylog"))?;
+    fs::remove_file(data.join("ene").join("keylog.head"))?;
+    fs::write(tempdir.path().join("plaintext.txt"), "Hello")?;
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("sendto").arg("alice@core.ene")
+        .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+        .arg("--output").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .failure();
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("must be protected by alice@core.ene"));
+    assert!(!tempdir.path().join("ciphertext.msg.ene").exists());
+
+    // protect again to accept a reset store
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--protect")
+        .arg("--as").arg("alice@core.ene")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("sendto").arg("alice@core.ene")
+        .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+        .arg("--output").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+#[test]
+fn test_lock() -> Fallible<()> {
+    use fs2::FileExt;
+
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+
+    // readers and writers wait for each other
+    let children = (0..6)
+        .map(|i| {
+            let mut command = bin.command();
+            command.env("XDG_DATA_HOME", &data).arg("-q").arg("contact");
+
+            if i % 2 == 0 {
+                command.arg("--import").arg("./tests/common/alice.pk.ene");
+            } else {
+                command.arg("--list");
+            }
+
+            command.spawn()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for mut child in children {
+        assert!(child.wait()?.success());
+    }
+
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("contact").arg("--list")
+        .assert()
+        .success();
+    assert!(String::from_utf8(assert.get_output().stdout.clone())?.contains("alice@core.ene"));
+
+    // a reader does not open sled while the store is locked
+    let lock = fs::File::open(data.join("ene/sled.lock"))?;
+    lock.lock_exclusive()?;
+
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("--lock-timeout").arg("1")
+        .arg("contact").arg("--list")
+        .assert()
+        .failure();
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("Wait for lock timeout"));
+
+    lock.unlock()?;
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("--lock-timeout").arg("1")
+        .arg("contact").arg("--list")
+        .assert()
+        .success();
+
+    // sled is locked exclusively even for reading
+    lock.lock_shared()?;
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("--lock-timeout").arg("1")
+        .arg("contact").arg("--list")
+        .assert()
+        .failure();
+
+    lock.unlock()?;
+
+    // readers of directory store share the lock, writers wait
+    let contacts = tempdir.path().join("contacts");
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact")
+        .arg("--import").arg("./tests/common/alice.pk.ene")
+        .assert()
+        .success();
+
+    let lock = fs::File::open(contacts.join(".lock"))?;
+    lock.lock_shared()?;
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("--lock-timeout").arg("1")
+        .arg("contact").arg("--list")
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("--lock-timeout").arg("1")
+        .arg("contact").arg("alice@core.ene")
+        .arg("--edit")
+        .arg("--name").arg("Alice")
+        .assert()
+        .failure();
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("Wait for lock timeout"));
+
+    lock.unlock()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_import_sender() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("profile")
+        .arg("--import").arg("./tests/common/alice.ene")
+        .assert()
+        .success();
+
+    // bob, and another bob with the same ID, send to alice
+    fs::write(tempdir.path().join("plaintext.txt"), "Hello")?;
+    for name in &["bob", "bob2"] {
+        let sk_path = tempdir.path().join(format!("{}.ene", name));
+
+        bin.command()
+            .arg("profile")
+            .arg("bob@core.ene").arg("--init")
+            .arg("--profile").arg(&sk_path)
+            .assert()
+            .success();
+
+        bin.command()
+            .arg("sendto")
+            .arg("--profile").arg(&sk_path)
+            .arg("--recipient-pubkey").arg("./tests/common/alice.pk.ene")
+            .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+            .arg("--output").arg(tempdir.path().join(format!("{}.msg.ene", name)))
+            .assert()
+            .success();
+    }
+
+    // unknown sender is refused without --import-sender
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("recvfrom").arg("bob@core.ene")
+        .arg("--input").arg(tempdir.path().join("bob.msg.ene"))
+        .assert()
+        .failure();
+
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("recvfrom").arg("--import-sender")
+        .arg("--input").arg(tempdir.path().join("bob.msg.ene"))
+        .assert()
+        .success();
+    assert_eq!(assert.get_output().stdout, b"Hello");
+
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list").arg("bob@core.ene")
+        .assert()
+        .success();
+    let output = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(output.contains("uid: bob@core.ene\n"));
+    assert!(output.contains("status: unverified\n"));
+
+    // pinned sender is verified without --import-sender
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("recvfrom").arg("bob@core.ene")
+        .arg("--input").arg(tempdir.path().join("bob.msg.ene"))
+        .assert()
+        .success()
+        .stdout("Hello");
+
+    // a contact is checked against the pinned key even with --import-sender,
+    // the changed key is kept as pending and nothing is written
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("recvfrom").arg("--import-sender")
+        .arg("--input").arg(tempdir.path().join("bob2.msg.ene"))
+        .arg("--output").arg(tempdir.path().join("bob2.txt"))
+        .assert()
+        .code(3);
+    let stderr = String::from_utf8(assert.get_output().stderr.clone())?;
+    assert!(stderr.contains("pk different"));
+    assert!(assert.get_output().stdout.is_empty());
+    assert!(!tempdir.path().join("bob2.txt").exists());
+
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("contact").arg("--list").arg("bob@core.ene")
+        .assert()
+        .success();
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("changed: "));
+
+    Ok(())
+}
+
+#[test]
+fn test_keyserver() -> Fallible<()> {
+    use std::collections::HashMap;
+    use std::io::{ BufRead, BufReader };
+    use std::process::Stdio;
+
+    let (bin, tempdir) = setup()?;
+    let contacts = tempdir.path().join("contacts");
+
+    let mut server = bin.command()
+        .arg("keyserver")
+        .arg("--listen").arg("127.0.0.1:0")
+        .arg("--dir").arg(tempdir.path().join("keyserver"))
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // keep reading, the server logs every request
+    let mut server_stdout = BufReader::new(server.stdout.take().unwrap());
+    let mut line = String::new();
+    server_stdout.read_line(&mut line)?;
+    let url = line.trim().rsplit(' ').next().unwrap().to_string();
+    let log = thread::spawn(move || server_stdout.lines().count());
+
+    // a slow client does not stall the others
+    let slow = std::net::TcpStream::connect(url.trim_start_matches("http://"))?;
+
+    // alice publish pubkey
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--publish")
+        .arg("--keyserver").arg(&url)
+        .assert()
+        .success();
+
+    // bob fetch alice
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact")
+        .arg("--fetch").arg("alice@core.ene")
+        .arg("--keyserver").arg(&url)
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact")
+        .arg("--fetch").arg("nobody@core.ene")
+        .arg("--keyserver").arg(&url)
+        .assert()
+        .failure();
+
+    // another key can not be published for alice@core.ene
+    bin.command()
+        .arg("profile")
+        .arg("alice@core.ene").arg("--init")
+        .arg("--profile").arg(tempdir.path().join("alice2.ene"))
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .arg("profile")
+        .arg("--profile").arg(tempdir.path().join("alice2.ene"))
+        .arg("--publish")
+        .arg("--keyserver").arg(&url)
+        .assert()
+        .failure();
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("409"));
+
+    // fetch by fingerprint of fetched contacts, until two of them share a prefix
+    let fingerprints = |id: &str| -> Fallible<Vec<String>> {
+        let assert = bin.command()
+            .env("ENE_CONTACT_DIR", &contacts)
+            .arg("contact").arg("--list").arg(id)
+            .assert()
+            .success();
+
+        Ok(String::from_utf8(assert.get_output().stdout.clone())?
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| word.starts_with("0x"))
+            .map(|word| word[2..].to_string())
+            .collect())
+    };
+
+    let alice_fingerprint = fingerprints("alice@core.ene")?.remove(0);
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts2"))
+        .arg("contact")
+        .arg("--fetch").arg(format!("0x{}", &alice_fingerprint[..8]))
+        .arg("--keyserver").arg(&url)
+        .assert()
+        .success();
+    assert!(String::from_utf8(assert.get_output().stdout.clone())?.contains("alice@core.ene"));
+
+    let mut owners: HashMap<char, String> = HashMap::new();
+    for fingerprint in fingerprints("alice@core.ene")? {
+        owners.insert(fingerprint.chars().next().unwrap(), "alice@core.ene".into());
+    }
+
+    let mut ambiguous = None;
+    for i in 0.. {
+        let id = format!("user{}@core.ene", i);
+        let sk_path = tempdir.path().join(format!("user{}.ene", i));
+
+        bin.command()
+            .arg("profile")
+            .arg(&id).arg("--init")
+            .arg("--profile").arg(&sk_path)
+            .assert()
+            .success();
+
+        bin.command()
+            .arg("profile")
+            .arg("--profile").arg(&sk_path)
+            .arg("--publish")
+            .arg("--keyserver").arg(&url)
+            .assert()
+            .success();
+
+        bin.command()
+            .env("ENE_CONTACT_DIR", &contacts)
+            .arg("contact")
+            .arg("--fetch").arg(&id)
+            .arg("--keyserver").arg(&url)
+            .assert()
+            .success();
+
+        for fingerprint in fingerprints(&id)? {
+            let first = fingerprint.chars().next().unwrap();
+
+            match owners.get(&first) {
+                Some(owner) if *owner != id => ambiguous = Some(first),
+                _ => { owners.insert(first, id.clone()); }
+            }
+        }
+
+        if ambiguous.is_some() {
+            break
+        }
+    }
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts3"))
+        .arg("contact")
+        .arg("--fetch").arg(format!("0x{}", ambiguous.unwrap()))
+        .arg("--keyserver").arg(&url)
+        .assert()
+        .failure();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone())?;
+    assert!(stderr.contains("409"));
+    assert!(stderr.contains("use a longer fingerprint"));
+
+    drop(slow);
+    server.kill()?;
+    log.join().unwrap();
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_agent() -> Fallible<()> {
+    use std::path::Path;
+    use std::process::Stdio;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+
+    let mut agent = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("agent").arg("--start")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut line = String::new();
+    BufReader::new(agent.stdout.take().unwrap()).read_line(&mut line)?;
+    let socket = line.trim().rsplit(' ').next().unwrap().to_string();
+
+    // socket directory is private
+    let mode = fs::metadata(Path::new(&socket).parent().unwrap())?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+
+    // oversized frame is refused, idle client does not stall agent
+    UnixStream::connect(&socket)?.write_all(&[0xff; 4])?;
+    let _idle = UnixStream::connect(&socket)?;
+
+    // alice use agent, without passphrase
+    fs::write(tempdir.path().join("plaintext.txt"), "Hello")?;
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .env("ENE_ASKPASS", "false")
+        .arg("sendto")
+        .arg("--recipient-pubkey").arg("./tests/common/alice.pk.ene")
+        .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+        .arg("--output").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .env("ENE_ASKPASS", "false")
+        .arg("-q")
+        .arg("recvfrom")
+        .arg("--as").arg("alice@core.ene")
+        .arg("--sender-pubkey").arg("./tests/common/alice.pk.ene")
+        .arg("--input").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success();
+    assert_eq!(assert.get_output().stdout, b"Hello");
+
+    // trial decryption takes agent first, other Profiles are not unlocked
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("profile")
+        .arg("bob@core.ene").arg("--init")
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .env("ENE_ASKPASS", "false")
+        .arg("-q")
+        .arg("recvfrom")
+        .arg("--sender-pubkey").arg("./tests/common/alice.pk.ene")
+        .arg("--input").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success();
+    assert_eq!(assert.get_output().stdout, b"Hello");
+
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("agent").arg("--stop")
+        .assert()
+        .success();
+
+    assert!(agent.wait()?.success());
+    assert!(!Path::new(&socket).exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_sendto_group() -> Fallible<()> {
+    use ene_core::contact::Record;
+
+    let (bin, tempdir) = setup()?;
+    let contacts = tempdir.path().join("contacts");
+    let output = tempdir.path().join("output");
+    fs::create_dir(&output)?;
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact")
+        .arg("--import").arg("./tests/common/alice.pk.ene")
+        .assert()
+        .success();
+
+    for name in &["bob", "carol", "dave"] {
+        let sk_path = tempdir.path().join(format!("{}.ene", name));
+        let pk_path = tempdir.path().join(format!("{}.pk.ene", name));
+
+        bin.command()
+            .arg("profile")
+            .arg(format!("{}@core.ene", name)).arg("--init")
+            .arg("--profile").arg(&sk_path)
+            .assert()
+            .success();
+
+        bin.command()
+            .arg("profile")
+            .arg("--profile").arg(&sk_path)
+            .arg("--export-pubkey").arg(&pk_path)
+            .assert()
+            .success();
+
+        bin.command()
+            .env("ENE_CONTACT_DIR", &contacts)
+            .arg("contact")
+            .arg("--import").arg(&pk_path)
+            .assert()
+            .success();
+    }
+
+    // carol is revoked, and verification does not undo it
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("carol@core.ene")
+        .arg("--edit").arg("--revoke")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact").arg("carol@core.ene")
+        .arg("--edit").arg("--verified").arg("true")
+        .assert()
+        .failure();
+
+    // dave is expired
+    let record_path = contacts.join("dave@core.ene.record");
+    let mut record: Record = serde_cbor::from_slice(&fs::read(&record_path)?)?;
+    record.expires = Some(1);
+    fs::write(&record_path, serde_cbor::to_vec(&record)?)?;
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("contact")
+        .arg("--group-add").arg("ops")
+        .arg("alice@core.ene").arg("bob@core.ene").arg("carol@core.ene").arg("dave@core.ene")
+        .arg("../evil")
+        .assert()
+        .success();
+
+    fs::write(tempdir.path().join("plaintext.txt"), "Hello")?;
+    for target in &["carol@core.ene", "dave@core.ene"] {
+        bin.command()
+            .env("ENE_CONTACT_DIR", &contacts)
+            .arg("sendto").arg(target)
+            .arg("--profile").arg("./tests/common/alice.ene")
+            .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+            .arg("--output").arg(tempdir.path().join("ciphertext.msg.ene"))
+            .assert()
+            .failure();
+    }
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", &contacts)
+        .arg("sendto").arg("@ops")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+        .arg("--output").arg(&output)
+        .assert()
+        .success();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone())?;
+
+    assert!(stderr.contains("skip carol@core.ene: PublicKey is revoked"));
+    assert!(stderr.contains("skip dave@core.ene: PublicKey is expired"));
+    assert!(stderr.contains("skip ../evil: "));
+    assert!(stderr.contains("3 of 5 members of @ops are skipped"));
+
+    let mut outputs = fs::read_dir(&output)?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
+    outputs.sort();
+    assert_eq!(outputs, vec![
+        "plaintext.txt.alice@core.ene.ene",
+        "plaintext.txt.bob@core.ene.ene"
+    ]);
+
+    Ok(())
+}
+
+#[test]
+fn test_select() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+    let data = tempdir.path().join("data");
+
+    // a new alice@core.ene is the default, the old key is kept under other name
+    bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("profile")
+        .arg("alice@core.ene").arg("--init")
+        .assert()
+        .success();
+    fs::copy("./tests/common/alice.ene", data.join("ene/profiles/alice-old.ene"))?;
+
+    fs::write(tempdir.path().join("plaintext.txt"), "Hello")?;
+    bin.command()
+        .arg("sendto")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--recipient-pubkey").arg("./tests/common/alice.pk.ene")
+        .arg("--input").arg(tempdir.path().join("plaintext.txt"))
+        .arg("--output").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success();
+
+    // recipient ID matches the new Profile, but only the old key can decrypt
+    let assert = bin.command()
+        .env("XDG_DATA_HOME", &data)
+        .arg("-q")
+        .arg("recvfrom")
+        .arg("--sender-pubkey").arg("./tests/common/alice.pk.ene")
+        .arg("--input").arg(tempdir.path().join("ciphertext.msg.ene"))
+        .assert()
+        .success();
+    assert_eq!(assert.get_output().stdout, b"Hello");
+
+    Ok(())
+}
+
+#[test]
+fn test_wkd() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+
+    // alice export pubkey to web key directory
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--export-wkd").arg(tempdir.path().join("wkd"))
+        .assert()
+        .success();
+
+    assert!(tempdir.path().join("wkd/.well-known/ene/core.ene/policy").is_file());
+
+    // bob discover alice
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts"))
+        .arg("contact")
+        .arg("--discover").arg("alice@core.ene")
+        .arg("--wkd-base").arg(tempdir.path().join("wkd"))
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts"))
+        .arg("contact")
+        .arg("--discover").arg("bob@core.ene")
+        .arg("--wkd-base").arg(tempdir.path().join("wkd"))
+        .assert()
+        .failure();
+
+    // contact is stored under the published ID, not as typed
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts2"))
+        .arg("contact")
+        .arg("--discover").arg("Alice@Core.ENE")
+        .arg("--wkd-base").arg(tempdir.path().join("wkd"))
+        .assert()
+        .success();
+    assert!(tempdir.path().join("contacts2/alice@core.ene.ene").is_file());
+    assert!(!tempdir.path().join("contacts2/Alice@Core.ENE.ene").is_file());
+
+    // domain can not break the request line
+    for email in &["alice@core.ene HTTP/1.0\r\nHost: evil", "alice@core.ene/../x", "alice@core ene"] {
+        let assert = bin.command()
+            .env("ENE_CONTACT_DIR", tempdir.path().join("contacts2"))
+            .arg("contact")
+            .arg("--discover").arg(email)
+            .arg("--wkd-base").arg("http://127.0.0.1:1")
+            .assert()
+            .failure();
+        assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("is not an email"));
+    }
+
+    // discovered key is logged
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts"))
+        .arg("-q")
+        .arg("contact")
+        .arg("--log")
+        .assert()
+        .success();
+
+    let log = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(log.contains("added alice@core.ene"));
+
+    // rewritten log is rejected
+    fs::write(tempdir.path().join("contacts").join(".keylog"), b"")?;
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts"))
+        .arg("contact")
+        .arg("--list")
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_autocrypt() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+
+    // alice generate autocrypt header
+    let assert = bin.command()
+        .arg("profile")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--autocrypt").arg("--prefer-encrypt")
+        .assert()
+        .success();
+
+    let header = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(header.starts_with("Autocrypt: addr=alice@core.ene; prefer-encrypt=mutual; keydata="));
+
+    let mail = format!(
+        "From: Alice <alice@core.ene>\nTo: bob@core.ene\nDate: Tue, 1 Jul 2003 10:52:37 +0200\n{}\nHello\n",
+        header
+    );
+
+    // bob receive mail from alice
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts"))
+        .arg("contact")
+        .arg("--autocrypt")
+        .with_stdin()
+        .buffer(mail)
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("contacts"))
+        .arg("contact")
+        .arg("alice@core.ene").arg("--list")
+        .assert()
+        .success();
+
+    let show = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(show.contains("source: autocrypt"));
+    assert!(show.contains("autocrypt: mutual"));
+
+    // the same mail is seen, a newer one updates the state
+    let newer = mail.replace("Tue, 1 Jul 2003", "Wed, 2 Jul 2003");
+    for (mail, expected) in vec![
+        (mail, "alice@core.ene is already up to date!"),
+        (newer, "Autocrypt state of alice@core.ene has been updated!")
+    ] {
+        let assert = bin.command()
+            .env("ENE_CONTACT_DIR", tempdir.path().join("contacts"))
+            .arg("contact")
+            .arg("--autocrypt")
+            .with_stdin()
+            .buffer(mail)
+            .assert()
+            .success();
+        assert!(String::from_utf8(assert.get_output().stdout.clone())?.contains(expected));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_mail() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+
+    // bob generate privkey
+    bin.command()
+        .arg("profile")
+        .arg("bob@core.ene").arg("--init")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .assert()
+        .success();
+
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--export-pubkey").arg(tempdir.path().join("bob.pk.ene"))
+        .assert()
+        .success();
+
+    // exchange pubkey
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("bob-contacts"))
+        .arg("contact")
+        .arg("--import").arg("./tests/common/alice.pk.ene")
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("contact")
+        .arg("--import").arg(tempdir.path().join("bob.pk.ene"))
+        .assert()
+        .success();
+
+    let mail = "From: Bob <bob@core.ene>\r\n\
+        To: Alice <alice@core.ene>\r\n\
+        Bcc: bob@core.ene\r\n\
+        Subject: Secret Plan\r\n\
+        \r\n\
+        Hello Alice\r\n";
+
+    // bob encrypt mail to alice
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("bob-contacts"))
+        .arg("mail").arg("encrypt")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--to").arg("alice@core.ene")
+        .arg("--output").arg(tempdir.path().join("mail.eml"))
+        .with_stdin()
+        .buffer(mail)
+        .assert()
+        .success();
+
+    let encrypted = fs::read_to_string(tempdir.path().join("mail.eml"))?;
+    assert!(encrypted.contains("multipart/encrypted; protocol=\"application/ene-encrypted\""));
+    assert!(encrypted.contains("To: Alice <alice@core.ene>"));
+    assert!(!encrypted.contains("Secret Plan"));
+    assert!(!encrypted.contains("Hello Alice"));
+    assert!(encrypted.contains("Message-ID: <"));
+
+    // alice decrypt mail from bob
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("decrypt")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--input").arg(tempdir.path().join("mail.eml"))
+        .assert()
+        .success();
+
+    let decrypted = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(decrypted.contains("Subject: Secret Plan\r\n"));
+    assert!(decrypted.ends_with("\r\n\r\nHello Alice\r\n"));
+    assert!(!decrypted.contains("Bcc"));
+    assert!(decrypted.contains("Date: "));
+
+    // bound headers can be refolded, but not modified
+    fs::write(
+        tempdir.path().join("refolded.eml"),
+        encrypted.replace("To: Alice <alice@core.ene>", "To:\r\n alice@core.ene")
+    )?;
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("decrypt")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--input").arg(tempdir.path().join("refolded.eml"))
+        .assert()
+        .success();
+
+    fs::write(
+        tempdir.path().join("tampered.eml"),
+        encrypted.replace("To: Alice <alice@core.ene>", "To: Alice <alice@core.ene>, mallory@core.ene")
+    )?;
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("decrypt")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--input").arg(tempdir.path().join("tampered.eml"))
+        .assert()
+        .failure();
+
+    // unknown sender is rejected
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("empty-contacts"))
+        .arg("mail").arg("decrypt")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--input").arg(tempdir.path().join("mail.eml"))
+        .assert()
+        .failure();
+
+    // mail of another key for bob, which does not decrypt, leaves the contact alone
+    bin.command()
+        .arg("profile")
+        .arg("bob@core.ene").arg("--init")
+        .arg("--profile").arg(tempdir.path().join("bob2.ene"))
+        .assert()
+        .success();
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("bob-contacts"))
+        .arg("mail").arg("encrypt")
+        .arg("--profile").arg(tempdir.path().join("bob2.ene"))
+        .arg("--to").arg("alice@core.ene")
+        .with_stdin()
+        .buffer(mail)
+        .assert()
+        .success();
+    fs::write(
+        tempdir.path().join("forged.eml"),
+        String::from_utf8(assert.get_output().stdout.clone())?
+            .replace("To: Alice <alice@core.ene>", "To: Alice <alice@core.ene>, mallory@core.ene")
+    )?;
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("decrypt")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--input").arg(tempdir.path().join("forged.eml"))
+        .assert()
+        .failure();
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("contact").arg("--list").arg("bob@core.ene")
+        .assert()
+        .success();
+    assert!(!String::from_utf8(assert.get_output().stderr.clone())?.contains("changed: "));
+
+    // alice decrypt maildir, tampered mail fails
+    let inbox = tempdir.path().join("inbox");
+    for subdir in &["tmp", "new", "cur"] {
+        fs::create_dir_all(inbox.join(subdir))?;
+    }
+    fs::copy(tempdir.path().join("mail.eml"), inbox.join("new").join("1.eml"))?;
+    fs::copy(tempdir.path().join("tampered.eml"), inbox.join("new").join("2.eml"))?;
+    fs::write(inbox.join("cur").join("3.eml"), mail)?;
+    fs::copy(tempdir.path().join("forged.eml"), inbox.join("cur").join("4.eml"))?;
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("decrypt-folder")
+        .arg(&inbox)
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--output").arg(tempdir.path().join("decrypted"))
+        .assert()
+        .failure();
+
+    let summary = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(summary.contains("1 decrypted, 2 failed, 1 not encrypted, 0 unknown senders"));
+    assert!(fs::read_to_string(tempdir.path().join("decrypted/new/1.eml"))?.contains("Subject: Secret Plan"));
+    assert!(!tempdir.path().join("decrypted/new/2.eml").exists());
+    assert!(!tempdir.path().join("decrypted/cur/3.eml").exists());
+
+    // the forged mail did not change bob
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("contact").arg("--list").arg("bob@core.ene")
+        .assert()
+        .success();
+    assert!(!String::from_utf8(assert.get_output().stderr.clone())?.contains("changed: "));
+
+    // mbox from unknown sender
+    fs::write(
+        tempdir.path().join("inbox.mbox"),
+        format!("From bob@core.ene Thu Jan  1 00:00:00 1970\n{}\n", encrypted)
+    )?;
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("empty-contacts"))
+        .arg("mail").arg("decrypt-folder")
+        .arg(tempdir.path().join("inbox.mbox"))
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--output").arg(tempdir.path().join("decrypted-mbox"))
+        .assert()
+        .success();
+
+    let summary = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(summary.contains("0 decrypted, 0 failed, 0 not encrypted, 1 unknown senders"));
+    assert!(summary.contains("unknown sender: bob@core.ene"));
+
+    // alice fetch from imap
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let server = listener.local_addr()?.to_string();
+    let imap = fake_imap(listener, "\\Seen \\*", vec![(7, encrypted.clone()), (8, mail.to_string())]);
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("fetch")
+        .arg("--imap").arg(&server)
+        .arg("--user").arg("alice")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--output").arg(tempdir.path().join("archive"))
+        .assert()
+        .success();
+
+    let summary = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(summary.contains("1 decrypted, 0 failed, 1 not encrypted, 0 unknown senders"));
+    assert_eq!(imap.join().unwrap(), vec![7, 8]);
+
+    let archived = fs::read_dir(tempdir.path().join("archive/new"))?
+        .map(|entry| fs::read_to_string(entry?.path()).map_err(Into::into))
+        .collect::<Fallible<Vec<_>>>()?;
+    assert_eq!(archived.len(), 2);
+    assert!(archived.iter().any(|mail| mail.contains("Subject: Secret Plan")));
+
+    // keywords were lost, stored messages are not stored again
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let server = listener.local_addr()?.to_string();
+    let imap = fake_imap(listener, "\\Seen \\*", vec![(7, encrypted.clone()), (8, mail.to_string())]);
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("fetch")
+        .arg("--imap").arg(&server)
+        .arg("--user").arg("alice")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--output").arg(tempdir.path().join("archive"))
+        .assert()
+        .success();
+
+    let output = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(output.contains("7: already stored"));
+    assert!(output.contains("0 decrypted, 0 failed, 0 not encrypted, 0 unknown senders"));
+    assert_eq!(imap.join().unwrap(), vec![7, 8]);
+    assert_eq!(fs::read_dir(tempdir.path().join("archive/new"))?.count(), 2);
+
+    // the keyword can not be kept
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let server = listener.local_addr()?.to_string();
+    let imap = fake_imap(listener, "\\Seen \\Deleted", vec![(7, encrypted.clone())]);
+
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("fetch")
+        .arg("--imap").arg(&server)
+        .arg("--user").arg("alice")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--output").arg(tempdir.path().join("archive-readonly"))
+        .assert()
+        .failure();
+
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("can not keep keyword"));
+    assert!(imap.join().unwrap().is_empty());
+    assert_eq!(fs::read_dir(tempdir.path().join("archive-readonly/new"))?.count(), 0);
+
+    // the password is not sent to a remote server in plain text
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("mail").arg("fetch")
+        .arg("--imap").arg("192.0.2.1:143")
+        .arg("--user").arg("alice")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("--output").arg(tempdir.path().join("archive-remote"))
+        .assert()
+        .failure();
+
+    assert!(String::from_utf8(assert.get_output().stderr.clone())?.contains("is not loopback"));
+
+    Ok(())
+}
+
+/// Fake IMAP server, accept one session of user `alice`, return the UIDs marked as processed.
+fn fake_imap(listener: TcpListener, permanent_flags: &'static str, messages: Vec<(u32, String)>)
+    -> thread::JoinHandle<Vec<u32>>
+{
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut processed = Vec::new();
+
+        stream.write_all(b"* OK fake IMAP4rev1\r\n").unwrap();
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break
+            }
+
+            let mut parts = line.trim_end().splitn(2, ' ');
+            let tag = parts.next().unwrap().to_string();
+            let command = parts.next().unwrap_or("");
+
+            let reply = if command.starts_with("LOGIN") {
+                if command == "LOGIN \"alice\" \"password\"" {
+                    format!("{} OK LOGIN completed\r\n", tag)
+                } else {
+                    format!("{} NO LOGIN failed\r\n", tag)
+                }
+            } else if command.starts_with("SELECT") {
+                format!(
+                    "* {} EXISTS\r\n* OK [UIDVALIDITY 1536] UIDs valid\r\n\
+                        * OK [PERMANENTFLAGS ({})] Limited\r\n{} OK [READ-WRITE] SELECT completed\r\n",
+                    messages.len(), permanent_flags, tag
+                )
+            } else if command == "UID SEARCH NOT KEYWORD $EneProcessed" {
+                let uids = messages.iter()
+                    .map(|(uid, _)| uid.to_string())
+                    .collect::<Vec<_>>();
+                format!("* SEARCH {}\r\n{} OK SEARCH completed\r\n", uids.join(" "), tag)
+            } else if command.starts_with("UID FETCH") {
+                let uid: u32 = command.split(' ').nth(2).unwrap().parse().unwrap();
+                let (_, data) = messages.iter().find(|(id, _)| *id == uid).unwrap();
+                format!(
+                    "* 1 FETCH (UID {} BODY[] {{{}}}\r\n{})\r\n{} OK FETCH completed\r\n",
+                    uid, data.len(), data, tag
+                )
+            } else if command.starts_with("UID STORE") && command.ends_with("+FLAGS.SILENT ($EneProcessed)") {
+                processed.push(command.split(' ').nth(2).unwrap().parse().unwrap());
+                format!("{} OK STORE completed\r\n", tag)
+            } else if command == "LOGOUT" {
+                stream.write_all(format!("* BYE\r\n{} OK LOGOUT completed\r\n", tag).as_bytes()).unwrap();
+                break
+            } else {
+                format!("{} BAD unknown command\r\n", tag)
+            };
+
+            stream.write_all(reply.as_bytes()).unwrap();
+        }
+
+        processed
+    })
+}
+
+/// Fake SMTP relay, accept one session and return every message with its recipients.
+fn fake_smtp(listener: TcpListener) -> thread::JoinHandle<Vec<(Vec<String>, String)>> {
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut messages = Vec::new();
+        let mut recipients = Vec::new();
+
+        stream.write_all(b"220 fake ESMTP\r\n").unwrap();
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break
+            }
+
+            let reply: &[u8] = match line.trim_end() {
+                cmd if cmd.starts_with("EHLO") => b"250-fake\r\n250 8BITMIME\r\n",
+                cmd if cmd.starts_with("MAIL FROM:") => b"250 ok\r\n",
+                cmd if cmd.starts_with("RCPT TO:") => {
+                    recipients.push(cmd["RCPT TO:".len()..].trim_matches(|c| c == '<' || c == '>').to_string());
+                    b"250 ok\r\n"
+                },
+                "DATA" => {
+                    stream.write_all(b"354 go ahead\r\n").unwrap();
+
+                    let mut data = String::new();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == ".\r\n" {
+                            break
+                        }
+                        data.push_str(if line.starts_with('.') { &line[1..] } else { &line });
+                    }
+
+                    messages.push((recipients.split_off(0), data));
+                    b"250 queued\r\n"
+                },
+                "QUIT" => {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break
+                },
+                _ => b"500 unknown\r\n"
+            };
+
+            stream.write_all(reply).unwrap();
+        }
+
+        messages
+    })
+}
+
+#[test]
+fn test_sendmail() -> Fallible<()> {
+    let (bin, tempdir) = setup()?;
+
+    // bob generate privkey
+    bin.command()
+        .arg("profile")
+        .arg("bob@core.ene").arg("--init")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .assert()
+        .success();
+
+    bin.command()
+        .arg("profile")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--export-pubkey").arg(tempdir.path().join("bob.pk.ene"))
+        .assert()
+        .success();
+
+    // alice knows bob, but not carol
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("contact")
+        .arg("--import").arg(tempdir.path().join("bob.pk.ene"))
+        .assert()
+        .success();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("bob-contacts"))
+        .arg("contact")
+        .arg("--import").arg("./tests/common/alice.pk.ene")
+        .assert()
+        .success();
+
+    let mail = "From: Alice <alice@core.ene>\n\
+        To: bob@core.ene\n\
+        Subject: Meeting\n\
+        \n\
+        .hidden dot line\n";
+
+    // unknown recipient, nothing is sent
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let relay = listener.local_addr()?.to_string();
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .arg("sendmail")
+        .arg("--relay").arg(&relay)
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("-oi").arg("--").arg("bob@core.ene").arg("carol@core.ene")
+        .with_stdin()
+        .buffer(mail)
+        .assert()
+        .failure();
+
+    // alice sendmail to bob
+    let server = fake_smtp(listener);
+
+    bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("alice-contacts"))
+        .env("ENE_SMTP_RELAY", &relay)
+        .arg("sendmail")
+        .arg("--profile").arg("./tests/common/alice.ene")
+        .arg("-oi").arg("-t")
+        .with_stdin()
+        .buffer(mail)
+        .assert()
+        .success();
+
+    let messages = server.join().unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].0, vec!["bob@core.ene"]);
+    assert!(!messages[0].1.contains("Meeting"));
+    fs::write(tempdir.path().join("mail.eml"), &messages[0].1)?;
+
+    // bob decrypt it
+    let assert = bin.command()
+        .env("ENE_CONTACT_DIR", tempdir.path().join("bob-contacts"))
+        .arg("mail").arg("decrypt")
+        .arg("--profile").arg(tempdir.path().join("bob.ene"))
+        .arg("--input").arg(tempdir.path().join("mail.eml"))
+        .assert()
+        .success();
+
+    let decrypted = String::from_utf8(assert.get_output().stdout.clone())?;
+    assert!(decrypted.contains("Subject: Meeting\r\n"));
+    assert!(decrypted.contains("\n.hidden dot line"));
+
+    Ok(())
+}